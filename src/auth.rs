@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::BitOr;
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// How many random bytes a server challenges clients with.
+pub const NONCE_LEN: usize = 32;
+
+/// What a client is allowed to do on a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Permissions(u8);
+
+impl Permissions {
+    pub const NONE: Permissions = Permissions(0);
+    /// Fetching, describing and listing blobs, and looking them up in the overlay
+    pub const READ: Permissions = Permissions(1);
+    /// Placing blobs, and copying or announcing them from other nodes
+    pub const WRITE: Permissions = Permissions(2);
+    /// Deleting blobs
+    pub const ADMIN: Permissions = Permissions(4);
+    pub const ALL: Permissions = Permissions(7);
+
+    pub fn from_bits(bits: u8) -> Permissions {
+        Permissions(bits & Permissions::ALL.0)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether everything `other` allows is allowed.
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parses a comma separated list of `read`, `write` and `admin`. Admins may also read
+    /// and write.
+    pub fn parse(s: &str) -> Result<Permissions, String> {
+        s.split(',').try_fold(Permissions::NONE, |permissions, name| {
+            let permission = match name.trim() {
+                "read" => Permissions::READ,
+                "write" => Permissions::WRITE,
+                "admin" => Permissions::ALL,
+                name => return Err(format!("Unknown permission {:?}, expected read, write or admin", name)),
+            };
+            Ok(permissions | permission)
+        })
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Permissions;
+
+    fn bitor(self, other: Permissions) -> Permissions {
+        Permissions(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = [(Permissions::READ, "read"), (Permissions::WRITE, "write"), (Permissions::ADMIN, "admin")]
+            .iter()
+            .filter(|(permission, _)| self.contains(*permission))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(","))
+        }
+    }
+}
+
+/// A fresh challenge for a client to prove it holds a token with.
+pub fn nonce() -> Result<Vec<u8>, String> {
+    let mut nonce = vec![0; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).map_err(|_| "Could not generate a challenge".to_string())?;
+    Ok(nonce)
+}
+
+/// The name of a token, and the secret shared with the servers that accept it.
+///
+/// The secret is never sent: the client proves it holds it by answering a challenge of
+/// the server with an HMAC-SHA256 of the challenge, keyed with the secret.
+#[derive(Clone)]
+pub struct Credentials {
+    pub name: String,
+    secret: Vec<u8>,
+}
+
+impl Credentials {
+    pub fn new(name: String, secret: Vec<u8>) -> Credentials {
+        Credentials {
+            name,
+            secret,
+        }
+    }
+
+    /// Parses credentials given as `NAME:SECRET`.
+    pub fn parse(s: &str) -> Result<Credentials, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(secret)) if !name.is_empty() && !secret.is_empty() => {
+                Ok(Credentials::new(name.to_string(), secret.as_bytes().to_vec()))
+            },
+            _ => Err("Invalid token, expected NAME:SECRET".to_string()),
+        }
+    }
+
+    /// The answer to the challenge `nonce`.
+    pub fn respond(&self, nonce: &[u8]) -> Vec<u8> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.secret);
+        hmac::sign(&key, nonce).as_ref().to_vec()
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Credentials({})", self.name)
+    }
+}
+
+/// The tokens a server accepts, and what each of them allows.
+#[derive(Debug, Clone)]
+pub struct Tokens {
+    tokens: HashMap<String, (hmac::Key, Permissions)>,
+}
+
+impl Tokens {
+    /// Reads the tokens in the file at `path`.
+    ///
+    /// Each line holds the name of a token, its secret and its permissions, separated by
+    /// whitespace, as in `ci s3cret read,write`. Empty lines and lines starting with `#`
+    /// are skipped.
+    pub fn load(path: &str) -> Result<Tokens, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        let mut tokens = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("{}:{}: expected NAME SECRET PERMISSIONS", path, i + 1));
+            }
+            let permissions = Permissions::parse(fields[2]).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+            let key = hmac::Key::new(hmac::HMAC_SHA256, fields[1].as_bytes());
+            if tokens.insert(fields[0].to_string(), (key, permissions)).is_some() {
+                return Err(format!("{}:{}: token {} is given twice", path, i + 1, fields[0]));
+            }
+        }
+        Ok(Tokens {
+            tokens,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// What the token `name` allows, if `mac` is the answer to the challenge `nonce` made
+    /// with its secret.
+    pub fn verify(&self, name: &str, nonce: &[u8], mac: &[u8]) -> Option<Permissions> {
+        let (key, permissions) = self.tokens.get(name)?;
+        hmac::verify(key, nonce, mac).ok().map(|_| *permissions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::TestDir;
+
+    /// Loads tokens from a file holding `text`.
+    fn load(text: &str) -> Result<Tokens, String> {
+        let dir = TestDir::new("tokens");
+        let path = dir.path().join("tokens");
+        fs::write(&path, text).unwrap();
+        Tokens::load(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn parses_and_displays_permissions() {
+        assert_eq!(Permissions::parse("read"), Ok(Permissions::READ));
+        assert_eq!(Permissions::parse("write, read"), Ok(Permissions::READ | Permissions::WRITE));
+        assert_eq!(Permissions::parse("admin"), Ok(Permissions::ALL));
+        assert!(Permissions::parse("read,delete").is_err());
+        assert!(Permissions::parse("").is_err());
+        assert!(Permissions::parse("read,").is_err());
+
+        assert_eq!(Permissions::NONE.to_string(), "none");
+        assert_eq!(Permissions::WRITE.to_string(), "write");
+        assert_eq!((Permissions::READ | Permissions::WRITE).to_string(), "read,write");
+        assert_eq!(Permissions::ALL.to_string(), "read,write,admin");
+        for bits in 1..8 {
+            let permissions = Permissions::from_bits(bits);
+            assert_eq!(Permissions::parse(&permissions.to_string()).map(|p| p.contains(permissions)), Ok(true));
+        }
+        assert_eq!(Permissions::from_bits(0xff), Permissions::ALL);
+        assert!(!Permissions::READ.contains(Permissions::READ | Permissions::WRITE));
+    }
+
+    #[test]
+    fn loads_tokens() {
+        let tokens = load("# name secret permissions\n\nci s3cret read,write\n  ops   0ther  admin  \n").unwrap();
+        assert_eq!(tokens.len(), 2);
+        let nonce = nonce().unwrap();
+        let ci = Credentials::parse("ci:s3cret").unwrap();
+        assert_eq!(tokens.verify("ci", &nonce, &ci.respond(&nonce)), Some(Permissions::READ | Permissions::WRITE));
+        let ops = Credentials::parse("ops:0ther").unwrap();
+        assert_eq!(tokens.verify("ops", &nonce, &ops.respond(&nonce)), Some(Permissions::ALL));
+
+        assert!(load("").unwrap().is_empty());
+        assert!(Tokens::load("/nonexistent/kitap/tokens").is_err());
+    }
+
+    #[test]
+    fn refuses_malformed_token_files() {
+        let e = load("ci s3cret read\nci2 s3cret\n").unwrap_err();
+        assert!(e.ends_with(":2: expected NAME SECRET PERMISSIONS"), "{}", e);
+        assert!(load("ci s3cret read write\n").is_err());
+        let e = load("ci s3cret read,delete\n").unwrap_err();
+        assert!(e.contains(":1: Unknown permission \"delete\""), "{}", e);
+        let e = load("ci s3cret read\nci other write\n").unwrap_err();
+        assert!(e.ends_with(":2: token ci is given twice"), "{}", e);
+    }
+
+    #[test]
+    fn verifies_answers_to_challenges() {
+        let tokens = load("ci s3cret read\n").unwrap();
+        let (nonce, other) = (nonce().unwrap(), nonce().unwrap());
+        assert_ne!(nonce, other);
+        let ci = Credentials::parse("ci:s3cret").unwrap();
+        let mac = ci.respond(&nonce);
+        assert_eq!(tokens.verify("ci", &nonce, &mac), Some(Permissions::READ));
+
+        let wrong = Credentials::parse("ci:guess").unwrap();
+        assert_eq!(tokens.verify("ci", &nonce, &wrong.respond(&nonce)), None);
+        assert_eq!(tokens.verify("cd", &nonce, &mac), None);
+        // an answer is only good for the challenge it answers
+        assert_eq!(tokens.verify("ci", &other, &mac), None);
+        assert_eq!(tokens.verify("ci", &nonce, &mac[..16]), None);
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let credentials = Credentials::parse("ci:s3:cret").unwrap();
+        assert_eq!(credentials.name, "ci");
+        assert_eq!(credentials.secret, b"s3:cret");
+        assert!(Credentials::parse("ci").is_err());
+        assert!(Credentials::parse(":s3cret").is_err());
+        assert!(Credentials::parse("ci:").is_err());
+        assert_eq!(format!("{:?}", credentials), "Credentials(ci)");
+    }
+}
@@ -0,0 +1,577 @@
+use std::fs;
+use std::io;
+use std::io::{SeekFrom, Write};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use hex::{decode, encode};
+
+use chrono::{LocalResult, TimeZone, Utc};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use tokio::prelude::*;
+use tokio::prelude::future::Loop;
+use tokio::runtime::Runtime;
+
+use kitap::auth::Credentials;
+use kitap::client::{Client, ClientError, FETCH_PIECE};
+use kitap::hash::HASH_SIZE;
+use kitap::shard::{HashRing, ShardedClient, DEFAULT_VNODES};
+use kitap::tls::Connector;
+use kitap::utils::{hash_file, hash_reader, create_base_app, resolve, resolve_addr, BoxedFuture};
+
+fn create_parser() -> App<'static, 'static> {
+    create_base_app("kitap")
+        .version("0.1")
+        .author("mandragore")
+        .about("RustDHT client")
+        .arg(
+            Arg::with_name("server")
+                .long("--server")
+                .help("A server to connect to, as HOST:PORT, instead of --host and --port. Given multiple times, each hash is kept on one of the servers, picked by consistent hashing, so every client must be given the same servers")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true)
+                .value_name("HOST:PORT"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("--token")
+                .help("The token to authenticate with, as NAME:SECRET")
+                .takes_value(true)
+                .global(true)
+                .value_name("NAME:SECRET")
+                .env("KITAP_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("tls_ca")
+                .long("--tls-ca")
+                .help("Connect over TLS, trusting the server certificates issued by the authorities in this PEM file")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("--tls-cert")
+                .help("The certificate chain presented to the servers that ask for one, in a PEM file")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE")
+                .requires_all(&["tls_key", "tls_ca"]),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("--tls-key")
+                .help("The private key of the certificate given with --tls-cert, in a PEM file")
+                .takes_value(true)
+                .global(true)
+                .value_name("FILE")
+                .requires("tls_cert"),
+        )
+        .arg(
+            Arg::with_name("tls_name")
+                .long("--tls-name")
+                .help("The host name the server certificates must be issued for. Defaults to the host connected to, or to that of the --server. Required when there is more than one --server")
+                .takes_value(true)
+                .global(true)
+                .value_name("NAME")
+                .requires("tls_ca"),
+        )
+        .arg(
+            Arg::with_name("min_prefix")
+                .long("--min-prefix")
+                .help("The fewest hex digits accepted as an abbreviated hash by fetch and stat")
+                .takes_value(true)
+                .global(true)
+                .default_value("8"),
+        )
+        .subcommand(
+            SubCommand::with_name("fetch")
+                .about("fethces a hash")
+                .arg(Arg::with_name("hash").min_values(1).required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("--output")
+                        .help("Where to write the fetched data. Use `-` for stdout, or a directory when fetching multiple hashes")
+                        .takes_value(true)
+                        .default_value("-"),
+                )
+                .arg(
+                    Arg::with_name("range")
+                        .long("--range")
+                        .help("Only fetch the bytes from START up to, but not including, END. Use `START-` to fetch up to the end, or `-COUNT` to fetch the last COUNT bytes. The data fetched cannot be checked against its hash")
+                        .takes_value(true)
+                        .allow_hyphen_values(true)
+                        .value_name("START-END"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("place")
+                .about("places a hash")
+                .arg(Arg::with_name("filename").required(true))
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("prints the size of the data stored under hashes, and when it was stored")
+                .arg(Arg::with_name("hash").min_values(1).required(true))
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("lists the stored hashes and the size of their data")
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("--prefix")
+                        .help("Only list the hashes whose hex encoding starts with this")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("deletes the data stored under a hash, and its copies on other nodes")
+                .arg(Arg::with_name("hash").required(true))
+        )
+}
+
+type DHTJob = BoxedFuture<(), ()>;
+
+/// Where fetched data is written to.
+#[derive(Debug, Clone)]
+enum Output {
+    Stdout,
+    File(PathBuf),
+    /// Each blob is written in a file named after its hash
+    Dir(PathBuf),
+}
+
+impl Output {
+    fn new(path: &str, count: usize) -> Result<Output, String> {
+        if path == "-" {
+            return Ok(Output::Stdout);
+        }
+        let path = PathBuf::from(path);
+        if path.is_dir() {
+            Ok(Output::Dir(path))
+        } else if count > 1 {
+            Err(format!("{} is not a directory", path.display()))
+        } else {
+            Ok(Output::File(path))
+        }
+    }
+
+    /// The file the data fetched for `hash` is written to, unless it goes to stdout.
+    fn path_of(&self, hash: &[u8]) -> Option<PathBuf> {
+        match self {
+            Output::Stdout => None,
+            Output::File(path) => Some(path.clone()),
+            Output::Dir(dir) => Some(dir.join(encode(hash))),
+        }
+    }
+}
+
+/// A part of the data to fetch.
+#[derive(Debug, Clone, Copy)]
+enum ByteRange {
+    /// The bytes from an offset up to, but not including, an end, or up to the end of the data
+    Span(u64, Option<u64>),
+    /// The last bytes of the data
+    Last(u64),
+}
+
+fn parse_range(range: &str) -> Result<ByteRange, String> {
+    let invalid = || format!("Invalid range {}, expected START-END, START- or -COUNT", range);
+    let mut bounds = range.splitn(2, '-');
+    let start = bounds.next().unwrap();
+    let end = bounds.next().ok_or_else(invalid)?;
+    let parse = |n: &str| n.parse::<u64>().map_err(|_| invalid());
+    match (start, end) {
+        ("", "") => Err(invalid()),
+        ("", count) => Ok(ByteRange::Last(parse(count)?)),
+        (start, "") => Ok(ByteRange::Span(parse(start)?, None)),
+        (start, end) => {
+            let (start, end) = (parse(start)?, parse(end)?);
+            if end < start {
+                return Err(format!("Invalid range {}, it ends before it starts", range));
+            }
+            Ok(ByteRange::Span(start, Some(end)))
+        },
+    }
+}
+
+fn parse_hash(hash: &str) -> Result<Vec<u8>, String> {
+    let v = decode(hash).or(Err("Invalid hex value as hash"))?;
+    if v.len() != HASH_SIZE {
+        return Err(String::from("Hash length is wrong"));
+    };
+    Ok(v)
+}
+
+/// Checks that `prefix` is the hex encoding of a hash, or of the start of one that is at
+/// least `min_len` digits long.
+fn parse_prefix(prefix: &str, min_len: usize) -> Result<String, String> {
+    let prefix = prefix.to_lowercase();
+    if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex value as hash: {}", prefix));
+    }
+    if prefix.len() < min_len || prefix.len() > HASH_SIZE * 2 {
+        return Err(format!("Hash length is wrong, it must have {} to {} digits", min_len, HASH_SIZE * 2));
+    }
+    Ok(prefix)
+}
+
+fn parse_prefixes(matches: &ArgMatches, min_len: usize) -> Result<Vec<String>, String> {
+    matches
+        .values_of("hash")
+        .unwrap()
+        .map(|prefix| parse_prefix(prefix, min_len))
+        .collect()
+}
+
+/// Finds the full hash that `prefix` abbreviates, on any of the servers.
+fn resolve_hash(client: &ShardedClient, prefix: String) -> BoxedFuture<Vec<u8>, String> {
+    if prefix.len() == HASH_SIZE * 2 {
+        return Box::new(future::result(decode(&prefix).map_err(|e| e.to_string())));
+    }
+    Box::new(client.resolve(&prefix).then(move |res| match res {
+        Ok(Some(hash)) => Ok(hash),
+        Ok(None) => Err(format!("Not Found: {}", prefix)),
+        Err(e) => Err(e.to_string()),
+    }))
+}
+
+/// How the servers are connected to, as the TLS and token options of `matches` tell.
+fn connector(matches: &ArgMatches) -> Result<Connector, String> {
+    let connector = secure_connector(matches)?;
+    Ok(match matches.value_of("token") {
+        Some(token) => connector.with_credentials(Credentials::parse(token)?),
+        None => connector,
+    })
+}
+
+fn secure_connector(matches: &ArgMatches) -> Result<Connector, String> {
+    let ca = match matches.value_of("tls_ca") {
+        Some(ca) => ca,
+        None => return Ok(Connector::Plain),
+    };
+    let identity = matches.value_of("tls_cert").map(|cert| (cert, matches.value_of("tls_key").unwrap()));
+    let servers: Vec<_> = matches.values_of("server").map(|servers| servers.collect()).unwrap_or_default();
+    let name = match (matches.value_of("tls_name"), servers.as_slice()) {
+        (Some(name), _) => name,
+        // the port follows the last colon, and ipv6 addresses are given in brackets
+        (None, [server]) => server.rsplitn(2, ':').last().unwrap(),
+        (None, []) => matches.value_of("host").unwrap(),
+        // every server is checked against the same name
+        (None, _) => return Err("--tls-name is required with more than one --server".to_string()),
+    };
+    Connector::tls(ca, identity, name.trim_start_matches('[').trim_end_matches(']'))
+}
+
+/// A connection to the server `hash` belongs to.
+fn connect(client: &ShardedClient, hash: &[u8]) -> impl Future<Item = Client, Error = ()> {
+    client.client_for(hash).map_err(|e| eprintln!("{}", e))
+}
+
+/// Fetches the data stored under `hash` into the file at `path`, `FETCH_PIECE` bytes at a
+/// time.
+///
+/// The data is written under a temporary name until all of it is received and verified,
+/// so nothing is left at `path` if fetching fails midway. If the temporary file is left
+/// from an interrupted fetch, fetching goes on from where it stopped.
+fn download(client: Client, hash: Vec<u8>, path: PathBuf) -> BoxedFuture<(), String> {
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".part");
+    let tmp = PathBuf::from(tmp);
+    let file = match OpenOptions::new().append(true).create(true).open(&tmp) {
+        Ok(file) => file,
+        Err(e) => return Box::new(future::err(format!("Could not write {}: {}", tmp.display(), e))),
+    };
+    let offset = file.metadata().map(|m| m.len()).unwrap_or(0);
+    if offset > 0 {
+        eprintln!("Resuming {} from byte {}", path.display(), offset);
+    }
+    let partial = tmp.clone();
+    let fetched = future::loop_fn((file, offset), move |(mut file, offset)| {
+        let hash = hash.clone();
+        let tmp = partial.clone();
+        client.fetch_range(&hash, offset, FETCH_PIECE)
+            .map_err(|e| e.to_string())
+            .and_then(move |data| {
+                let data = match data {
+                    Some(data) => data,
+                    None => {
+                        if offset == 0 {
+                            let _ = fs::remove_file(&tmp);
+                        }
+                        return Err(format!("Not Found: {}", encode(hash)));
+                    },
+                };
+                file.write_all(&data)
+                    .map_err(|e| format!("Could not write {}: {}", tmp.display(), e))?;
+                if (data.len() as u64) < FETCH_PIECE {
+                    Ok(Loop::Break(hash))
+                } else {
+                    Ok(Loop::Continue((file, offset + data.len() as u64)))
+                }
+            })
+    });
+    Box::new(fetched.and_then(move |hash| {
+        let received = File::open(&tmp).and_then(hash_reader)
+            .map_err(|e| format!("Could not read {}: {}", tmp.display(), e))?;
+        if received.as_slice() != hash.as_slice() {
+            let _ = fs::remove_file(&tmp);
+            return Err(ClientError::Corrupted(hash).to_string());
+        }
+        fs::rename(&tmp, &path)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }))
+}
+
+/// Fetches the part of the data stored under `hash` that `range` covers.
+fn fetch_part(client: Client, hash: Vec<u8>, range: ByteRange) -> BoxedFuture<Vec<u8>, String> {
+    let span = match range {
+        ByteRange::Span(start, end) => {
+            future::Either::A(future::ok((start, end.map_or(u64::MAX, |end| end - start))))
+        },
+        ByteRange::Last(count) => {
+            let hash = hash.clone();
+            future::Either::B(client.stat(vec![hash.clone()])
+                .map_err(|e| e.to_string())
+                .and_then(move |stats| match stats[0] {
+                    Some(stat) => Ok((stat.size.saturating_sub(count), count)),
+                    None => Err(format!("Not Found: {}", encode(hash))),
+                }))
+        },
+    };
+    Box::new(span.and_then(move |(offset, len)| {
+        client.fetch_range(&hash, offset, len)
+            .map_err(|e| e.to_string())
+            .and_then(move |data| data.ok_or_else(|| format!("Not Found: {}", encode(hash))))
+    }))
+}
+
+/// Writes `data` to the file at `path`.
+///
+/// The file is first written under a temporary name, so nothing is left behind if writing
+/// fails midway.
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".part");
+    File::create(&tmp)
+        .and_then(|mut f| f.write_all(data))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+/// Fetches the data stored under `hash`, or the part of it that `range` covers, into
+/// `path`. The data is returned instead when it is written to stdout.
+fn fetch_one(client: Client, hash: Vec<u8>, path: Option<PathBuf>, range: Option<ByteRange>) -> BoxedFuture<Option<Vec<u8>>, String> {
+    match (path, range) {
+        (Some(path), None) => Box::new(download(client, hash, path).map(|_| None)),
+        (Some(path), Some(range)) => Box::new(fetch_part(client, hash, range)
+            .and_then(move |data| write_file(&path, &data).map(|_| None))),
+        (None, None) => Box::new(client.fetch(&hash)
+            .map_err(|e| e.to_string())
+            .and_then(move |data| match data {
+                Some(data) => Ok(Some(data)),
+                None => Err(format!("Not Found: {}", encode(hash))),
+            })),
+        (None, Some(range)) => Box::new(fetch_part(client, hash, range).map(Some)),
+    }
+}
+
+fn fetch(client: ShardedClient, matches: &ArgMatches, min_len: usize) -> Result<DHTJob, String> {
+    let prefixes = parse_prefixes(matches, min_len)?;
+    let output = Output::new(matches.value_of("output").unwrap(), prefixes.len())?;
+    let range = matches.value_of("range").map(parse_range).transpose()?;
+    // all the hashes are requested at once, and the data of those written to stdout is
+    // written in order
+    let fetches = prefixes.into_iter().map(move |prefix| {
+        let client = client.clone();
+        let output = output.clone();
+        resolve_hash(&client, prefix)
+            .and_then(move |hash| {
+                client.client_for(&hash)
+                    .map_err(|e| e.to_string())
+                    .and_then(move |client| {
+                        let path = output.path_of(&hash);
+                        fetch_one(client, hash, path, range)
+                    })
+            })
+            .then(Ok::<_, ()>)
+    });
+    let client = future::join_all(fetches)
+        .and_then(move |results| {
+            let mut success = true;
+            for res in results {
+                let written = res.and_then(|data| match data {
+                    Some(data) => io::stdout().write_all(&data)
+                        .map_err(|e| format!("Could not write data: {}", e)),
+                    None => Ok(()),
+                });
+                if let Err(e) = written {
+                    eprintln!("{}", e);
+                    success = false;
+                }
+            }
+            if success { Ok(()) } else { Err(()) }
+        });
+    Ok(Box::new(client))
+}
+
+/// Places a file through an upload, so that running it again after it was interrupted
+/// only sends the data the server has not received yet.
+fn place(client: ShardedClient, matches: &ArgMatches) -> Result<DHTJob, String> {
+    let filename = matches.value_of("filename").unwrap();
+    let f = File::open(filename).or(Err(format!("Could not open {}", filename)))?;
+    let hash = hash_file(filename)?;
+    println!("{}", encode(hash));
+
+    let datasize = f.metadata().unwrap().len();
+    let filename = filename.to_string();
+
+    let client = connect(&client, &hash)
+        .and_then(move |client| {
+            client.begin_upload(&hash, datasize)
+                .map_err(|e| eprintln!("{}", e))
+                .and_then(move |offset| {
+                    let offset = match offset {
+                        Some(offset) => offset,
+                        // the data is already stored
+                        None => return future::Either::A(future::ok(())),
+                    };
+                    if offset > 0 {
+                        eprintln!("Resuming from byte {}", offset);
+                    }
+                    future::Either::B(tokio::fs::File::open(filename)
+                        .and_then(move |f| f.seek(SeekFrom::Start(offset)))
+                        .map_err(|e| eprintln!("{}", e))
+                        .and_then(move |(f, _)| {
+                            client.append(&hash, offset, datasize - offset, f)
+                                .and_then(move |_| client.commit(&hash))
+                                .map_err(|e| match e {
+                                    ClientError::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                                        eprintln!("file changed while being sent")
+                                    },
+                                    e => eprintln!("{}", e),
+                                })
+                        }))
+                })
+        })
+        .map(|_| println!("ITSOK"));
+    Ok(Box::new(client))
+}
+
+/// Formats a time given in seconds since the unix epoch.
+fn format_time(secs: u64) -> String {
+    match Utc.timestamp_opt(secs as i64, 0) {
+        LocalResult::Single(t) => t.format("%Y-%m-%d %H:%M:%S").to_string(),
+        _ => secs.to_string(),
+    }
+}
+
+fn stat(client: ShardedClient, matches: &ArgMatches, min_len: usize) -> Result<DHTJob, String> {
+    let prefixes = parse_prefixes(matches, min_len)?;
+    let resolves = prefixes.into_iter()
+        .map(|prefix| resolve_hash(&client, prefix).then(Ok::<_, ()>))
+        .collect::<Vec<_>>();
+    let client = future::join_all(resolves)
+        .and_then(move |resolved| {
+            let mut resolved_all = true;
+            let mut hashes = Vec::new();
+            for res in resolved {
+                match res {
+                    Ok(hash) => hashes.push(hash),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        resolved_all = false;
+                    },
+                }
+            }
+            client.stat(hashes.clone())
+                .map_err(|e| eprintln!("{}", e))
+                .and_then(move |stats| {
+                    let mut success = resolved_all;
+                    for (hash, stat) in hashes.iter().zip(stats) {
+                        match stat {
+                            Some(stat) => println!("{} {} {}", encode(hash), stat.size, format_time(stat.stored_at)),
+                            None => {
+                                eprintln!("Not Found: {}", encode(hash));
+                                success = false;
+                            },
+                        }
+                    }
+                    if success { Ok(()) } else { Err(()) }
+                })
+        });
+    Ok(Box::new(client))
+}
+
+fn list(client: ShardedClient, matches: &ArgMatches) -> Result<DHTJob, String> {
+    let prefix = matches.value_of("prefix").unwrap_or("").to_lowercase();
+    if !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid hex prefix {}", prefix));
+    }
+    let client = client.list_all(&prefix)
+        .for_each(|(hash, size)| {
+            println!("{} {}", encode(hash), size);
+            Ok(())
+        })
+        .map_err(|e| eprintln!("{}", e));
+    Ok(Box::new(client))
+}
+
+fn delete(client: ShardedClient, matches: &ArgMatches) -> Result<DHTJob, String> {
+    let hash = parse_hash(matches.value_of("hash").unwrap())?;
+    let client = connect(&client, &hash)
+        .and_then(move |client| {
+            client.delete(&hash)
+                .map_err(|e| eprintln!("{}", e))
+                .and_then(move |deleted| {
+                    if deleted {
+                        println!("DELETED");
+                        Ok(())
+                    } else {
+                        eprintln!("Not Found: {}", encode(hash));
+                        Err(())
+                    }
+                })
+        });
+    Ok(Box::new(client))
+}
+
+fn main() -> Result<(), String> {
+    let matches = create_parser().get_matches();
+
+    let servers = match matches.values_of("server") {
+        Some(servers) => servers.map(resolve_addr).collect::<Result<Vec<_>, _>>()?,
+        None => {
+            let host = matches
+                .value_of("host")
+                .expect("Host address not specified");
+            let port = matches.value_of("port").expect("Port not specified");
+            vec![resolve(host, port)?[0]]
+        },
+    };
+    let client = ShardedClient::new(HashRing::new(servers, DEFAULT_VNODES)?, connector(&matches)?);
+    let min_len: usize = matches.value_of("min_prefix").unwrap().parse()
+        .or(Err("Invalid minimum prefix length"))?;
+
+    let thread = match matches.subcommand() {
+        ("fetch", Some(submatches)) => fetch(client, submatches, min_len)?,
+        ("place", Some(submatches)) => place(client, submatches)?,
+        ("delete", Some(submatches)) => delete(client, submatches)?,
+        ("stat", Some(submatches)) => stat(client, submatches, min_len)?,
+        ("list", Some(submatches)) => list(client, submatches)?,
+        _ => Box::new(future::err(()))
+    };
+    let mut runtime = Runtime::new().or(Err("Could not start the runtime"))?;
+    if runtime.block_on(thread).is_err() {
+        process::exit(1);
+    }
+    Ok(())
+}
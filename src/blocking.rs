@@ -0,0 +1,357 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+
+use bytes::BytesMut;
+
+use tokio::codec::{Decoder, Encoder};
+
+use tokio_rustls::rustls::{ClientSession, StreamOwned};
+
+use crate::auth::{Credentials, Permissions};
+use crate::client::{authenticated, challenged};
+use crate::client::{appended, deleted, expect_place_ok, fetched, fetched_range, listed, resolved, stats, uploading};
+use crate::client::{verified, ClientError, ListPage, FETCH_PIECE};
+use crate::hash::{KitapHash, KitapHasher};
+use crate::messages::{check_server_handshake, DeleteMessage, ErrorCode, FetchMessage, Frame, KitapCodec, PlaceMessage, RequestId};
+use crate::messages::{Handshake, ListMessage, ResolveMessage, StatMessage, HANDSHAKE_LEN, PROTOCOL_VERSION};
+use crate::messages::{AppendMessage, CommitMessage, FetchRangeMessage, UploadMessage};
+use crate::messages::{AuthenticateMessage, HelloMessage};
+use crate::store::BlobStat;
+use crate::tls;
+
+/// The size of the chunks in which replies are read.
+const READ_SIZE: usize = 64 * 1024;
+
+/// A connection with a server, over plain TCP or over TLS.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientSession, TcpStream>>),
+}
+
+impl Stream {
+    fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.shutdown(Shutdown::Both),
+            Stream::Tls(stream) => stream.sock.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stream::Plain(sock) => write!(f, "Plain({:?})", sock),
+            Stream::Tls(stream) => write!(f, "Tls({:?})", stream.sock),
+        }
+    }
+}
+
+/// A connection to a kitap server, for callers that do not run an executor.
+///
+/// It speaks the same protocol as `kitap::client::Client`, but sends one request at a time
+/// and blocks until it is answered.
+#[derive(Debug)]
+pub struct Client {
+    stream: Stream,
+    codec: KitapCodec,
+    buf: BytesMut,
+    next_id: RequestId,
+}
+
+impl Client {
+    /// Connects to the server at `addr` and agrees on the protocol with it.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Client, ClientError> {
+        Client::open(Stream::Plain(TcpStream::connect(addr)?))
+    }
+
+    /// Connects to the server at `addr` over TLS and agrees on the protocol with it.
+    ///
+    /// As with `kitap::tls::Connector::tls`, the certificate of the server must be issued
+    /// for `name` by one of the authorities in the PEM file at `ca`, and `identity` is the
+    /// certificate chain and private key files presented if the server asks for a client
+    /// certificate.
+    pub fn connect_tls<A: ToSocketAddrs>(addr: A, ca: &str, identity: Option<(&str, &str)>, name: &str) -> Result<Client, ClientError> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let config = tls::client_config(ca, identity).map_err(invalid)?;
+        let session = ClientSession::new(&config, tls::server_name(name).map_err(invalid)?);
+        let sock = TcpStream::connect(addr)?;
+        Client::open(Stream::Tls(Box::new(StreamOwned::new(session, sock))))
+    }
+
+    /// Agrees on the protocol with the server at the other end of `stream`.
+    fn open(mut stream: Stream) -> Result<Client, ClientError> {
+        stream.write_all(&Handshake::to_bytes(PROTOCOL_VERSION))?;
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf)?;
+        check_server_handshake(buf).map_err(ClientError::Protocol)?;
+        Ok(Client {
+            stream,
+            codec: KitapCodec::new(),
+            buf: BytesMut::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Writes `frame` as a new request, returning its id.
+    fn send(&mut self, frame: Frame) -> Result<RequestId, ClientError> {
+        let id = self.next_id;
+        self.next_id = id.wrapping_add(1);
+        let mut buf = BytesMut::new();
+        self.codec.encode((id, frame), &mut buf)?;
+        self.stream.write_all(&buf)?;
+        Ok(id)
+    }
+
+    /// Reads the reply to request `id`.
+    fn receive(&mut self, id: RequestId) -> Result<Frame, ClientError> {
+        let mut chunk = vec![0; READ_SIZE];
+        // every request is sent once the previous one was answered, and is answered by a
+        // single reply, so the next reply read is the one to `id` and any other is an error
+        loop {
+            match self.codec.decode(&mut self.buf)? {
+                Some((reply_id, frame)) if reply_id == id => return Ok(frame),
+                Some((reply_id, _)) => {
+                    return Err(ClientError::Protocol(format!("Received a reply to unknown request {}", reply_id)));
+                },
+                None => (),
+            }
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(ClientError::Closed);
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Proves to the server that we hold `credentials`, returning what the server allows
+    /// us to do from now on.
+    pub fn authenticate(&mut self, credentials: &Credentials) -> Result<Permissions, ClientError> {
+        let id = self.send(Frame::Hello(HelloMessage::new()))?;
+        let nonce = challenged(self.receive(id)?)?;
+        let msg = AuthenticateMessage::new(credentials.name.clone(), credentials.respond(&nonce));
+        let id = self.send(Frame::Authenticate(msg))?;
+        authenticated(self.receive(id)?)
+    }
+
+    /// Stores `data` on the server, returning the hash it is stored under.
+    pub fn place(&mut self, data: &[u8]) -> Result<KitapHash, ClientError> {
+        let mut hasher = KitapHasher::new();
+        hasher.input(data);
+        let hash = hasher.result();
+        self.place_reader(&hash, data.len() as u64, data)?;
+        Ok(hash)
+    }
+
+    /// Stores the `len` bytes read from `reader` on the server, under `hash`.
+    ///
+    /// The server refuses the data if it does not hash to `hash`. If `reader` ends before
+    /// `len` bytes are read, the connection is closed, as the server has no way to tell
+    /// where the data ends.
+    pub fn place_reader<R: Read>(&mut self, hash: &[u8], len: u64, reader: R) -> Result<(), ClientError> {
+        let id = self.send(Frame::Place(PlaceMessage::new(hash.to_vec(), len)))?;
+        let sent = io::copy(&mut reader.take(len), &mut self.stream)?;
+        if sent != len {
+            let _ = self.stream.shutdown();
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data is shorter than its length").into());
+        }
+        expect_place_ok(self.receive(id)?)
+    }
+
+    /// Begins an upload of `len` bytes under `hash`, or resumes the upload already begun.
+    ///
+    /// Returns how many bytes of the upload the server has received, or `None` if it
+    /// already stores the data.
+    pub fn begin_upload(&mut self, hash: &[u8], len: u64) -> Result<Option<u64>, ClientError> {
+        let id = self.send(Frame::Upload(UploadMessage::new(hash.to_vec(), len)))?;
+        uploading(self.receive(id)?)
+    }
+
+    /// Adds the `len` bytes read from `reader` to the upload of `hash`, which must have
+    /// received `offset` bytes so far. Returns how many bytes the upload has received.
+    ///
+    /// If the connection is closed midway, the server keeps the data it received, and the
+    /// upload can be resumed with `begin_upload`.
+    pub fn append<R: Read>(&mut self, hash: &[u8], offset: u64, len: u64, reader: R) -> Result<u64, ClientError> {
+        let id = self.send(Frame::Append(AppendMessage::new(hash.to_vec(), offset, len)))?;
+        let sent = io::copy(&mut reader.take(len), &mut self.stream)?;
+        if sent != len {
+            let _ = self.stream.shutdown();
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data is shorter than its length").into());
+        }
+        appended(self.receive(id)?)
+    }
+
+    /// Stores the data of the upload of `hash`, once all of it has been appended.
+    ///
+    /// The server refuses the data if it does not hash to `hash`, and drops the upload.
+    pub fn commit(&mut self, hash: &[u8]) -> Result<(), ClientError> {
+        let id = self.send(Frame::Commit(CommitMessage::new(hash.to_vec())))?;
+        expect_place_ok(self.receive(id)?)
+    }
+
+    /// Fetches the data stored under `hash`, if there is any.
+    ///
+    /// Data too large to be sent at once is fetched `FETCH_PIECE` bytes at a time. The data
+    /// is checked against the hash before it is returned.
+    pub fn fetch(&mut self, hash: &[u8]) -> Result<Option<Vec<u8>>, ClientError> {
+        let id = self.send(Frame::Fetch(FetchMessage::new(vec![hash.to_vec()])))?;
+        match self.receive(id)? {
+            Frame::Error(ref e) if e.code == ErrorCode::TooLarge => self.fetch_pieces(hash, FETCH_PIECE),
+            reply => fetched(hash, reply),
+        }
+    }
+
+    /// Fetches the data stored under `hash` `piece` bytes at a time, then checks it.
+    pub(crate) fn fetch_pieces(&mut self, hash: &[u8], piece: u64) -> Result<Option<Vec<u8>>, ClientError> {
+        let mut data = Vec::new();
+        loop {
+            let fetched = match self.fetch_range(hash, data.len() as u64, piece)? {
+                Some(fetched) => fetched,
+                // deleted while it was fetched
+                None => return Ok(None),
+            };
+            let last = (fetched.len() as u64) < piece;
+            data.extend(fetched);
+            if last {
+                return verified(hash, data).map(Some);
+            }
+        }
+    }
+
+    /// Fetches at most `len` bytes of the data stored under `hash`, starting at `offset`,
+    /// if there is any data. Fewer bytes are returned at the end of the data.
+    ///
+    /// A part of the data cannot be checked against the hash, so callers should check the
+    /// whole data once they have it.
+    pub fn fetch_range(&mut self, hash: &[u8], offset: u64, len: u64) -> Result<Option<Vec<u8>>, ClientError> {
+        let id = self.send(Frame::FetchRange(FetchRangeMessage::new(hash.to_vec(), offset, len)))?;
+        fetched_range(hash, self.receive(id)?)
+    }
+
+    /// Deletes the data stored under `hash`, and the copies the server made of it on other
+    /// nodes, returning whether there was any.
+    pub fn delete(&mut self, hash: &[u8]) -> Result<bool, ClientError> {
+        let id = self.send(Frame::Delete(DeleteMessage::new(hash.to_vec())))?;
+        deleted(self.receive(id)?)
+    }
+
+    /// Describes the data stored under each of `hashes`, without transferring it.
+    ///
+    /// The stats are returned in the order of `hashes`, with `None` for the hashes under
+    /// which nothing is stored.
+    pub fn stat(&mut self, hashes: &[Vec<u8>]) -> Result<Vec<Option<BlobStat>>, ClientError> {
+        let id = self.send(Frame::Stat(StatMessage::new(hashes.to_vec())))?;
+        stats(hashes, self.receive(id)?)
+    }
+
+    /// Lists a page of the hashes whose hex encoding starts with `prefix`, starting after
+    /// `cursor`. The server decides how many hashes a page holds.
+    pub fn list(&mut self, prefix: &str, cursor: Option<Vec<u8>>) -> Result<ListPage, ClientError> {
+        let id = self.send(Frame::List(ListMessage::new(prefix.to_string(), cursor, 0)))?;
+        listed(self.receive(id)?)
+    }
+
+    /// Lists all the hashes whose hex encoding starts with `prefix`.
+    pub fn list_all(&mut self, prefix: &str) -> Result<Vec<(Vec<u8>, u64)>, ClientError> {
+        let mut entries = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self.list(prefix, cursor)?;
+            entries.extend(page.entries);
+            cursor = match page.cursor {
+                Some(cursor) => Some(cursor),
+                None => return Ok(entries),
+            };
+        }
+    }
+
+    /// Finds the stored hash whose hex encoding starts with `prefix`, if there is one.
+    ///
+    /// Fails with `ClientError::Ambiguous` if more than one hash starts with it.
+    pub fn resolve(&mut self, prefix: &str) -> Result<Option<Vec<u8>>, ClientError> {
+        let id = self.send(Frame::Resolve(ResolveMessage::new(prefix.to_string())))?;
+        resolved(prefix, self.receive(id)?)
+    }
+
+    /// Checks whether the server stores anything under `hash`.
+    pub fn exists(&mut self, hash: &[u8]) -> Result<bool, ClientError> {
+        self.stat(&[hash.to_vec()]).map(|stats| stats[0].is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use tokio::runtime::Runtime;
+
+    use crate::client::tests::{memory_store, serve};
+    use crate::messages::NotFoundMessage;
+    use crate::store::tests::hash_of;
+
+    #[test]
+    fn places_and_fetches_data_on_a_loopback_server() {
+        let mut runtime = Runtime::new().unwrap();
+        let addr = serve(&mut runtime, memory_store());
+        let mut client = Client::connect(addr).unwrap();
+        let data = b"placed, then fetched without a runtime".to_vec();
+
+        let hash = client.place(&data).unwrap();
+        assert_eq!(hash.to_vec(), hash_of(&data));
+        assert!(client.exists(&hash).unwrap());
+        assert_eq!(client.fetch(&hash).unwrap(), Some(data.clone()));
+        let read = b"read from a reader".to_vec();
+        client.place_reader(&hash_of(&read), read.len() as u64, Cursor::new(read.clone())).unwrap();
+        let stats = client.stat(&[hash.to_vec(), hash_of(&read), vec![0; 64]]).unwrap();
+        let sizes: Vec<_> = stats.iter().map(|stat| stat.map(|stat| stat.size)).collect();
+        assert_eq!(sizes, vec![Some(data.len() as u64), Some(read.len() as u64), None]);
+
+        assert!(client.delete(&hash).unwrap());
+        assert_eq!(client.fetch(&hash).unwrap(), None);
+        assert!(!client.delete(&hash).unwrap());
+        assert_eq!(client.fetch(&hash_of(&read)).unwrap(), Some(read));
+    }
+
+    #[test]
+    fn refuses_replies_to_other_requests() {
+        let mut runtime = Runtime::new().unwrap();
+        let addr = serve(&mut runtime, |id, frame| match frame {
+            Frame::Fetch(mut msg) => vec![(id + 1, Frame::NotFound(NotFoundMessage::new(msg.hashes.remove(0))))],
+            frame => panic!("unexpected {:?}", frame),
+        });
+        let mut client = Client::connect(addr).unwrap();
+        match client.fetch(&[0; 64]) {
+            Err(ClientError::Protocol(_)) => (),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -1,136 +1,941 @@
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
-use std::fs::File;
-use std::str;
-
-use hex::{decode, encode};
-
-use clap::{App, Arg, ArgMatches, SubCommand};
-
-use tokio::io::{read_exact, write_all};
-use tokio::prelude::*;
-
-use byteorder::{LittleEndian, ReadBytesExt};
-
-use kitap::hash::HASH_SIZE;
-use kitap::messages::{FetchMessage, Message, PlaceMessage};
-use kitap::utils::{file_chunks, hash_file, connect, create_base_app, BoxedFuture};
-
-fn create_parser() -> App<'static, 'static> {
-    create_base_app("kitap")
-        .version("0.1")
-        .author("mandragore")
-        .about("RustDHT client")
-        .subcommand(
-            SubCommand::with_name("fetch")
-                .about("fethces a hash")
-                .arg(Arg::with_name("hash").min_values(1).required(true)),
-        )
-        .subcommand(
-            SubCommand::with_name("place")
-                .about("places a hash")
-                .arg(Arg::with_name("filename").required(true))
-        )
-}
-
-type DHTJob = BoxedFuture<(), ()>;
-
-fn fetch(addr: SocketAddr, matches: &ArgMatches) -> Result<DHTJob, String> {
-    let hashes: Result<Vec<Vec<u8>>, _> = matches
-        .values_of("hash")
-        .unwrap()
-        .map(|x| {
-            let v = decode(x.to_string().into_bytes())
-                .or(Err("Invalid hex value as hash"))?;
-            if v.len() != HASH_SIZE {
-                return Err(String::from("Hash length is wrong"));
-            };
-            Ok(v)
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+
+use futures::future;
+use futures::future::Loop;
+use futures::sync::mpsc;
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::{stream, Future, Sink, Stream};
+
+use log::{debug, warn};
+
+use tokio::codec::{BytesCodec, Framed, FramedRead};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::auth::{Credentials, Permissions};
+use crate::dht::Contact;
+use crate::hash::{KitapHash, KitapHasher};
+use crate::messages::{client_handshake, DeleteMessage, ErrorCode, FetchMessage, PlaceMessage};
+use crate::messages::{AppendMessage, CommitMessage, FetchRangeMessage, UploadMessage};
+use crate::messages::{Frame, KitapCodec, ListMessage, RequestId, ResolveMessage, StatMessage};
+use crate::messages::{FindNodeMessage, FindValueMessage, NodesMessage, ReplicateMessage, StoreMessage};
+use crate::messages::{AuthenticateMessage, HelloMessage};
+use crate::store::BlobStat;
+use crate::utils::BoxedFuture;
+
+/// The reasons for which a request made by a `Client` can fail.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Reading from or writing to the connection failed
+    Io(io::Error),
+    /// The server does not speak our protocol, or replied with something unexpected
+    Protocol(String),
+    /// The server refused the request
+    Server { code: ErrorCode, reason: String },
+    /// The data received does not match the hash it was fetched with
+    Corrupted(Vec<u8>),
+    /// An abbreviated hash matches more than one stored hash. A few of them are given.
+    Ambiguous { prefix: String, candidates: Vec<Vec<u8>> },
+    /// The connection was closed before the request was answered
+    Closed,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "Connection failed: {}", e),
+            ClientError::Protocol(e) => write!(f, "Protocol error: {}", e),
+            ClientError::Server { code, reason } => write!(f, "Request failed ({:?}): {}", code, reason),
+            ClientError::Corrupted(hash) => write!(f, "Data received for {} does not match its hash", hex::encode(hash)),
+            ClientError::Ambiguous { prefix, candidates } => {
+                write!(f, "Prefix {} is ambiguous, it matches", prefix)?;
+                for hash in candidates {
+                    write!(f, " {}", hex::encode(hash))?;
+                }
+                Ok(())
+            },
+            ClientError::Closed => write!(f, "Connection closed"),
+        }
+    }
+}
+
+impl error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+/// A page of the hashes stored on a server, with the size of their data.
+#[derive(Debug)]
+pub struct ListPage {
+    pub entries: Vec<(Vec<u8>, u64)>,
+    /// The cursor to list the next page from, if there is one
+    pub cursor: Option<Vec<u8>>,
+}
+
+/// What a node of the overlay answers to a lookup of the data stored under a hash.
+#[derive(Debug)]
+pub enum FoundValue {
+    /// The requested part of the data, which the node stores
+    Data(Vec<u8>),
+    /// The nodes closer to the hash, and those known to store its data
+    Nodes(NodesMessage),
+}
+
+/// How much data is asked for at a time when data is too large to be fetched at once.
+pub const FETCH_PIECE: u64 = 4 * 1024 * 1024;
+
+type Reply = Result<Frame, ClientError>;
+
+/// The frames of a request, queued to be written to the connection.
+type Outgoing = (RequestId, Box<dyn Stream<Item = (RequestId, Frame), Error = io::Error> + Send>);
+
+/// The requests of a connection that wait for their reply.
+#[derive(Debug, Default)]
+struct Pending {
+    next_id: RequestId,
+    waiting: HashMap<RequestId, oneshot::Sender<Reply>>,
+    closed: bool,
+}
+
+impl Pending {
+    /// Fails every waiting request, and any request made from now on.
+    fn close(&mut self) {
+        self.closed = true;
+        for (_, waiter) in self.waiting.drain() {
+            let _ = waiter.send(Err(ClientError::Closed));
+        }
+    }
+}
+
+/// A connection to a kitap server.
+///
+/// The connection is driven by a task spawned on the current executor, and the client
+/// is a handle to it, so it can be cloned and used from many places. Requests are
+/// pipelined, and each is answered as soon as the server replies to it. The connection
+/// is closed once every handle is dropped and every request has been answered.
+#[derive(Clone)]
+pub struct Client {
+    pending: Arc<Mutex<Pending>>,
+    sender: Sender<Outgoing>,
+}
+
+impl Client {
+    /// Connects to the server at `addr` and agrees on the protocol with it.
+    pub fn connect(addr: &SocketAddr) -> impl Future<Item = Client, Error = ClientError> {
+        TcpStream::connect(addr)
+            .map_err(ClientError::Io)
+            .and_then(Client::open)
+    }
+
+    /// Agrees on the protocol with the server at the other end of `stream`, and makes
+    /// requests over it.
+    pub(crate) fn open<S>(stream: S) -> impl Future<Item = Client, Error = ClientError>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        client_handshake(stream)
+            .map_err(ClientError::Protocol)
+            .map(Client::start)
+    }
+
+    /// Whether the connection is closed, so that no more requests can be made with it.
+    pub fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    /// Spawns the task that drives the connection.
+    fn start<S>(sock: S) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (sender, outgoing) = mpsc::channel::<Outgoing>(1);
+        let (sink, frames) = Framed::new(sock, KitapCodec::new()).split();
+
+        // requests are written one after the other, so that the data of a place is not
+        // interleaved with other requests
+        let writer = outgoing
+            .map_err(|_| (0, io::Error::other("request queue failed")))
+            .fold(sink, |sink, (id, frames)| {
+                sink.send_all(frames)
+                    .map(|(sink, _)| sink)
+                    .map_err(move |e| (id, e))
+            })
+            // let the server know that no more requests are coming
+            .and_then(|mut sink| future::poll_fn(move || sink.close()).map_err(|e| (0, e)));
+
+        let failed = pending.clone();
+        let writer = writer.map_err(move |(id, e)| {
+            debug!("Could not send request {}: {}", id, e);
+            if let Some(waiter) = failed.lock().unwrap().waiting.remove(&id) {
+                let _ = waiter.send(Err(ClientError::Io(e)));
+            }
+        });
+
+        let replies = pending.clone();
+        let reader = frames
+            .map_err(|e| debug!("Could not read reply: {}", e))
+            .for_each(move |(id, frame)| {
+                match replies.lock().unwrap().waiting.remove(&id) {
+                    Some(waiter) => {
+                        let _ = waiter.send(Ok(frame));
+                    },
+                    None => warn!("Received a reply to unknown request {}", id),
+                }
+                Ok(())
+            });
+
+        let closed = pending.clone();
+        let connection = writer.select2(reader)
+            .then(|res| match res {
+                // keep reading the replies to the requests already sent
+                Ok(future::Either::A((_, reader))) => future::Either::A(reader),
+                _ => future::Either::B(future::ok(())),
+            })
+            .then(move |_| {
+                closed.lock().unwrap().close();
+                debug!("Connection closed");
+                Ok(())
+            });
+        tokio::spawn(connection);
+        Client {
+            pending,
+            sender,
+        }
+    }
+
+    /// Sends the frames built by `frames` as a new request, and waits for its reply.
+    fn request<F, S>(&self, frames: F) -> BoxedFuture<Frame, ClientError>
+    where
+        F: FnOnce(RequestId) -> S,
+        S: Stream<Item = (RequestId, Frame), Error = io::Error> + Send + 'static,
+    {
+        let (waiter, reply) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Box::new(future::err(ClientError::Closed));
+            }
+            let id = pending.next_id;
+            pending.next_id = id.wrapping_add(1);
+            pending.waiting.insert(id, waiter);
+            id
+        };
+        Box::new(self.sender.clone().send((id, Box::new(frames(id))))
+            .map_err(|_| ClientError::Closed)
+            .and_then(|_| reply.map_err(|_| ClientError::Closed))
+            .and_then(|reply| reply))
+    }
+
+    /// Stores `data` on the server, returning the hash it is stored under.
+    pub fn place(&self, data: Vec<u8>) -> impl Future<Item = KitapHash, Error = ClientError> {
+        let mut hasher = KitapHasher::new();
+        hasher.input(&data);
+        let hash = hasher.result();
+        let len = data.len() as u64;
+        let body = Bytes::from(data);
+        self.request(move |id| stream::iter_ok(vec![
+            (id, Frame::Place(PlaceMessage::new(hash.to_vec(), len))),
+            (id, Frame::Body(body)),
+        ]))
+        .and_then(move |reply| expect_place_ok(reply).map(|_| hash))
+    }
+
+    /// Stores the `len` bytes read from `reader` on the server, under `hash`.
+    ///
+    /// The server refuses the data if it does not hash to `hash`. If `reader` ends before
+    /// `len` bytes are read, the connection is closed, as the server has no way to tell
+    /// where the data ends.
+    pub fn place_reader<R>(&self, hash: &[u8], len: u64, reader: R) -> impl Future<Item = (), Error = ClientError>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let hash = hash.to_vec();
+        self.request(move |id| {
+            let header = stream::once(Ok((id, Frame::Place(PlaceMessage::new(hash, len)))));
+            header.chain(body_frames(id, reader, len))
         })
-        .collect();
-    let hashes = hashes?;
-    let client = connect(addr)
-        .and_then(|(rx, wx)| {
-            let msg = FetchMessage::new(hashes);
-            let string = msg.into_bytes();
-            let w = write_all(wx, string)
-                .map(|(wx, _buf)| (rx, wx))
-                .map_err(|e| eprintln!("failed to send bytes {}", e));
-            w
+        .and_then(expect_place_ok)
+    }
+
+    /// Copies the `len` bytes read from `reader` to the server, under `hash`, as a replica of
+    /// data placed on another node. The server does not copy it any further.
+    pub fn replicate<R>(&self, hash: &[u8], len: u64, reader: R) -> impl Future<Item = (), Error = ClientError>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let hash = hash.to_vec();
+        self.request(move |id| {
+            let header = stream::once(Ok((id, Frame::Replicate(ReplicateMessage::new(hash, len)))));
+            header.chain(body_frames(id, reader, len))
         })
-        .and_then(|(rx, _wx)| {
-            let mut buf = Vec::with_capacity(6);
-            buf.resize(6, 0);
-            let r = read_exact(rx, buf)
-                .map_err(|_| eprintln!("failed to receive bytes"))
-                .and_then(|(rx, buf)| {
-                    let mut cursor = Cursor::new(buf);
-                    let _typ = cursor.read_u16::<LittleEndian>().expect("read type") as usize;
-                    let length = cursor.read_u32::<LittleEndian>().expect("read length") as usize;
-                    let mut buf = Vec::with_capacity(length);
-                    buf.resize(length, 0);
-                    read_exact(rx, buf)
-                        .map_err(|e| eprintln!("could not read response {}", e))
-                        .map(|(_, buf)| {
-                            let mut message = "Not Found: ".to_string();
-                            message.extend(encode(buf).chars());
-                            println!("{}", message)
-                        })
-                });
-            tokio::spawn(r)
-        });
-    Ok(Box::new(client))
-}
-
-fn place(addr: SocketAddr, matches: &ArgMatches) -> Result<DHTJob, String> {
-    let filename = matches.value_of("filename").unwrap();
-    let f = File::open(filename).or(Err(format!("Could not open {}", filename)))?;
-    let hash = hash_file(filename)?;
-    println!("{}", encode(&hash));
-
-    let datasize = f.metadata().unwrap().len() as usize;
-    let data = file_chunks(filename)?;
-
-    let client = connect(addr)
-        .and_then(move |(rx, wx)| {
-            let msg = PlaceMessage::new(hash.to_vec(), datasize);
-            let mut vec = vec![msg.into_bytes()];
-            vec.extend(data.into_iter().map(|c| {
-                let v: Result<Vec<u8>, _> = c.collect();
-                let v = v.unwrap();
-                v
-            }));
-            stream::iter_ok(vec).
-                fold((rx, wx), |(reader, writer) , buf| {
-                    write_all(writer, buf)
-                        .map(|(wx, _buf)| (reader, wx))
-                        .map_err(|e| eprintln!("failed to send bytes {}", e))
+        .and_then(expect_place_ok)
+    }
+
+    /// Copies the `len` bytes of `pieces` to the server, under `hash`, as a replica of data
+    /// placed on another node. The server does not copy it any further.
+    ///
+    /// `pieces` must hold exactly `len` bytes, as that is how many the server reads.
+    pub fn replicate_pieces<S>(&self, hash: &[u8], len: u64, pieces: S) -> impl Future<Item = (), Error = ClientError>
+    where
+        S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+    {
+        let hash = hash.to_vec();
+        self.request(move |id| {
+            let header = stream::once(Ok((id, Frame::Replicate(ReplicateMessage::new(hash, len)))));
+            header.chain(pieces.map(move |piece| (id, Frame::Body(piece))))
+        })
+        .and_then(expect_place_ok)
+    }
+
+    /// Begins an upload of `len` bytes under `hash`, or resumes the upload already begun.
+    ///
+    /// Returns how many bytes of the upload the server has received, or `None` if it
+    /// already stores the data.
+    pub fn begin_upload(&self, hash: &[u8], len: u64) -> impl Future<Item = Option<u64>, Error = ClientError> {
+        let msg = UploadMessage::new(hash.to_vec(), len);
+        self.request(move |id| stream::once(Ok((id, Frame::Upload(msg)))))
+            .and_then(uploading)
+    }
+
+    /// Adds the `len` bytes read from `reader` to the upload of `hash`, which must have
+    /// received `offset` bytes so far. Returns how many bytes the upload has received.
+    ///
+    /// If the connection is closed midway, the server keeps the data it received, and the
+    /// upload can be resumed with `begin_upload`.
+    pub fn append<R>(&self, hash: &[u8], offset: u64, len: u64, reader: R) -> impl Future<Item = u64, Error = ClientError>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let hash = hash.to_vec();
+        self.request(move |id| {
+            let header = stream::once(Ok((id, Frame::Append(AppendMessage::new(hash, offset, len)))));
+            header.chain(body_frames(id, reader, len))
+        })
+        .and_then(appended)
+    }
+
+    /// Stores the data of the upload of `hash`, once all of it has been appended.
+    ///
+    /// The server refuses the data if it does not hash to `hash`, and drops the upload.
+    pub fn commit(&self, hash: &[u8]) -> impl Future<Item = (), Error = ClientError> {
+        let msg = CommitMessage::new(hash.to_vec());
+        self.request(move |id| stream::once(Ok((id, Frame::Commit(msg)))))
+            .and_then(expect_place_ok)
+    }
+
+    /// Fetches the data stored under `hash`, if there is any.
+    ///
+    /// Data too large to be sent at once is fetched `FETCH_PIECE` bytes at a time. The data
+    /// is checked against the hash before it is returned.
+    pub fn fetch(&self, hash: &[u8]) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let client = self.clone();
+        let hash = hash.to_vec();
+        let msg = FetchMessage::new(vec![hash.clone()]);
+        self.request(move |id| stream::once(Ok((id, Frame::Fetch(msg)))))
+            .and_then(move |reply| match reply {
+                Frame::Error(ref e) if e.code == ErrorCode::TooLarge => future::Either::A(client.fetch_pieces(hash, FETCH_PIECE)),
+                reply => future::Either::B(future::result(fetched(&hash, reply))),
+            })
+    }
+
+    /// Fetches the data stored under `hash` `piece` bytes at a time, then checks it.
+    fn fetch_pieces(&self, hash: Vec<u8>, piece: u64) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let client = self.clone();
+        future::loop_fn(Vec::new(), move |mut data: Vec<u8>| {
+            let hash = hash.clone();
+            client.fetch_range(&hash, data.len() as u64, piece)
+                .and_then(move |fetched| {
+                    let fetched = match fetched {
+                        Some(fetched) => fetched,
+                        // deleted while it was fetched
+                        None => return Ok(Loop::Break(None)),
+                    };
+                    let last = (fetched.len() as u64) < piece;
+                    data.extend(fetched);
+                    if !last {
+                        return Ok(Loop::Continue(data));
+                    }
+                    verified(&hash, data).map(|data| Loop::Break(Some(data)))
                 })
         })
-        .and_then(|(rx, _wx)| {
-            let mut buf = Vec::with_capacity(5);
-            buf.resize(5, 0);
-            read_exact(rx, buf)
-                .map(|(_, t)| println!("{}", str::from_utf8(&t).unwrap()))
-                .map_err(|e| eprintln!("failed to receive bytes {}", e))
-        });
-    Ok(Box::new(client))
+    }
+
+    /// Fetches at most `len` bytes of the data stored under `hash`, starting at `offset`,
+    /// if there is any data. Fewer bytes are returned at the end of the data.
+    ///
+    /// A part of the data cannot be checked against the hash, so callers should check the
+    /// whole data once they have it.
+    pub fn fetch_range(&self, hash: &[u8], offset: u64, len: u64) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let hash = hash.to_vec();
+        let msg = FetchRangeMessage::new(hash.clone(), offset, len);
+        self.request(move |id| stream::once(Ok((id, Frame::FetchRange(msg)))))
+            .and_then(move |reply| fetched_range(&hash, reply))
+    }
+
+    /// Deletes the data stored under `hash`, and the copies the server made of it on other
+    /// nodes, returning whether there was any.
+    pub fn delete(&self, hash: &[u8]) -> impl Future<Item = bool, Error = ClientError> {
+        let msg = DeleteMessage::new(hash.to_vec());
+        self.request(move |id| stream::once(Ok((id, Frame::Delete(msg)))))
+            .and_then(deleted)
+    }
+
+    /// Deletes the copy of the data of `hash` stored on the server, as a replica of data
+    /// deleted on another node. The server does not delete the other copies.
+    pub fn delete_replica(&self, hash: &[u8]) -> impl Future<Item = bool, Error = ClientError> {
+        let msg = DeleteMessage::replica(hash.to_vec());
+        self.request(move |id| stream::once(Ok((id, Frame::Delete(msg)))))
+            .and_then(deleted)
+    }
+
+    /// Describes the data stored under each of `hashes`, without transferring it.
+    ///
+    /// The stats are returned in the order of `hashes`, with `None` for the hashes under
+    /// which nothing is stored.
+    pub fn stat(&self, hashes: Vec<Vec<u8>>) -> impl Future<Item = Vec<Option<BlobStat>>, Error = ClientError> {
+        let msg = StatMessage::new(hashes.clone());
+        self.request(move |id| stream::once(Ok((id, Frame::Stat(msg)))))
+            .and_then(move |reply| stats(&hashes, reply))
+    }
+
+    /// Lists a page of the hashes whose hex encoding starts with `prefix`, starting after
+    /// `cursor`. The server decides how many hashes a page holds.
+    pub fn list(&self, prefix: &str, cursor: Option<Vec<u8>>) -> impl Future<Item = ListPage, Error = ClientError> {
+        let msg = ListMessage::new(prefix.to_string(), cursor, 0);
+        self.request(move |id| stream::once(Ok((id, Frame::List(msg)))))
+            .and_then(listed)
+    }
+
+    /// Lists all the hashes whose hex encoding starts with `prefix`, a page at a time.
+    pub fn list_all(&self, prefix: &str) -> impl Stream<Item = (Vec<u8>, u64), Error = ClientError> {
+        let client = self.clone();
+        let prefix = prefix.to_string();
+        stream::unfold(Some(None), move |cursor| {
+            let cursor = cursor?;
+            Some(client.list(&prefix, cursor).map(|page| (stream::iter_ok(page.entries), page.cursor.map(Some))))
+        })
+        .flatten()
+    }
+
+    /// Finds the stored hash whose hex encoding starts with `prefix`, if there is one.
+    ///
+    /// Fails with `ClientError::Ambiguous` if more than one hash starts with it.
+    pub fn resolve(&self, prefix: &str) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let prefix = prefix.to_string();
+        let msg = ResolveMessage::new(prefix.clone());
+        self.request(move |id| stream::once(Ok((id, Frame::Resolve(msg)))))
+            .and_then(move |reply| resolved(&prefix, reply))
+    }
+
+    /// Asks the server for the nodes of the overlay it knows closest to `target`.
+    ///
+    /// `sender` is the contact of the node asking, if the client is a node itself.
+    pub fn find_node(&self, sender: Option<Contact>, target: &[u8]) -> impl Future<Item = NodesMessage, Error = ClientError> {
+        let msg = FindNodeMessage::new(sender, target.to_vec());
+        self.request(move |id| stream::once(Ok((id, Frame::FindNode(msg)))))
+            .and_then(nodes)
+    }
+
+    /// Asks the server for at most `len` bytes of the data stored under `hash`, starting at
+    /// `offset`, or for the nodes that may know where it is stored.
+    ///
+    /// Unlike a fetch, the server only answers with data it stores itself.
+    pub fn find_value(&self, sender: Option<Contact>, hash: &[u8], offset: u64, len: u64) -> impl Future<Item = FoundValue, Error = ClientError> {
+        let hash = hash.to_vec();
+        let msg = FindValueMessage::new(sender, hash.clone(), offset, len);
+        self.request(move |id| stream::once(Ok((id, Frame::FindValue(msg)))))
+            .and_then(move |reply| found_value(&hash, reply))
+    }
+
+    /// Lets the server know that the node `sender` stores the data of `hash`.
+    pub fn store(&self, sender: Contact, hash: &[u8]) -> impl Future<Item = (), Error = ClientError> {
+        let msg = StoreMessage::new(sender, hash.to_vec());
+        self.request(move |id| stream::once(Ok((id, Frame::Store(msg)))))
+            .and_then(expect_place_ok)
+    }
+
+    /// Proves to the server that we hold `credentials`, returning what the server allows
+    /// us to do from now on.
+    pub fn authenticate(&self, credentials: &Credentials) -> impl Future<Item = Permissions, Error = ClientError> {
+        let client = self.clone();
+        let credentials = credentials.clone();
+        self.request(|id| stream::once(Ok((id, Frame::Hello(HelloMessage::new())))))
+            .and_then(challenged)
+            .and_then(move |nonce| {
+                let msg = AuthenticateMessage::new(credentials.name.clone(), credentials.respond(&nonce));
+                client.request(move |id| stream::once(Ok((id, Frame::Authenticate(msg)))))
+            })
+            .and_then(authenticated)
+    }
+
+    /// Checks whether the server stores anything under `hash`.
+    pub fn exists(&self, hash: &[u8]) -> impl Future<Item = bool, Error = ClientError> {
+        self.stat(vec![hash.to_vec()]).map(|stats| stats[0].is_some())
+    }
+}
+
+/// Splits the `len` bytes read from `reader` into body frames of request `id`.
+fn body_frames<R>(id: RequestId, reader: R, len: u64) -> impl Stream<Item = (RequestId, Frame), Error = io::Error>
+where
+    R: AsyncRead,
+{
+    let chunks = FramedRead::new(reader.take(len), BytesCodec::new());
+    stream::unfold((chunks, len), move |(chunks, remaining)| {
+        if remaining == 0 {
+            return None;
+        }
+        Some(chunks.into_future()
+            .map_err(|(e, _)| e)
+            .and_then(move |(chunk, chunks)| match chunk {
+                Some(chunk) => {
+                    let remaining = remaining - chunk.len() as u64;
+                    Ok(((id, Frame::Body(chunk.freeze())), (chunks, remaining)))
+                },
+                None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data is shorter than its length")),
+            }))
+    })
+}
+
+/// Returns the data of the reply to a fetch of `hash`, after checking it hashes to it.
+pub(crate) fn fetched(hash: &[u8], reply: Frame) -> Result<Option<Vec<u8>>, ClientError> {
+    match reply {
+        Frame::Data(msg) => {
+            if msg.hash != hash {
+                return Err(ClientError::Corrupted(hash.to_vec()));
+            }
+            verified(hash, Arc::try_unwrap(msg.data).unwrap_or_else(|data| data.to_vec())).map(Some)
+        },
+        Frame::NotFound(_) => Ok(None),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Returns `data` after checking it hashes to `hash`.
+pub(crate) fn verified(hash: &[u8], data: Vec<u8>) -> Result<Vec<u8>, ClientError> {
+    let mut hasher = KitapHasher::new();
+    hasher.input(&data);
+    if hasher.result().as_slice() != hash {
+        return Err(ClientError::Corrupted(hash.to_vec()));
+    }
+    Ok(data)
+}
+
+/// Returns the part of the data held by the reply to a ranged fetch of `hash`.
+pub(crate) fn fetched_range(hash: &[u8], reply: Frame) -> Result<Option<Vec<u8>>, ClientError> {
+    match reply {
+        Frame::Data(msg) => {
+            if msg.hash != hash {
+                return Err(ClientError::Protocol("Data does not match the requested hash".to_string()));
+            }
+            Ok(Some(Arc::try_unwrap(msg.data).unwrap_or_else(|data| data.to_vec())))
+        },
+        Frame::NotFound(_) => Ok(None),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+pub(crate) fn nodes(reply: Frame) -> Result<NodesMessage, ClientError> {
+    match reply {
+        Frame::Nodes(msg) => Ok(msg),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+pub(crate) fn found_value(hash: &[u8], reply: Frame) -> Result<FoundValue, ClientError> {
+    match reply {
+        Frame::Nodes(msg) => Ok(FoundValue::Nodes(msg)),
+        reply => fetched_range(hash, reply)?
+            .map(FoundValue::Data)
+            .ok_or_else(|| ClientError::Protocol("Unexpected response NotFound".to_string())),
+    }
+}
+
+/// Returns the nonce of the challenge the server replied to a hello with.
+pub(crate) fn challenged(reply: Frame) -> Result<Vec<u8>, ClientError> {
+    match reply {
+        Frame::Challenge(msg) => Ok(msg.nonce),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Returns what the server allows once it accepted the answer to its challenge.
+pub(crate) fn authenticated(reply: Frame) -> Result<Permissions, ClientError> {
+    match reply {
+        Frame::Authenticated(msg) => Ok(msg.permissions),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Tells from the reply to the beginning of an upload how much of it the server has, or
+/// whether the data is already stored.
+pub(crate) fn uploading(reply: Frame) -> Result<Option<u64>, ClientError> {
+    match reply {
+        Frame::UploadStatus(msg) => Ok(Some(msg.offset)),
+        Frame::PlaceOk(_) => Ok(None),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+pub(crate) fn appended(reply: Frame) -> Result<u64, ClientError> {
+    match reply {
+        Frame::UploadStatus(msg) => Ok(msg.offset),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Returns the stats of the reply to a stat of `hashes`, after checking it describes them.
+pub(crate) fn stats(hashes: &[Vec<u8>], reply: Frame) -> Result<Vec<Option<BlobStat>>, ClientError> {
+    match reply {
+        Frame::Stats(msg) => {
+            let described = msg.stats.iter().map(|(hash, _)| hash);
+            if !described.eq(hashes.iter()) {
+                return Err(ClientError::Protocol("Stats do not match the requested hashes".to_string()));
+            }
+            Ok(msg.stats.into_iter().map(|(_, stat)| stat).collect())
+        },
+        reply => Err(unexpected(reply)),
+    }
+}
+
+pub(crate) fn listed(reply: Frame) -> Result<ListPage, ClientError> {
+    match reply {
+        Frame::Listing(msg) => Ok(ListPage {
+            entries: msg.entries,
+            cursor: msg.cursor,
+        }),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Picks the hash an abbreviated one stands for from the reply to its resolution.
+pub(crate) fn resolved(prefix: &str, reply: Frame) -> Result<Option<Vec<u8>>, ClientError> {
+    match reply {
+        Frame::Resolved(mut msg) => match msg.hashes.len() {
+            0 => Ok(None),
+            1 => Ok(msg.hashes.pop()),
+            _ => Err(ClientError::Ambiguous {
+                prefix: prefix.to_string(),
+                candidates: msg.hashes,
+            }),
+        },
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Tells from the reply to a delete whether something was deleted.
+pub(crate) fn deleted(reply: Frame) -> Result<bool, ClientError> {
+    match reply {
+        Frame::Deleted(_) => Ok(true),
+        Frame::NotFound(_) => Ok(false),
+        reply => Err(unexpected(reply)),
+    }
 }
 
-fn main() -> Result<(), String> {
-    let matches = create_parser().get_matches();
+pub(crate) fn expect_place_ok(reply: Frame) -> Result<(), ClientError> {
+    match reply {
+        Frame::PlaceOk(_) => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Builds the error for a reply that does not answer the request it was sent for.
+pub(crate) fn unexpected(reply: Frame) -> ClientError {
+    match reply {
+        Frame::Error(e) => ClientError::Server {
+            code: e.code,
+            reason: e.reason,
+        },
+        reply => ClientError::Protocol(format!("Unexpected response {:?}", reply)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::cmp;
+    use std::io::{Read, Write};
+
+    use tokio::io::{read_exact, write_all};
+    use tokio::runtime::Runtime;
+
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::messages::{DataMessage, DeletedMessage, ErrorMessage, NotFoundMessage, PlaceOkMessage, StatsMessage};
+    use crate::messages::{Handshake, HANDSHAKE_LEN, PROTOCOL_VERSION};
+    use crate::store::tests::hash_of;
+    use crate::utils::bind;
+
+    /// Serves connections on a loopback port with the replies `answer` gives to each frame
+    /// received, returning the address of the port.
+    pub(crate) fn serve<F>(runtime: &mut Runtime, answer: F) -> SocketAddr
+    where
+        F: FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> + Send + 'static,
+    {
+        serve_over(runtime, None, answer)
+    }
+
+    /// Serves connections as `serve` does, over TLS if there is an `acceptor`.
+    pub(crate) fn serve_over<F>(runtime: &mut Runtime, acceptor: Option<TlsAcceptor>, answer: F) -> SocketAddr
+    where
+        F: FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> + Send + 'static,
+    {
+        let listener = bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let answer = Arc::new(Mutex::new(answer));
+        let server = listener.incoming()
+            .map_err(|e| panic!("Could not accept a connection: {}", e))
+            .for_each(move |sock| {
+                let answer = answer.clone();
+                match acceptor {
+                    Some(ref acceptor) => tokio::spawn(acceptor.accept(sock)
+                        .map_err(|e| debug!("TLS handshake failed: {}", e))
+                        .and_then(move |sock| answer_on(sock, answer))),
+                    None => tokio::spawn(answer_on(sock, answer)),
+                };
+                Ok(())
+            });
+        runtime.spawn(server);
+        addr
+    }
+
+    /// Agrees on the protocol with the client at the other end of `sock`, then replies to
+    /// its requests with `answer`.
+    fn answer_on<S, F>(sock: S, answer: Arc<Mutex<F>>) -> impl Future<Item = (), Error = ()>
+    where
+        S: AsyncRead + AsyncWrite,
+        F: FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)>,
+    {
+        read_exact(sock, [0; HANDSHAKE_LEN])
+            .and_then(|(sock, _)| write_all(sock, Handshake::to_bytes(PROTOCOL_VERSION)))
+            .and_then(move |(sock, _)| {
+                let (sink, frames) = Framed::new(sock, KitapCodec::new()).split();
+                let replies = frames
+                    .map(move |(id, frame)| stream::iter_ok::<_, io::Error>((*answer.lock().unwrap())(id, frame)))
+                    .flatten();
+                sink.send_all(replies)
+            })
+            .then(|_| Ok(()))
+    }
+
+    /// Answers requests as a server keeping blobs in memory would, without checking them
+    /// against their hash.
+    pub(crate) fn memory_store() -> impl FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> {
+        let mut blobs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        let mut placing: Option<(Vec<u8>, u64, Vec<u8>)> = None;
+        move |id, frame| {
+            let reply = match frame {
+                Frame::Place(msg) => {
+                    placing = Some((msg.hash, msg.datasize, Vec::new()));
+                    None
+                },
+                Frame::Body(data) => {
+                    placing.as_mut().unwrap().2.extend_from_slice(&data);
+                    None
+                },
+                Frame::Fetch(mut msg) => {
+                    let hash = msg.hashes.remove(0);
+                    Some(match blobs.get(&hash) {
+                        Some(data) => Frame::Data(DataMessage::new(hash, Arc::new(data.clone()))),
+                        None => Frame::NotFound(NotFoundMessage::new(hash)),
+                    })
+                },
+                Frame::Stat(msg) => {
+                    let stats = msg.hashes.into_iter()
+                        .map(|hash| {
+                            let stat = blobs.get(&hash).map(|data| BlobStat { size: data.len() as u64, stored_at: 0 });
+                            (hash, stat)
+                        })
+                        .collect();
+                    Some(Frame::Stats(StatsMessage::new(stats)))
+                },
+                Frame::Delete(msg) => Some(match blobs.remove(&msg.hash) {
+                    Some(_) => Frame::Deleted(DeletedMessage::new(msg.hash)),
+                    None => Frame::NotFound(NotFoundMessage::new(msg.hash)),
+                }),
+                frame => panic!("unexpected {:?}", frame),
+            };
+            let placed = match placing.take() {
+                Some((hash, len, data)) if data.len() as u64 == len => {
+                    blobs.insert(hash.clone(), data);
+                    Some(Frame::PlaceOk(PlaceOkMessage::new(hash)))
+                },
+                copying => {
+                    placing = copying;
+                    None
+                },
+            };
+            reply.or(placed).map(|reply| (id, reply)).into_iter().collect()
+        }
+    }
 
-    let host = matches
-        .value_of("host")
-        .expect("Host address not specified");
-    let port = matches.value_of("port").expect("Port not specified");
-    let addr = format!("{}:{}", host, port).parse().or(Err("Asd"))?;
+    /// Answers fetches of `data` as a server would if it was too large to be fetched at
+    /// once, counting the ranges asked for. The data is served under `hash`, whether it
+    /// matches it or not.
+    fn serve_in_ranges(hash: Vec<u8>, data: Vec<u8>, ranges: Arc<Mutex<usize>>) -> impl FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> {
+        move |id, frame| {
+            let reply = match frame {
+                Frame::Fetch(_) => Frame::Error(ErrorMessage::new(ErrorCode::TooLarge, "too large")),
+                Frame::FetchRange(ref msg) if msg.hash == hash => {
+                    *ranges.lock().unwrap() += 1;
+                    let start = cmp::min(msg.offset, data.len() as u64) as usize;
+                    let end = cmp::min(msg.offset.saturating_add(msg.length), data.len() as u64) as usize;
+                    Frame::Data(DataMessage::new(hash.clone(), Arc::new(data[start..end].to_vec())))
+                },
+                Frame::FetchRange(msg) => Frame::NotFound(NotFoundMessage::new(msg.hash)),
+                frame => panic!("unexpected {:?}", frame),
+            };
+            vec![(id, reply)]
+        }
+    }
+
+    #[test]
+    fn fetches_data_too_large_to_be_sent_at_once_in_ranges() {
+        let mut runtime = Runtime::new().unwrap();
+        let data: Vec<u8> = (0..2500).map(|i| i as u8).collect();
+        let hash = hash_of(&data);
+        let ranges = Arc::new(Mutex::new(0));
+        let addr = serve(&mut runtime, serve_in_ranges(hash.clone(), data.clone(), ranges.clone()));
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+
+        assert_eq!(runtime.block_on(client.fetch(&hash)).unwrap(), Some(data.clone()));
+        assert_eq!(*ranges.lock().unwrap(), 1);
+        assert_eq!(runtime.block_on(client.fetch_pieces(hash.clone(), 1000)).unwrap(), Some(data.clone()));
+        assert_eq!(*ranges.lock().unwrap(), 4);
+        assert_eq!(runtime.block_on(client.fetch_pieces(hash.clone(), 500)).unwrap(), Some(data.clone()));
+        assert_eq!(*ranges.lock().unwrap(), 10);
+        assert_eq!(runtime.block_on(client.fetch(&[1; 64])).unwrap(), None);
+
+        let mut blocking = crate::blocking::Client::connect(addr).unwrap();
+        assert_eq!(blocking.fetch(&hash).unwrap(), Some(data.clone()));
+        assert_eq!(blocking.fetch_pieces(&hash, 1000).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn checks_data_fetched_in_ranges() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut data = vec![7; 2000];
+        let hash = hash_of(&data);
+        data[1500] = 8;
+        let addr = serve(&mut runtime, serve_in_ranges(hash.clone(), data, Arc::new(Mutex::new(0))));
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+        match runtime.block_on(client.fetch_pieces(hash.clone(), 1000)) {
+            Err(ClientError::Corrupted(corrupted)) => assert_eq!(corrupted, hash),
+            res => panic!("unexpected {:?}", res),
+        }
+        let mut blocking = crate::blocking::Client::connect(addr).unwrap();
+        match blocking.fetch(&hash) {
+            Err(ClientError::Corrupted(corrupted)) => assert_eq!(corrupted, hash),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn places_and_fetches_data() {
+        let mut runtime = Runtime::new().unwrap();
+        let addr = serve(&mut runtime, memory_store());
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+        let data = b"placed, then fetched".to_vec();
+
+        let hash = runtime.block_on(client.place(data.clone())).unwrap();
+        assert_eq!(hash.to_vec(), hash_of(&data));
+        assert!(runtime.block_on(client.exists(&hash)).unwrap());
+        assert_eq!(runtime.block_on(client.fetch(&hash)).unwrap(), Some(data.clone()));
+        let stats = runtime.block_on(client.stat(vec![hash.to_vec(), vec![0; 64]])).unwrap();
+        assert_eq!(stats.iter().map(|stat| stat.map(|stat| stat.size)).collect::<Vec<_>>(), vec![Some(data.len() as u64), None]);
+
+        assert!(runtime.block_on(client.delete(&hash)).unwrap());
+        assert!(!runtime.block_on(client.exists(&hash)).unwrap());
+        assert_eq!(runtime.block_on(client.fetch(&hash)).unwrap(), None);
+        assert!(!runtime.block_on(client.delete(&hash)).unwrap());
+    }
+
+    #[test]
+    fn matches_replies_to_requests_by_id() {
+        let mut runtime = Runtime::new().unwrap();
+        let (first, second) = (b"asked for first".to_vec(), b"asked for second".to_vec());
+        let blobs = [(hash_of(&first), first.clone()), (hash_of(&second), second.clone())];
+        let mut held = None;
+        // the first request is answered after the second
+        let addr = serve(&mut runtime, move |id, frame| {
+            let hash = match frame {
+                Frame::Fetch(mut msg) => msg.hashes.remove(0),
+                frame => panic!("unexpected {:?}", frame),
+            };
+            let data = blobs.iter().find(|(h, _)| *h == hash).unwrap().1.clone();
+            let reply = (id, Frame::Data(DataMessage::new(hash, Arc::new(data))));
+            match held.take() {
+                None => {
+                    held = Some(reply);
+                    Vec::new()
+                },
+                Some(first) => vec![reply, first],
+            }
+        });
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+        let fetches = client.fetch(&hash_of(&first)).join(client.fetch(&hash_of(&second)));
+        assert_eq!(runtime.block_on(fetches).unwrap(), (Some(first), Some(second)));
+    }
+
+    #[test]
+    fn refuses_data_that_does_not_match_its_hash() {
+        let mut runtime = Runtime::new().unwrap();
+        let data = b"not what was asked for".to_vec();
+        let addr = serve(&mut runtime, move |id, frame| match frame {
+            // the data is sent under the hash it was asked for, or under its own
+            Frame::Fetch(mut msg) => {
+                let hash = msg.hashes.remove(0);
+                let hash = if hash[0] == 0 { hash } else { hash_of(&data) };
+                vec![(id, Frame::Data(DataMessage::new(hash, Arc::new(data.clone()))))]
+            },
+            frame => panic!("unexpected {:?}", frame),
+        });
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+        for hash in [vec![0; 64], vec![1; 64]] {
+            match runtime.block_on(client.fetch(&hash)) {
+                Err(ClientError::Corrupted(corrupted)) => assert_eq!(corrupted, hash),
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn fails_the_requests_of_closed_connections() {
+        let mut runtime = Runtime::new().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // agrees on the protocol, then hangs up on the first request
+        let server = std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut handshake = [0; HANDSHAKE_LEN];
+            sock.read_exact(&mut handshake).unwrap();
+            sock.write_all(&Handshake::to_bytes(PROTOCOL_VERSION)).unwrap();
+            sock.read_exact(&mut [0; 1]).unwrap();
+        });
+        let client = runtime.block_on(Client::connect(&addr)).unwrap();
+        assert!(!client.is_closed());
 
-    let thread = match matches.subcommand() {
-        ("fetch", Some(submatches)) => fetch(addr, submatches)?,
-        ("place", Some(submatches)) => place(addr, submatches)?,
-        _ => Box::new(future::err(()))
-    };
-    tokio::run(thread);
-    Ok(())
+        match runtime.block_on(client.fetch(&[0; 64])) {
+            Err(ClientError::Closed) => (),
+            res => panic!("unexpected {:?}", res),
+        }
+        server.join().unwrap();
+        assert!(client.is_closed());
+        match runtime.block_on(client.exists(&[0; 64])) {
+            Err(ClientError::Closed) => (),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,627 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Loop};
+use futures::{stream, Future, Stream};
+
+use hex::encode;
+
+use log::{debug, info};
+
+use tokio::timer::Timeout;
+
+use crate::client::{Client, ClientError, FoundValue};
+use crate::hash::{KitapHasher, HASH_SIZE};
+use crate::messages::NodesMessage;
+use crate::tls::Connector;
+use crate::utils::BoxedFuture;
+
+/// How many nodes a bucket of the routing table holds, and how many nodes a lookup ends
+/// with.
+pub const K: usize = 8;
+
+/// How many nodes a lookup queries at once.
+const ALPHA: usize = 3;
+
+/// How long a node has to answer a query before it is given up on.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often nodes announce the blobs they store again, so that the nodes that joined
+/// since learn where they are stored.
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long an announcement is remembered. A node that stops announcing a blob, because it
+/// no longer stores it or left the overlay, is forgotten as storing it after a few missed
+/// announcements.
+pub const PROVIDER_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// A node of the overlay: its id, and the address it is reached at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub id: Vec<u8>,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    pub fn new(id: Vec<u8>, addr: SocketAddr) -> Contact {
+        Contact {
+            id,
+            addr,
+        }
+    }
+
+    /// The contact of the node reached at `addr`, whose id is the hash of that address.
+    pub fn from_addr(addr: SocketAddr) -> Contact {
+        let mut hasher = KitapHasher::new();
+        hasher.input(addr.to_string());
+        Contact::new(hasher.result().to_vec(), addr)
+    }
+
+    /// Whether the id of the contact is the hash of its address. Contacts are only
+    /// accepted from other nodes if it is, so that nodes cannot pick their place in the
+    /// overlay, next to the hashes they want to hear about.
+    pub fn is_genuine(&self) -> bool {
+        *self == Contact::from_addr(self.addr)
+    }
+}
+
+/// The XOR distance between two ids. Distances compare as big endian numbers.
+pub fn distance(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b).map(|(a, b)| a ^ b).collect()
+}
+
+/// The number of leading bits `a` and `b` have in common, or `None` if they are equal.
+fn common_prefix_len(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter().zip(b)
+        .position(|(a, b)| a != b)
+        .map(|i| i * 8 + (a[i] ^ b[i]).leading_zeros() as usize)
+}
+
+/// The nodes a node knows about, kept in k-buckets.
+///
+/// The nodes whose id shares exactly `i` leading bits with ours go in bucket `i`, so the
+/// table knows most about the part of the id space close to us. A full bucket keeps the
+/// nodes it has, as those that have been up for long are the likeliest to stay up: a new
+/// node only takes the place of the least recently seen one if that one fails to answer.
+#[derive(Debug)]
+pub struct RoutingTable {
+    own: Vec<u8>,
+    /// Least recently seen nodes first
+    buckets: Vec<VecDeque<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: Vec<u8>) -> RoutingTable {
+        RoutingTable {
+            own,
+            buckets: (0..HASH_SIZE * 8).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    /// Records that `contact` was heard from. Returns whether the table holds it.
+    pub fn insert(&mut self, contact: Contact) -> bool {
+        if contact.id.len() != HASH_SIZE {
+            return false;
+        }
+        let bucket = match common_prefix_len(&self.own, &contact.id) {
+            Some(i) => &mut self.buckets[i],
+            None => return false,
+        };
+        if let Some(i) = bucket.iter().position(|c| c.id == contact.id) {
+            bucket.remove(i);
+        } else if bucket.len() >= K {
+            return false;
+        }
+        bucket.push_back(contact);
+        true
+    }
+
+    /// The least recently seen node of the bucket the node with id `id` belongs in, if that
+    /// bucket is full and does not hold it.
+    pub fn stale(&self, id: &[u8]) -> Option<Contact> {
+        if id.len() != HASH_SIZE {
+            return None;
+        }
+        let bucket = &self.buckets[common_prefix_len(&self.own, id)?];
+        if bucket.len() < K || bucket.iter().any(|c| c.id == id) {
+            return None;
+        }
+        bucket.front().cloned()
+    }
+
+    /// Removes the node with id `id`, if the table holds it.
+    pub fn remove(&mut self, id: &[u8]) {
+        if let Some(i) = common_prefix_len(&self.own, id) {
+            self.buckets[i].retain(|c| c.id != id);
+        }
+    }
+
+    /// Returns at most `count` of the nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &[u8], count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flatten().cloned().collect();
+        contacts.sort_by_key(|c| distance(&c.id, target));
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+/// The state of an iterative lookup.
+struct Lookup {
+    target: Vec<u8>,
+    /// The closest nodes found so far, closest first
+    shortlist: Vec<Contact>,
+    queried: HashSet<Vec<u8>>,
+    /// The nodes found to store the data of the target
+    providers: Vec<Contact>,
+}
+
+impl Lookup {
+    /// Adds the nodes of a reply to the lookup. Only the `K` closest are kept, and the
+    /// nodes whose id is not the hash of their address are left out.
+    fn merge(&mut self, own: &[u8], reply: NodesMessage) {
+        for contact in reply.nodes {
+            if contact.is_genuine() && contact.id != own && !self.shortlist.iter().any(|c| c.id == contact.id) {
+                self.shortlist.push(contact);
+            }
+        }
+        let target = &self.target;
+        self.shortlist.sort_by_key(|c| distance(&c.id, target));
+        self.shortlist.truncate(K);
+        for contact in reply.providers {
+            if contact.is_genuine() && contact.id != own && !self.providers.iter().any(|c| c.id == contact.id) {
+                self.providers.push(contact);
+            }
+        }
+    }
+}
+
+/// The nodes known to store the data of each hash, with when they announced it, most
+/// recently announced last.
+type Providers = HashMap<Vec<u8>, Vec<(Contact, Instant)>>;
+
+/// This node's view of the overlay the kitapd nodes form.
+///
+/// Nodes are keyed by ids in the space of blob hashes, and the nodes closest to a hash
+/// remember which nodes store its data: a node that stores a blob announces it to them.
+/// Lookups query the nodes they know closest to a hash for closer ones, until they find
+/// the data or run out of closer nodes.
+///
+/// Unlike in Kademlia, STORE does not copy the data itself to the closest nodes: it only
+/// records where the data is. Blobs stay on the nodes they were placed on and on their
+/// replicas, which are the nodes closest to the hash when the overlay is used for
+/// replication, and are fetched from there.
+#[derive(Debug, Clone)]
+pub struct Dht {
+    own: Contact,
+    table: Arc<Mutex<RoutingTable>>,
+    /// The ids of the stale nodes being checked before they are evicted
+    pinging: Arc<Mutex<HashSet<Vec<u8>>>>,
+    providers: Arc<Mutex<Providers>>,
+    connector: Connector,
+}
+
+impl Dht {
+    /// Creates the view of the overlay of the node `own`, knowing no other node yet.
+    ///
+    /// Other nodes are reached through `connector`.
+    pub fn new(own: Contact, connector: Connector) -> Dht {
+        Dht {
+            table: Arc::new(Mutex::new(RoutingTable::new(own.id.clone()))),
+            pinging: Arc::new(Mutex::new(HashSet::new())),
+            own,
+            providers: Arc::new(Mutex::new(HashMap::new())),
+            connector,
+        }
+    }
+
+    /// The contact of this node.
+    pub fn contact(&self) -> &Contact {
+        &self.own
+    }
+
+    /// How other nodes are reached.
+    pub fn connector(&self) -> &Connector {
+        &self.connector
+    }
+
+    /// Records that `contact` was heard from.
+    ///
+    /// If its bucket is full, the least recently seen node of the bucket is pinged in the
+    /// background, and `contact` takes its place if it does not answer. Must be called on
+    /// a tokio runtime.
+    pub fn observe(&self, contact: Contact) {
+        let stale = {
+            let mut table = self.table.lock().unwrap();
+            if table.insert(contact.clone()) {
+                debug!("Node {} is in the routing table", contact.addr);
+                return;
+            }
+            match table.stale(&contact.id) {
+                Some(stale) => stale,
+                None => return,
+            }
+        };
+        // a single ping decides for all the nodes waiting on the same stale node
+        if self.pinging.lock().unwrap().insert(stale.id.clone()) {
+            tokio::spawn(self.evict_if_down(stale, contact));
+        }
+    }
+
+    /// Pings `stale`, and replaces it with `newcomer` in the routing table if it does not
+    /// answer. A node that answers is kept, as the most recently seen of its bucket.
+    fn evict_if_down(&self, stale: Contact, newcomer: Contact) -> BoxedFuture<(), ()> {
+        let dht = self.clone();
+        let (sender, target) = (Some(self.own.clone()), self.own.id.clone());
+        Box::new(self.ask(stale.clone(), move |client| client.find_node(sender, &target))
+            .map(move |reply| {
+                dht.pinging.lock().unwrap().remove(&stale.id);
+                match reply {
+                    Some(_) => debug!("Node {} answered, leaving out node {}", stale.addr, newcomer.addr),
+                    None => dht.observe(newcomer),
+                }
+            }))
+    }
+
+    /// Removes a node that failed to answer from the routing table.
+    fn forget(&self, contact: &Contact) {
+        debug!("Forgetting node {}", contact.addr);
+        self.table.lock().unwrap().remove(&contact.id);
+    }
+
+    /// Returns at most `count` of the known nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &[u8], count: usize) -> Vec<Contact> {
+        self.table.lock().unwrap().closest(target, count)
+    }
+
+    /// Records that `provider` stores the data of `hash`, for `PROVIDER_TTL`.
+    pub fn add_provider(&self, hash: Vec<u8>, provider: Contact) {
+        let mut providers = self.providers.lock().unwrap();
+        let known = providers.entry(hash).or_default();
+        known.retain(|(c, _)| c.id != provider.id);
+        if known.len() >= K {
+            known.remove(0);
+        }
+        known.push((provider, Instant::now()));
+    }
+
+    /// The nodes known to store the data of `hash`.
+    pub fn providers(&self, hash: &[u8]) -> Vec<Contact> {
+        self.providers_at(hash, Instant::now())
+    }
+
+    /// The nodes whose announcement that they store the data of `hash` is still remembered
+    /// at `now`.
+    fn providers_at(&self, hash: &[u8], now: Instant) -> Vec<Contact> {
+        match self.providers.lock().unwrap().get(hash) {
+            Some(known) => known.iter()
+                .filter(|(_, announced)| now.duration_since(*announced) < PROVIDER_TTL)
+                .map(|(c, _)| c.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Forgets the announcements older than `PROVIDER_TTL`.
+    pub fn expire(&self) {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) {
+        let mut providers = self.providers.lock().unwrap();
+        providers.retain(|_, known| {
+            known.retain(|(_, announced)| now.duration_since(*announced) < PROVIDER_TTL);
+            !known.is_empty()
+        });
+    }
+
+    /// The reply of this node to a lookup of `target`.
+    pub fn neighbours(&self, target: &[u8]) -> NodesMessage {
+        NodesMessage::new(self.own.clone(), self.closest(target, K), self.providers(target))
+    }
+
+    /// Sends the request built by `request` to `contact`, giving up on it if it cannot be
+    /// reached or does not answer in time.
+    fn ask<F, R>(&self, contact: Contact, request: F) -> BoxedFuture<Option<R::Item>, ()>
+    where
+        F: FnOnce(Client) -> R + Send + 'static,
+        R: Future<Error = ClientError> + Send + 'static,
+        R::Item: Send + 'static,
+    {
+        let dht = self.clone();
+        let query = self.connector.connect(&contact.addr).and_then(request);
+        Box::new(Timeout::new(query, QUERY_TIMEOUT)
+            .then(move |res| match res {
+                Ok(reply) => {
+                    dht.observe(contact);
+                    Ok(Some(reply))
+                },
+                Err(e) => {
+                    debug!("Node {} did not answer: {}", contact.addr, e);
+                    dht.forget(&contact);
+                    Ok(None)
+                },
+            }))
+    }
+
+    /// Asks `contact` for the nodes closest to `target`, or for a part of the data stored
+    /// under `target` if `range` is given.
+    fn query(&self, contact: Contact, target: Vec<u8>, range: Option<(u64, u64)>) -> BoxedFuture<(Contact, Option<FoundValue>), ()> {
+        let sender = Some(self.own.clone());
+        Box::new(self.ask(contact.clone(), move |client| match range {
+            Some((offset, len)) => future::Either::A(client.find_value(sender, &target, offset, len)),
+            None => future::Either::B(client.find_node(sender, &target).map(FoundValue::Nodes)),
+        })
+        .map(move |reply| (contact, reply)))
+    }
+
+    /// Looks iteratively for the nodes closest to `target`, querying `ALPHA` nodes at a
+    /// time, until the `K` closest nodes found have all been queried.
+    ///
+    /// If `range` is given, the nodes are asked for that part of the data stored under
+    /// `target`, and the lookup stops at the first node that sends it.
+    fn lookup(&self, target: Vec<u8>, range: Option<(u64, u64)>) -> BoxedFuture<(Lookup, Option<Vec<u8>>), ()> {
+        let lookup = Lookup {
+            shortlist: self.closest(&target, K),
+            target,
+            queried: HashSet::new(),
+            providers: Vec::new(),
+        };
+        let dht = self.clone();
+        Box::new(future::loop_fn(lookup, move |mut lookup| {
+            let batch: Vec<Contact> = lookup.shortlist.iter()
+                .filter(|c| !lookup.queried.contains(&c.id))
+                .take(ALPHA)
+                .cloned()
+                .collect();
+            if batch.is_empty() {
+                return future::Either::A(future::ok(Loop::Break((lookup, None))));
+            }
+            let queries: Vec<_> = batch.into_iter()
+                .map(|contact| {
+                    lookup.queried.insert(contact.id.clone());
+                    dht.query(contact, lookup.target.clone(), range)
+                })
+                .collect();
+            let own = dht.own.id.clone();
+            future::Either::B(future::join_all(queries).map(move |replies| {
+                for (contact, reply) in replies {
+                    match reply {
+                        Some(FoundValue::Data(data)) => return Loop::Break((lookup, Some(data))),
+                        Some(FoundValue::Nodes(reply)) => lookup.merge(&own, reply),
+                        None => lookup.shortlist.retain(|c| c.id != contact.id),
+                    }
+                }
+                Loop::Continue(lookup)
+            }))
+        }))
+    }
+
+    /// Finds the nodes of the overlay closest to `target`, closest first.
+    pub fn lookup_nodes(&self, target: Vec<u8>) -> BoxedFuture<Vec<Contact>, ()> {
+        Box::new(self.lookup(target, None).map(|(lookup, _)| lookup.shortlist))
+    }
+
+    /// Finds at most `len` bytes of the data stored under `hash` in the overlay, starting
+    /// at `offset`.
+    ///
+    /// The nodes closest to the hash are asked first, then the nodes they know to store
+    /// the data. The data is not checked against the hash.
+    pub fn find_value(&self, hash: Vec<u8>, offset: u64, len: u64) -> BoxedFuture<Option<Vec<u8>>, ()> {
+        let dht = self.clone();
+        let range = Some((offset, len));
+        Box::new(self.lookup(hash.clone(), range)
+            .and_then(move |(lookup, data)| {
+                if data.is_some() {
+                    return future::Either::A(future::ok(data));
+                }
+                let Lookup { mut providers, queried, .. } = lookup;
+                for contact in dht.providers(&hash) {
+                    if !providers.iter().any(|c| c.id == contact.id) {
+                        providers.push(contact);
+                    }
+                }
+                // providers that were queried already answered they do not have the data
+                providers.retain(|c| c.id != dht.own.id && !queried.contains(&c.id));
+                debug!("Asking {} providers for {}", providers.len(), encode(&hash));
+                future::Either::B(stream::iter_ok(providers)
+                    .and_then(move |contact| dht.query(contact, hash.clone(), range))
+                    .filter_map(|(_, reply)| match reply {
+                        Some(FoundValue::Data(data)) => Some(data),
+                        _ => None,
+                    })
+                    .into_future()
+                    .map(|(data, _)| data)
+                    .map_err(|_| ()))
+            }))
+    }
+
+    /// Lets the nodes closest to `hash` know that this node stores its data.
+    ///
+    /// Only the announcement is stored on those nodes, not the data.
+    pub fn announce(&self, hash: Vec<u8>) -> BoxedFuture<(), ()> {
+        let dht = self.clone();
+        Box::new(self.lookup_nodes(hash.clone())
+            .and_then(move |closest| {
+                let stores: Vec<_> = closest.into_iter()
+                    .map(|contact| {
+                        let (sender, hash) = (dht.own.clone(), hash.clone());
+                        dht.ask(contact, move |client| client.store(sender, &hash))
+                    })
+                    .collect();
+                future::join_all(stores).map(move |stored| {
+                    let count = stored.iter().filter(|s| s.is_some()).count();
+                    debug!("Announced {} to {} nodes", encode(&hash), count);
+                })
+            }))
+    }
+
+    /// Joins the overlay through the nodes reached at `addrs`, and looks for the nodes
+    /// closest to this one to fill the routing table.
+    pub fn bootstrap(&self, addrs: Vec<SocketAddr>) -> BoxedFuture<(), ()> {
+        let dht = self.clone();
+        let greetings: Vec<_> = addrs.into_iter()
+            .map(|addr| {
+                let dht = dht.clone();
+                let (sender, target) = (Some(dht.own.clone()), dht.own.id.clone());
+                let greeting = dht.connector.connect(&addr)
+                    .and_then(move |client| client.find_node(sender, &target));
+                Timeout::new(greeting, QUERY_TIMEOUT)
+                    .then(move |res| {
+                        match res {
+                            Ok(ref reply) if !reply.sender.is_genuine() => {
+                                info!("Node {} does not go by the id of its address", addr)
+                            },
+                            Ok(reply) => dht.observe(reply.sender),
+                            Err(e) => info!("Could not reach node {}: {}", addr, e),
+                        }
+                        Ok(())
+                    })
+            })
+            .collect();
+        Box::new(future::join_all(greetings)
+            .and_then(move |_| dht.lookup_nodes(dht.own.id.clone()))
+            .map(|closest| info!("Joined the overlay, knowing {} nodes", closest.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener as StdTcpListener;
+
+    use tokio::runtime::Runtime;
+
+    use crate::client::tests::serve;
+    use crate::messages::Frame;
+
+    /// A node of `own`'s bucket 0, told apart from the others of the bucket by `n`.
+    fn far_contact(own: &[u8], n: u8, addr: SocketAddr) -> Contact {
+        let mut id = own.to_vec();
+        id[0] ^= 0x80;
+        id[HASH_SIZE - 1] ^= n;
+        Contact::new(id, addr)
+    }
+
+    /// An address nothing listens on.
+    fn dead_addr() -> SocketAddr {
+        StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn full_buckets_name_their_least_recently_seen_node() {
+        let own = vec![0; HASH_SIZE];
+        let mut table = RoutingTable::new(own.clone());
+        let addr = dead_addr();
+        for n in 0..K as u8 {
+            assert_eq!(table.stale(&far_contact(&own, 0xff, addr).id), None);
+            assert!(table.insert(far_contact(&own, n, addr)));
+        }
+        let newcomer = far_contact(&own, 0xff, addr);
+        assert!(!table.insert(newcomer.clone()));
+        assert_eq!(table.stale(&newcomer.id), Some(far_contact(&own, 0, addr)));
+        assert!(table.insert(far_contact(&own, 0, addr)));
+        assert_eq!(table.stale(&newcomer.id), Some(far_contact(&own, 1, addr)));
+        assert_eq!(table.stale(&far_contact(&own, 1, addr).id), None);
+        // other buckets still have room
+        let mut near = own.clone();
+        near[0] = 0x01;
+        assert_eq!(table.stale(&near), None);
+        assert!(table.insert(Contact::new(near, addr)));
+    }
+
+    #[test]
+    fn evicts_stale_nodes_that_do_not_answer() {
+        let mut runtime = Runtime::new().unwrap();
+        let own = vec![0; HASH_SIZE];
+        let dht = Dht::new(Contact::new(own.clone(), dead_addr()), Connector::Plain);
+        let addr = dead_addr();
+        let newcomer = far_contact(&own, 0xff, addr);
+        let evicted = runtime.block_on(future::lazy(move || {
+            for n in 0..K as u8 {
+                dht.observe(far_contact(&own, n, addr));
+            }
+            dht.evict_if_down(far_contact(&own, 0, addr), newcomer.clone())
+                .map(move |_| (dht, own, newcomer))
+        }));
+        let (dht, own, newcomer) = evicted.unwrap();
+        let bucket = dht.closest(&newcomer.id, 2 * K);
+        assert_eq!(bucket.len(), K);
+        assert!(bucket.contains(&newcomer));
+        assert!(!bucket.contains(&far_contact(&own, 0, addr)));
+    }
+
+    #[test]
+    fn keeps_stale_nodes_that_answer() {
+        let mut runtime = Runtime::new().unwrap();
+        let answering = serve(&mut runtime, |id, frame| match frame {
+            Frame::FindNode(_) => {
+                let sender = Contact::from_addr(dead_addr());
+                vec![(id, Frame::Nodes(NodesMessage::new(sender, Vec::new(), Vec::new())))]
+            },
+            frame => panic!("unexpected {:?}", frame),
+        });
+        let stale = Contact::from_addr(answering);
+        let mut own = stale.id.clone();
+        own[0] ^= 0x80;
+        let dht = Dht::new(Contact::new(own.clone(), dead_addr()), Connector::Plain);
+        let addr = dead_addr();
+        let newcomer = far_contact(&own, 0xff, addr);
+        let kept = runtime.block_on(future::lazy(move || {
+            dht.observe(stale.clone());
+            for n in 1..K as u8 {
+                dht.observe(far_contact(&own, n, addr));
+            }
+            assert_eq!(dht.table.lock().unwrap().stale(&newcomer.id), Some(stale.clone()));
+            dht.evict_if_down(stale.clone(), newcomer.clone())
+                .map(move |_| (dht, stale, newcomer))
+        }));
+        let (dht, stale, newcomer) = kept.unwrap();
+        let table = dht.table.lock().unwrap();
+        assert_eq!(table.stale(&newcomer.id), Some(far_contact(&dht.own.id, 1, newcomer.addr)));
+        let bucket = table.closest(&newcomer.id, 2 * K);
+        assert!(bucket.contains(&stale));
+        assert!(!bucket.contains(&newcomer));
+    }
+
+    #[test]
+    fn forgets_announcements_that_are_not_renewed() {
+        let dht = Dht::new(Contact::from_addr(dead_addr()), Connector::Plain);
+        let (hash, other) = (vec![1; HASH_SIZE], vec![2; HASH_SIZE]);
+        let provider = Contact::from_addr(dead_addr());
+        let announced = Instant::now();
+        dht.add_provider(hash.clone(), provider.clone());
+        dht.add_provider(other.clone(), provider.clone());
+
+        assert_eq!(dht.providers(&hash), vec![provider.clone()]);
+        assert_eq!(dht.providers_at(&hash, announced + PROVIDER_TTL / 2), vec![provider.clone()]);
+        let expired = Instant::now() + PROVIDER_TTL;
+        assert_eq!(dht.providers_at(&hash, expired), Vec::new());
+
+        // the announcement of `other` was renewed since, that of `hash` was not
+        dht.providers.lock().unwrap().get_mut(&other).unwrap()[0].1 = announced + PROVIDER_TTL / 2;
+        dht.expire_at(expired);
+        let providers = dht.providers.lock().unwrap();
+        assert!(!providers.contains_key(&hash));
+        assert_eq!(providers[&other].len(), 1);
+    }
+
+    #[test]
+    fn looks_up_only_nodes_that_go_by_the_id_of_their_address() {
+        let own = Contact::from_addr(dead_addr());
+        let genuine = Contact::from_addr(dead_addr());
+        let forged = Contact::new(vec![3; HASH_SIZE], dead_addr());
+        let mut lookup = Lookup {
+            target: vec![3; HASH_SIZE],
+            shortlist: Vec::new(),
+            queried: HashSet::new(),
+            providers: Vec::new(),
+        };
+        let nodes = vec![genuine.clone(), forged.clone(), own.clone()];
+        lookup.merge(&own.id, NodesMessage::new(genuine.clone(), nodes.clone(), nodes));
+        assert_eq!(lookup.shortlist, vec![genuine.clone()]);
+        assert_eq!(lookup.providers, vec![genuine]);
+    }
+}
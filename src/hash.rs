@@ -9,6 +9,12 @@ pub struct KitapHasher {
     hasher: Sha3_512
 }
 
+impl Default for KitapHasher {
+    fn default() -> KitapHasher {
+        KitapHasher::new()
+    }
+}
+
 impl KitapHasher {
     pub fn new() -> KitapHasher {
         let hasher = Sha3_512::new();
@@ -22,6 +28,6 @@ impl KitapHasher {
     }
 
     pub fn result(self) -> KitapHash {
-        self.hasher.result().into()
+        self.hasher.result()
     }
 }
@@ -1,4 +1,13 @@
 pub mod utils;
+pub mod auth;
 pub mod mapper;
 pub mod messages;
 pub mod hash;
+pub mod store;
+pub mod upload;
+pub mod dht;
+pub mod replication;
+pub mod client;
+pub mod tls;
+pub mod shard;
+pub mod blocking;
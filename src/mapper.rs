@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::borrow::Borrow;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use futures::sink::Sink;
@@ -7,7 +10,10 @@ use futures::sync::mpsc;
 use futures::sync::mpsc::{Receiver, Sender};
 use futures::Future;
 
-use log::{debug, info};
+use log::{debug, info, warn};
+
+use crate::store::{BlobStat, BlobStore, MemoryStore, SpoolFile};
+use crate::utils::has_hex_prefix;
 
 #[derive(Debug)]
 pub struct DataContents<T>
@@ -15,12 +21,26 @@ pub struct DataContents<T>
     pub data: Arc<T>,
 }
 
+/// A page of the keys held by the mapper, in ascending order.
+#[derive(Debug)]
+pub struct ListingContents<K>
+{
+    pub keys: Vec<(K, BlobStat)>,
+    /// Whether more keys follow the last one of the page
+    pub more: bool,
+}
+
 #[derive(Debug)]
-pub enum MapperReply<T>
+pub enum MapperReply<K, T>
 {
     Data(DataContents<T>),
+    /// A part of a value
+    Range(Vec<u8>),
+    Stat(BlobStat),
+    Listing(ListingContents<K>),
     Ok,
     NotFound,
+    Error(String),
 }
 
 #[derive(Debug)]
@@ -31,6 +51,16 @@ where
     pub key: K,
 }
 
+#[derive(Debug)]
+struct FetchRangeContents<K>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub key: K,
+    pub offset: u64,
+    pub len: u64,
+}
+
 #[derive(Debug)]
 struct PlaceContents<K, T>
 where
@@ -40,13 +70,53 @@ where
     pub data: T,
 }
 
+#[derive(Debug)]
+struct StatContents<K>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub key: K,
+}
+
+#[derive(Debug)]
+struct ListContents<K>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub prefix: String,
+    pub after: Option<K>,
+    pub limit: usize,
+}
+
+#[derive(Debug)]
+struct RemoveContents<K>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub key: K,
+}
+
+#[derive(Debug)]
+struct PlaceSpooledContents<K>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub key: K,
+    pub spool: SpoolFile,
+}
+
 #[derive(Debug)]
 enum Contents<K, T>
 where
     K: std::hash::Hash + std::cmp::Eq,
 {
     Fetch(FetchContents<Arc<K>>),
+    FetchRange(FetchRangeContents<Arc<K>>),
     Place(PlaceContents<K, T>),
+    PlaceSpooled(PlaceSpooledContents<K>),
+    Remove(RemoveContents<K>),
+    Stat(StatContents<Arc<K>>),
+    List(ListContents<K>),
 }
 
 #[derive(Debug)]
@@ -55,109 +125,263 @@ where
     K: std::hash::Hash + std::cmp::Eq,
 {
     pub contents: Contents<K, T>,
-    pub snd: Sender<MapperReply<T>>,
+    pub snd: Sender<MapperReply<K, T>>,
 }
 
 #[derive(Debug)]
 /// Shared state between threads.
 ///
-/// The state is shared by message passing. It is preserved as a HashMap that uses 
-/// hashable objects as strings.
+/// The state is shared by message passing. It is preserved in a `BlobStore` that uses
+/// hashable objects as keys.
 pub struct Mapper<K, T>
 where
     K: std::hash::Hash + std::cmp::Eq,
 {
 
-    map: Option<HashMap<K, Arc<T>>>,
+    map: Option<Box<dyn BlobStore<K, T>>>,
+    spool_dir: PathBuf,
     sender: Sender<RequestMessage<K, T>>,
     receiver: Option<Receiver<RequestMessage<K, T>>>,
 }
 
+impl<K, T> Default for Mapper<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq + Send + Sync + std::fmt::Debug + 'static,
+    T: From<Vec<u8>> + AsRef<[u8]> + Send + Sync + std::fmt::Debug + 'static,
+{
+    fn default() -> Mapper<K, T> {
+        Mapper::new()
+    }
+}
+
 impl<K, T> Mapper<K, T>
 where
-    K: std::hash::Hash + std::cmp::Eq,
+    K: std::hash::Hash + std::cmp::Eq + Send + Sync + std::fmt::Debug + 'static,
+    T: From<Vec<u8>> + AsRef<[u8]> + Send + Sync + std::fmt::Debug + 'static,
 {
+    /// Creates a mapper that keeps its state in memory.
     pub fn new() -> Mapper<K, T> {
-        let map = Some(HashMap::new());
+        Mapper::with_store(Box::new(MemoryStore::new()))
+    }
+}
+
+impl<K, T> Mapper<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    /// Creates a mapper that keeps its state in `store`.
+    pub fn with_store(store: Box<dyn BlobStore<K, T>>) -> Mapper<K, T> {
+        let spool_dir = store.spool_dir();
+        let map = Some(store);
         let (sender, receiver) = mpsc::channel::<RequestMessage<K, T>>(1);
         let receiver = Some(receiver);
         Mapper {
             map,
+            spool_dir,
             sender,
             receiver,
         }
     }
 
-    /// Set the value of a key while the HashMap is still owned by the mapper.
+    /// Set the value of a key while the store is still owned by the mapper.
     pub fn owned_set(&mut self, k: K, t: T) -> Result<(), String> {
         match self.map {
             Some(ref mut m) => {
-                m.insert(k, Arc::new(t));
-                Ok(())
+                m.put(k, t).map_err(|e| e.to_string())
             },
             None => Err(String::from("The mapper no longer owns its map"))
         }
     }
 
-    /// Get the value of a key while the HashMap is still owned by the mapper.
-    pub fn owned_get(&self, k: &K) -> Result<Option<&Arc<T>>, String> {
+    /// Get the value of a key while the store is still owned by the mapper.
+    pub fn owned_get(&self, k: &K) -> Result<Option<Arc<T>>, String> {
         match self.map {
             Some(ref m) => {
-                Ok(m.get(k))
+                m.get(k).map_err(|e| e.to_string())
             },
             None => Err(String::from("The mapper no longer owns its map"))
         }
     }
 
     /// Send a message to the mapper
-    fn send_request(&self, contents: Contents<K, T>) -> impl Future< Item = MapperReply<T>, Error = String> {
-        let (snd, rcv) = mpsc::channel::<MapperReply<T>>(1);
+    fn send_request(&self, contents: Contents<K, T>) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let (snd, rcv) = mpsc::channel::<MapperReply<K, T>>(1);
         let msg = RequestMessage{contents, snd};
         self.sender.clone().send(msg)
             .map(|_| debug!("Successfully sent request to map"))
             .map_err(|_| "Could not sent request to map")
             .and_then(|_| rcv.collect().map_err(|_| "could not collect from receiver"))
-            .map(|mut replies| {
-                let reply = replies.remove(0);
-                reply
-            }).map_err(|e| e.to_string())
+            .map(|mut replies| replies.remove(0))
+            .map_err(|e| e.to_string())
     }
 
     /// Get the value of a key after the mapper thread has been spawned.
-    pub fn get(&self, key: Arc<K>) -> impl Future< Item = MapperReply<T>, Error = String> {
+    pub fn get(&self, key: Arc<K>) -> impl Future< Item = MapperReply<K, T>, Error = String> {
         let msg = Contents::Fetch(FetchContents {key});
         self.send_request(msg)
     }
 
+    /// Get at most `len` bytes of the value of a key, starting at `offset`, after the mapper
+    /// thread has been spawned.
+    ///
+    /// Stores that can read part of a value do not read the rest of it.
+    pub fn get_range(&self, key: Arc<K>, offset: u64, len: u64) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let msg = Contents::FetchRange(FetchRangeContents {key, offset, len});
+        self.send_request(msg)
+    }
+
+    /// Describe the value of a key after the mapper thread has been spawned.
+    ///
+    /// Unlike `get`, the value itself is never read, so this is cheap even for large values.
+    pub fn stat(&self, key: Arc<K>) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let msg = Contents::Stat(StatContents {key});
+        self.send_request(msg)
+    }
+
+    /// List the keys after the mapper thread has been spawned.
+    ///
+    /// Only the keys whose hex encoding starts with `prefix` are listed, in ascending order,
+    /// starting after `after` and stopping after `limit` of them.
+    pub fn list(&self, prefix: String, after: Option<K>, limit: usize) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let msg = Contents::List(ListContents {prefix, after, limit});
+        self.send_request(msg)
+    }
+
     /// Set the value of a key after the mapper thread has been spawned.
-    pub fn set(&self, key: K, data: T) -> impl Future< Item = MapperReply<T>, Error = String> {
+    pub fn set(&self, key: K, data: T) -> impl Future< Item = MapperReply<K, T>, Error = String> {
         let msg = Contents::Place(PlaceContents {key, data});
             self.send_request(msg)
     }
 
+    /// Creates a spool file in which data can be received before being placed with
+    /// `set_spooled`.
+    pub fn spool(&self) -> std::io::Result<SpoolFile> {
+        SpoolFile::create(&self.spool_dir)
+    }
+
+    /// The directory in which data is spooled before being placed.
+    pub fn spool_dir(&self) -> &Path {
+        &self.spool_dir
+    }
+
+    /// Set the value of a key to the contents of a spool file after the mapper thread has
+    /// been spawned.
+    pub fn set_spooled(&self, key: K, spool: SpoolFile) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let msg = Contents::PlaceSpooled(PlaceSpooledContents {key, spool});
+        self.send_request(msg)
+    }
+
+    /// Remove the value of a key after the mapper thread has been spawned.
+    ///
+    /// The mapper replies with `Ok` if the value was removed, or with `NotFound` if there
+    /// was none.
+    pub fn remove(&self, key: K) -> impl Future< Item = MapperReply<K, T>, Error = String> {
+        let msg = Contents::Remove(RemoveContents {key});
+        self.send_request(msg)
+    }
+
     /// Spawns a thread that receives and sends message in order to pass state around.
     ///
-    /// After calling this function the mapper will no longer own the HashMap, as it will
-    /// be given to the spawned thread.
-    pub fn receive(&mut self) -> Result<impl Future<Item = (), Error = ()>, String> {
+    /// After calling this function the mapper will no longer own the store, as it will
+    /// be given to the spawned thread. The returned future blocks on the store, so it is
+    /// best run on a thread of its own rather than among futures serving connections.
+    pub fn receive(&mut self) -> Result<impl Future<Item = (), Error = ()>, String>
+    where
+        K: AsRef<[u8]> + Borrow<[u8]> + Ord + Clone,
+        T: AsRef<[u8]>,
+    {
         let mut map = self.map.take().ok_or("Receive Future already created")?;
         let receiver = self.receiver.take().ok_or("Receive Future already created")?;
+        // stores keep no order, so the keys are also kept sorted here for listing
+        let mut index: BTreeSet<K> = map.iter().cloned().collect();
         Ok(receiver.for_each(move |msg| {
             match msg.contents {
                 Contents::Fetch(fetch) => {
                     info!("Received a Fetch request");
                     match map.get(&fetch.key) {
-                        Some(found_data) => {
-                            let data = found_data.clone();
+                        Ok(Some(data)) => {
                             msg.snd.send(MapperReply::Data(DataContents { data }))
                         },
-                        None => msg.snd.send(MapperReply::NotFound)
+                        Ok(None) => msg.snd.send(MapperReply::NotFound),
+                        Err(e) => {
+                            warn!("Could not read from store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
+                },
+                Contents::FetchRange(fetch) => {
+                    info!("Received a ranged Fetch request");
+                    match map.get_range(&fetch.key, fetch.offset, fetch.len) {
+                        Ok(Some(data)) => msg.snd.send(MapperReply::Range(data)),
+                        Ok(None) => msg.snd.send(MapperReply::NotFound),
+                        Err(e) => {
+                            warn!("Could not read from store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
                     }
                 },
                 Contents::Place(place) => {
                     info!("Received a Place request");
-                    map.insert(place.key, Arc::new(place.data));
-                    msg.snd.send(MapperReply::Ok)
+                    let key = place.key.clone();
+                    match map.put(place.key, place.data) {
+                        Ok(()) => {
+                            index.insert(key);
+                            msg.snd.send(MapperReply::Ok)
+                        },
+                        Err(e) => {
+                            warn!("Could not write to store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
+                },
+                Contents::PlaceSpooled(place) => {
+                    info!("Received a spooled Place request");
+                    let key = place.key.clone();
+                    match map.put_spooled(place.key, place.spool) {
+                        Ok(()) => {
+                            index.insert(key);
+                            msg.snd.send(MapperReply::Ok)
+                        },
+                        Err(e) => {
+                            warn!("Could not write to store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
+                },
+                Contents::Stat(stat) => {
+                    info!("Received a Stat request");
+                    match map.stat(&stat.key) {
+                        Ok(Some(stat)) => msg.snd.send(MapperReply::Stat(stat)),
+                        Ok(None) => msg.snd.send(MapperReply::NotFound),
+                        Err(e) => {
+                            warn!("Could not read from store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
+                },
+                Contents::List(list) => {
+                    info!("Received a List request");
+                    match list_keys(map.as_ref(), &index, list) {
+                        Ok(listing) => msg.snd.send(MapperReply::Listing(listing)),
+                        Err(e) => {
+                            warn!("Could not read from store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
+                },
+                Contents::Remove(remove) => {
+                    info!("Received a Remove request");
+                    match map.delete(&remove.key) {
+                        Ok(true) => {
+                            index.remove::<K>(&remove.key);
+                            msg.snd.send(MapperReply::Ok)
+                        },
+                        Ok(false) => msg.snd.send(MapperReply::NotFound),
+                        Err(e) => {
+                            warn!("Could not delete from store: {}", e);
+                            msg.snd.send(MapperReply::Error(e.to_string()))
+                        }
+                    }
                 }
             }
             .map(|_| info!("replied to request"))
@@ -165,3 +389,162 @@ where
         }))
     }
 }
+
+/// The smallest key whose hex encoding can start with `prefix`, if `prefix` is made of hex
+/// digits.
+fn prefix_start(prefix: &str) -> Option<Vec<u8>> {
+    let digits: Option<Vec<u8>> = prefix.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect();
+    Some(digits?.chunks(2).map(|pair| pair[0] << 4 | pair.get(1).unwrap_or(&0)).collect())
+}
+
+/// Collects the page of keys asked for by `list` from `index`, the sorted keys of `map`.
+///
+/// Keys sharing a prefix are next to each other in `index`, so only the keys of the page
+/// are looked at.
+fn list_keys<K, T>(map: &dyn BlobStore<K, T>, index: &BTreeSet<K>, list: ListContents<K>) -> std::io::Result<ListingContents<K>>
+where
+    K: std::hash::Hash + std::cmp::Eq + AsRef<[u8]> + Borrow<[u8]> + Ord + Clone,
+{
+    let start = match prefix_start(&list.prefix) {
+        Some(start) => start,
+        None => return Ok(ListingContents {
+            keys: Vec::new(),
+            more: false,
+        }),
+    };
+    let from: Bound<&[u8]> = match &list.after {
+        Some(after) if after.borrow() >= start.as_slice() => Bound::Excluded(after.borrow()),
+        _ => Bound::Included(start.as_slice()),
+    };
+    let mut keys = index.range::<[u8], _>((from, Bound::Unbounded))
+        .take_while(|key| has_hex_prefix(key.as_ref(), &list.prefix));
+    let mut page = Vec::new();
+    for key in keys.by_ref().take(list.limit) {
+        // keys are only listed when they can be described
+        if let Some(stat) = map.stat(key)? {
+            page.push((key.clone(), stat));
+        }
+    }
+    Ok(ListingContents {
+        keys: page,
+        more: keys.next().is_some(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::client::{resolved, ClientError};
+    use crate::messages::{Frame, ResolvedMessage};
+
+    /// Starts a mapper holding `data` under each of `keys`, with its actor loop on a thread.
+    fn running(keys: &[Vec<u8>], data: &[u8]) -> Mapper<Vec<u8>, Vec<u8>> {
+        let mut mapper = Mapper::new();
+        for key in keys {
+            mapper.owned_set(key.clone(), data.to_vec()).unwrap();
+        }
+        let receiving = mapper.receive().unwrap();
+        thread::spawn(move || receiving.wait());
+        mapper
+    }
+
+    fn listed(map: &dyn BlobStore<Vec<u8>, Vec<u8>>, index: &BTreeSet<Vec<u8>>, prefix: &str, after: Option<Vec<u8>>, limit: usize) -> (Vec<Vec<u8>>, bool) {
+        let listing = list_keys(map, index, ListContents { prefix: prefix.to_string(), after, limit }).unwrap();
+        (listing.keys.into_iter().map(|(key, _)| key).collect(), listing.more)
+    }
+
+    #[test]
+    fn lists_keys_in_pages() {
+        let mut map: MemoryStore<Vec<u8>, Vec<u8>> = MemoryStore::new();
+        for key in &[vec![0x12, 0x00], vec![0xab, 0x01], vec![0xa0, 0x02], vec![0xab, 0x00], vec![0xff]] {
+            map.put(key.clone(), b"data".to_vec()).unwrap();
+        }
+        let index: BTreeSet<Vec<u8>> = map.iter().cloned().collect();
+
+        assert_eq!(listed(&map, &index, "", None, 2), (vec![vec![0x12, 0x00], vec![0xa0, 0x02]], true));
+        assert_eq!(listed(&map, &index, "", Some(vec![0xa0, 0x02]), 2), (vec![vec![0xab, 0x00], vec![0xab, 0x01]], true));
+        assert_eq!(listed(&map, &index, "", Some(vec![0xab, 0x01]), 2), (vec![vec![0xff]], false));
+
+        assert_eq!(listed(&map, &index, "a", None, 10), (vec![vec![0xa0, 0x02], vec![0xab, 0x00], vec![0xab, 0x01]], false));
+        assert_eq!(listed(&map, &index, "ab", None, 1), (vec![vec![0xab, 0x00]], true));
+        assert_eq!(listed(&map, &index, "ab", Some(vec![0x12, 0x00]), 1), (vec![vec![0xab, 0x00]], true));
+        assert_eq!(listed(&map, &index, "ab00", Some(vec![0xab, 0x00]), 5), (vec![], false));
+        assert_eq!(listed(&map, &index, "xyz", None, 5), (vec![], false));
+    }
+
+    #[test]
+    fn tells_removed_keys_from_missing_ones() {
+        let mapper = running(&[vec![0xab, 0x00], vec![0xab, 0x01]], b"data");
+
+        match mapper.remove(vec![0xab, 0x00]).wait().unwrap() {
+            MapperReply::Ok => (),
+            reply => panic!("unexpected {:?}", reply),
+        }
+        match mapper.remove(vec![0xab, 0x00]).wait().unwrap() {
+            MapperReply::NotFound => (),
+            reply => panic!("unexpected {:?}", reply),
+        }
+        match mapper.get(Arc::new(vec![0xab, 0x00])).wait().unwrap() {
+            MapperReply::NotFound => (),
+            reply => panic!("unexpected {:?}", reply),
+        }
+        match mapper.list("ab".to_string(), None, 10).wait().unwrap() {
+            MapperReply::Listing(listing) => {
+                let keys: Vec<_> = listing.keys.into_iter().map(|(key, _)| key).collect();
+                assert_eq!(keys, vec![vec![0xab, 0x01]]);
+            },
+            reply => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn describes_stored_values() {
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mapper = running(&[vec![0xab, 0x00]], b"some data");
+        let after = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        match mapper.stat(Arc::new(vec![0xab, 0x00])).wait().unwrap() {
+            MapperReply::Stat(stat) => {
+                assert_eq!(stat.size, 9);
+                assert!(before <= stat.stored_at && stat.stored_at <= after);
+            },
+            reply => panic!("unexpected {:?}", reply),
+        }
+        match mapper.stat(Arc::new(vec![0xab, 0x01])).wait().unwrap() {
+            MapperReply::NotFound => (),
+            reply => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    /// Resolves `prefix` the way the server and clients do, from a page of the keys.
+    fn resolve(mapper: &Mapper<Vec<u8>, Vec<u8>>, prefix: &str) -> Result<Option<Vec<u8>>, ClientError> {
+        match mapper.list(prefix.to_string(), None, 16).wait().unwrap() {
+            MapperReply::Listing(listing) => {
+                let hashes = listing.keys.into_iter().map(|(key, _)| key).collect();
+                resolved(prefix, Frame::Resolved(ResolvedMessage::new(hashes)))
+            },
+            reply => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn resolves_prefixes_matching_a_single_key() {
+        let mapper = running(&[vec![0x12, 0x34], vec![0xab, 0x00], vec![0xab, 0x01]], b"data");
+
+        assert_eq!(resolve(&mapper, "1").unwrap(), Some(vec![0x12, 0x34]));
+        assert_eq!(resolve(&mapper, "ab01").unwrap(), Some(vec![0xab, 0x01]));
+        match resolve(&mapper, "ab0") {
+            Err(ClientError::Ambiguous { prefix, candidates }) => {
+                assert_eq!(prefix, "ab0");
+                assert_eq!(candidates, vec![vec![0xab, 0x00], vec![0xab, 0x01]]);
+            },
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(resolve(&mapper, "ab02").unwrap(), None);
+        assert_eq!(resolve(&mapper, "ff").unwrap(), None);
+    }
+}
@@ -1,18 +1,76 @@
-use std::io::Cursor;
+use std::cmp;
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
+use bytes::{Bytes, BytesMut};
+
+use futures::Future;
+
+use tokio::codec::{Decoder, Encoder};
+use tokio::io::{read_exact, write_all};
+use tokio::prelude::{AsyncRead, AsyncWrite};
+
+use crate::auth::Permissions;
+use crate::dht::Contact;
 use crate::hash::HASH_SIZE;
+use crate::store::BlobStat;
 
-static NOTFOUND: [u8; 5] = [78, 84, 70, 78, 68];
+/// The version of the protocol spoken by this crate.
+///
+/// Version 1 had no handshake and framed messages with a 32 bit length. Version 2 had no
+/// request ids, and served a single request per connection.
+pub const PROTOCOL_VERSION: u16 = 3;
 
-pub const MSG_HEADER_LEN: usize = 6;
+/// Sent by both ends when a connection is opened, followed by their protocol version.
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"KTAP";
 
-#[derive(Debug)]
+pub const HANDSHAKE_LEN: usize = 6;
+
+/// type (2) + request id (4) + length (8)
+pub const MSG_HEADER_LEN: usize = 14;
+
+/// The header of messages in version 1 of the protocol: type (2) + length (4)
+pub const LEGACY_MSG_HEADER_LEN: usize = 6;
+
+/// Identifies a request within a connection. Replies carry the id of the request they
+/// answer, so that requests can be pipelined and answered in any order.
+pub type RequestId = u32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
     Place,
     Fetch,
     NotFound,
+    PlaceOk,
+    Error,
+    Data,
+    Delete,
+    Deleted,
+    Stat,
+    Stats,
+    List,
+    Listing,
+    Resolve,
+    Resolved,
+    Upload,
+    UploadStatus,
+    Append,
+    Commit,
+    FetchRange,
+    FindNode,
+    FindValue,
+    Store,
+    Nodes,
+    Replicate,
+    Hello,
+    Challenge,
+    Authenticate,
+    Authenticated,
     Unknown
 }
 
@@ -21,32 +79,150 @@ impl From<u16> for MessageType {
         match t {
             0 => MessageType::Place,
             1 => MessageType::Fetch,
+            2 => MessageType::NotFound,
+            3 => MessageType::PlaceOk,
+            4 => MessageType::Error,
+            5 => MessageType::Data,
+            6 => MessageType::Delete,
+            7 => MessageType::Deleted,
+            8 => MessageType::Stat,
+            9 => MessageType::Stats,
+            10 => MessageType::List,
+            11 => MessageType::Listing,
+            12 => MessageType::Resolve,
+            13 => MessageType::Resolved,
+            14 => MessageType::Upload,
+            15 => MessageType::UploadStatus,
+            16 => MessageType::Append,
+            17 => MessageType::Commit,
+            18 => MessageType::FetchRange,
+            19 => MessageType::FindNode,
+            20 => MessageType::FindValue,
+            21 => MessageType::Store,
+            22 => MessageType::Nodes,
+            23 => MessageType::Replicate,
+            24 => MessageType::Hello,
+            25 => MessageType::Challenge,
+            26 => MessageType::Authenticate,
+            27 => MessageType::Authenticated,
             _ => MessageType::Unknown,
         }
     }
 }
 
-impl Into<u16> for MessageType {
-    fn into(self) -> u16 {
-        match self {
+impl From<MessageType> for u16 {
+    fn from(t: MessageType) -> u16 {
+        match t {
             MessageType::Place => 0,
             MessageType::Fetch => 1,
             MessageType::NotFound => 2,
+            MessageType::PlaceOk => 3,
+            MessageType::Error => 4,
+            MessageType::Data => 5,
+            MessageType::Delete => 6,
+            MessageType::Deleted => 7,
+            MessageType::Stat => 8,
+            MessageType::Stats => 9,
+            MessageType::List => 10,
+            MessageType::Listing => 11,
+            MessageType::Resolve => 12,
+            MessageType::Resolved => 13,
+            MessageType::Upload => 14,
+            MessageType::UploadStatus => 15,
+            MessageType::Append => 16,
+            MessageType::Commit => 17,
+            MessageType::FetchRange => 18,
+            MessageType::FindNode => 19,
+            MessageType::FindValue => 20,
+            MessageType::Store => 21,
+            MessageType::Nodes => 22,
+            MessageType::Replicate => 23,
+            MessageType::Hello => 24,
+            MessageType::Challenge => 25,
+            MessageType::Authenticate => 26,
+            MessageType::Authenticated => 27,
             MessageType::Unknown => 255,
         }
     }
 }
 
+/// The reasons for which the server can refuse a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorCode {
+    /// The server failed while serving the request
+    Internal,
+    /// The hash of the placed data does not match the hash it was placed under
+    HashMismatch,
+    /// The request could not be parsed
+    BadRequest,
+    /// The client speaks a version of the protocol the server does not
+    UnsupportedVersion,
+    /// The data was stored, but not copied to as many nodes as required
+    QuorumNotMet,
+    /// The client has not proven that it holds a token the server accepts
+    Unauthenticated,
+    /// The token of the client does not allow the request
+    Forbidden,
+    /// The data is too large to be sent in a single message, and must be fetched in ranges
+    TooLarge,
+    Unknown,
+}
+
+impl From<u16> for ErrorCode {
+    fn from(c: u16) -> ErrorCode {
+        match c {
+            0 => ErrorCode::Internal,
+            1 => ErrorCode::HashMismatch,
+            2 => ErrorCode::BadRequest,
+            3 => ErrorCode::UnsupportedVersion,
+            4 => ErrorCode::QuorumNotMet,
+            5 => ErrorCode::Unauthenticated,
+            6 => ErrorCode::Forbidden,
+            7 => ErrorCode::TooLarge,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(c: ErrorCode) -> u16 {
+        match c {
+            ErrorCode::Internal => 0,
+            ErrorCode::HashMismatch => 1,
+            ErrorCode::BadRequest => 2,
+            ErrorCode::UnsupportedVersion => 3,
+            ErrorCode::QuorumNotMet => 4,
+            ErrorCode::Unauthenticated => 5,
+            ErrorCode::Forbidden => 6,
+            ErrorCode::TooLarge => 7,
+            ErrorCode::Unknown => 255,
+        }
+    }
+}
+
 /// A trait for struct that can be sent as a Kitap message.
 pub trait Message {
     fn get_type(&self) -> MessageType;
     fn get_contents(&self) -> Vec<u8>;
 
-    fn into_bytes(&self) -> Vec<u8> {
+    fn to_bytes(&self, id: RequestId) -> Vec<u8> {
+        let msg_type = self.get_type();
+        let contents = self.get_contents();
+        let len = contents.len();
+        let mut v = Vec::with_capacity(MSG_HEADER_LEN + len);
+        v.write_u16::<LittleEndian>(msg_type.into()).unwrap();
+        v.write_u32::<LittleEndian>(id).unwrap();
+        v.write_u64::<LittleEndian>(len as u64).unwrap();
+        v.extend(contents);
+        v
+    }
+
+    /// Frames the message as version 1 of the protocol did, so that old peers can decode it.
+    fn to_legacy_bytes(&self) -> Vec<u8> {
         let msg_type = self.get_type();
         let contents = self.get_contents();
         let len = contents.len();
-        let mut v = Vec::with_capacity(2 + len);
+        let mut v = Vec::with_capacity(LEGACY_MSG_HEADER_LEN + len);
         v.write_u16::<LittleEndian>(msg_type.into()).unwrap();
         v.write_u32::<LittleEndian>(len as u32).unwrap();
         v.extend(contents);
@@ -54,16 +230,73 @@ pub trait Message {
     }
 }
 
+/// What the first bytes of a connection announce.
+#[derive(Debug, PartialEq)]
+pub enum Handshake {
+    /// The peer speaks the given version of the protocol
+    Version(u16),
+    /// The peer speaks version 1 of the protocol, which had no handshake. The bytes read
+    /// are the header of its first message.
+    Legacy,
+}
+
+impl Handshake {
+    pub fn to_bytes(version: u16) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0; HANDSHAKE_LEN];
+        buf[..PROTOCOL_MAGIC.len()].copy_from_slice(&PROTOCOL_MAGIC);
+        (&mut buf[PROTOCOL_MAGIC.len()..]).write_u16::<LittleEndian>(version).unwrap();
+        buf
+    }
+
+    pub fn parse(buf: [u8; HANDSHAKE_LEN]) -> Handshake {
+        if buf[..PROTOCOL_MAGIC.len()] != PROTOCOL_MAGIC {
+            return Handshake::Legacy;
+        }
+        let version = (&buf[PROTOCOL_MAGIC.len()..]).read_u16::<LittleEndian>().unwrap();
+        Handshake::Version(version)
+    }
+}
+
+/// Opens a connection to a server by exchanging protocol versions with it.
+pub fn client_handshake<S>(stream: S) -> impl Future<Item = S, Error = String>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    write_all(stream, Handshake::to_bytes(PROTOCOL_VERSION))
+        .and_then(|(stream, _)| read_exact(stream, [0; HANDSHAKE_LEN]))
+        .map_err(|e| format!("Handshake failed: {}", e))
+        .and_then(|(stream, buf)| check_server_handshake(buf).map(|_| stream))
+}
+
+/// Checks that the handshake the server replied with agrees on our protocol version.
+pub fn check_server_handshake(buf: [u8; HANDSHAKE_LEN]) -> Result<(), String> {
+    match Handshake::parse(buf) {
+        Handshake::Version(PROTOCOL_VERSION) => Ok(()),
+        Handshake::Version(v) => Err(format!(
+            "The server speaks version {} of the protocol, but this client speaks version {}",
+            v, PROTOCOL_VERSION)),
+        Handshake::Legacy => Err("The server does not speak this version of the protocol".to_string()),
+    }
+}
+
 /// A message for Fetch requests
+///
+/// The server replies to it with one `DataMessage` or `NotFoundMessage` per hash, in the
+/// order the hashes were requested, or with an `ErrorMessage` for data larger than
+/// `MAX_DATA_LEN`.
+#[derive(Debug)]
 pub struct FetchMessage {
-    hashes: Vec<Vec<u8>>,
+    pub hashes: Vec<Vec<u8>>,
 }
 
 /// A message for Place requests
+///
+/// It is followed by `datasize` bytes of raw data, which are not part of the message.
+#[derive(Debug)]
 pub struct PlaceMessage
 {
     pub hash: Vec<u8>,
-    pub datasize: usize,
+    pub datasize: u64,
 }
 
 impl FetchMessage {
@@ -72,6 +305,12 @@ impl FetchMessage {
             hashes,
         }
     }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<FetchMessage, String> {
+        Ok(FetchMessage {
+            hashes: read_hashes(&mut Cursor::new(buf))?,
+        })
+    }
 }
 
 impl Message for FetchMessage {
@@ -80,13 +319,149 @@ impl Message for FetchMessage {
     }
 
     fn get_contents(&self) -> Vec<u8> {
-        self.hashes.join(&(':' as u8))
+        let mut v = Vec::new();
+        write_hashes(&mut v, &self.hashes);
+        v
+    }
+}
+
+/// Reads a hash prefixed by its length.
+fn read_hash(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<u8>, String> {
+    let len = cursor.read_u16::<LittleEndian>()
+        .or(Err("Could not read hash length from buffer"))? as usize;
+    let mut hash = vec![0; len];
+    cursor.read_exact(&mut hash)
+        .or(Err("Could not read hash from buffer"))?;
+    Ok(hash)
+}
+
+fn write_hash(v: &mut Vec<u8>, hash: &[u8]) {
+    v.write_u16::<LittleEndian>(hash.len() as u16).unwrap();
+    v.extend(hash);
+}
+
+/// Reads a list of hashes prefixed by their count.
+fn read_hashes(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<Vec<u8>>, String> {
+    let count = cursor.read_u32::<LittleEndian>()
+        .or(Err("Could not read hash count from buffer"))?;
+    let mut hashes = Vec::new();
+    for _ in 0..count {
+        hashes.push(read_hash(cursor)?);
+    }
+    Ok(hashes)
+}
+
+fn write_hashes(v: &mut Vec<u8>, hashes: &[Vec<u8>]) {
+    v.write_u32::<LittleEndian>(hashes.len() as u32).unwrap();
+    for hash in hashes {
+        write_hash(v, hash);
+    }
+}
+
+/// A message for Stat requests
+///
+/// The server replies to it with a single `StatsMessage` describing every hash, without
+/// sending any data.
+#[derive(Debug)]
+pub struct StatMessage {
+    pub hashes: Vec<Vec<u8>>,
+}
+
+impl StatMessage {
+    pub fn new(hashes: Vec<Vec<u8>>) -> StatMessage {
+        StatMessage {
+            hashes,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<StatMessage, String> {
+        Ok(StatMessage {
+            hashes: read_hashes(&mut Cursor::new(buf))?,
+        })
+    }
+}
+
+impl Message for StatMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Stat
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hashes(&mut v, &self.hashes);
+        v
+    }
+}
+
+/// A reply describing the blobs stored under the hashes of a Stat request, in the order
+/// they were requested. Hashes under which nothing is stored have no stat.
+///
+/// Each hash is followed by a presence byte, and by the size and the time the blob was
+/// stored at if it is present.
+#[derive(Debug)]
+pub struct StatsMessage {
+    pub stats: Vec<(Vec<u8>, Option<BlobStat>)>,
+}
+
+impl StatsMessage {
+    pub fn new(stats: Vec<(Vec<u8>, Option<BlobStat>)>) -> StatsMessage {
+        StatsMessage {
+            stats,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<StatsMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let count = cursor.read_u32::<LittleEndian>()
+            .or(Err("Could not read stat count from buffer"))?;
+        let mut stats = Vec::new();
+        for _ in 0..count {
+            let hash = read_hash(&mut cursor)?;
+            let present = cursor.read_u8()
+                .or(Err("Could not read presence from buffer"))?;
+            let stat = if present != 0 {
+                let size = cursor.read_u64::<LittleEndian>()
+                    .or(Err("Could not read size from buffer"))?;
+                let stored_at = cursor.read_u64::<LittleEndian>()
+                    .or(Err("Could not read timestamp from buffer"))?;
+                Some(BlobStat { size, stored_at })
+            } else {
+                None
+            };
+            stats.push((hash, stat));
+        }
+        Ok(StatsMessage {
+            stats,
+        })
+    }
+}
+
+impl Message for StatsMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Stats
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.write_u32::<LittleEndian>(self.stats.len() as u32).unwrap();
+        for (hash, stat) in &self.stats {
+            write_hash(&mut v, hash);
+            match stat {
+                Some(stat) => {
+                    v.write_u8(1).unwrap();
+                    v.write_u64::<LittleEndian>(stat.size).unwrap();
+                    v.write_u64::<LittleEndian>(stat.stored_at).unwrap();
+                },
+                None => v.write_u8(0).unwrap(),
+            }
+        }
+        v
     }
 }
 
 impl PlaceMessage
 {
-    pub fn new(hash: Vec<u8>, datasize: usize) -> PlaceMessage 
+    pub fn new(hash: Vec<u8>, datasize: u64) -> PlaceMessage
     {
         PlaceMessage {
             hash,
@@ -95,9 +470,12 @@ impl PlaceMessage
     }
 
     pub fn try_from(mut buf: Vec<u8>) -> Result<PlaceMessage, String> {
+        if buf.len() != HASH_SIZE + 8 {
+            return Err(format!("Place message has length {}, expected {}", buf.len(), HASH_SIZE + 8));
+        }
         let datasize = Cursor::new(buf.split_off(HASH_SIZE))
-            .read_u32::<LittleEndian>()
-            .or(Err("Could not read datasize from buffer"))? as usize;
+            .read_u64::<LittleEndian>()
+            .or(Err("Could not read datasize from buffer"))?;
         let hash = buf;
         Ok(PlaceMessage {
             datasize,
@@ -114,26 +492,66 @@ impl Message for PlaceMessage
     }
 
     fn get_contents(&self) -> Vec<u8> {
-        let mut v = Vec::with_capacity(self.hash.len() + 4);
+        let mut v = Vec::with_capacity(self.hash.len() + 8);
         v.extend(&self.hash);
-        v.write_u32::<LittleEndian>(self.datasize as u32).unwrap();
+        v.write_u64::<LittleEndian>(self.datasize).unwrap();
         v
     }
 }
 
-pub struct NotFoundMessage<'a> {
-    key: &'a Vec<u8>,
+/// A message for Replicate requests, by which a node copies the data it was placed to
+/// another node.
+///
+/// It is followed by `datasize` bytes of raw data like a `PlaceMessage`, but the node that
+/// receives it does not copy the data any further.
+#[derive(Debug)]
+pub struct ReplicateMessage {
+    pub hash: Vec<u8>,
+    pub datasize: u64,
+}
+
+impl ReplicateMessage {
+    pub fn new(hash: Vec<u8>, datasize: u64) -> ReplicateMessage {
+        ReplicateMessage {
+            hash,
+            datasize,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ReplicateMessage, String> {
+        let place = PlaceMessage::try_from(buf)?;
+        Ok(ReplicateMessage {
+            hash: place.hash,
+            datasize: place.datasize,
+        })
+    }
+}
+
+impl Message for ReplicateMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Replicate
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        PlaceMessage::new(self.hash.clone(), self.datasize).get_contents()
+    }
+}
+
+/// A reply reporting that nothing is stored under a hash
+#[derive(Debug)]
+pub struct NotFoundMessage {
+    pub key: Vec<u8>,
 }
 
-impl<'a> NotFoundMessage<'a> {
-    pub fn new(key: &Vec<u8>) -> NotFoundMessage {
+impl NotFoundMessage {
+    pub fn new(key: Vec<u8>) -> NotFoundMessage {
         NotFoundMessage {
             key,
         }
     }
 }
 
-impl<'a> Message for NotFoundMessage<'a> {
+impl Message for NotFoundMessage {
     fn get_type(&self) -> MessageType {
         MessageType::NotFound
     }
@@ -142,3 +560,1256 @@ impl<'a> Message for NotFoundMessage<'a> {
         self.key.clone()
     }
 }
+
+/// A reply carrying the data stored under a hash
+#[derive(Debug)]
+pub struct DataMessage {
+    pub hash: Vec<u8>,
+    pub data: Arc<Vec<u8>>,
+}
+
+impl DataMessage {
+    pub fn new(hash: Vec<u8>, data: Arc<Vec<u8>>) -> DataMessage {
+        DataMessage {
+            hash,
+            data,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<DataMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let len = cursor.read_u16::<LittleEndian>()
+            .or(Err("Could not read hash length from buffer"))? as usize;
+        let mut hash = vec![0; len];
+        cursor.read_exact(&mut hash)
+            .or(Err("Could not read hash from buffer"))?;
+        let start = cursor.position() as usize;
+        let data = cursor.into_inner().split_off(start);
+        Ok(DataMessage {
+            hash,
+            data: Arc::new(data),
+        })
+    }
+}
+
+impl Message for DataMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Data
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(2 + self.hash.len() + self.data.len());
+        v.write_u16::<LittleEndian>(self.hash.len() as u16).unwrap();
+        v.extend(&self.hash);
+        v.extend(self.data.as_ref());
+        v
+    }
+}
+
+/// A reply acknowledging that data has been placed under a hash
+#[derive(Debug)]
+pub struct PlaceOkMessage {
+    pub hash: Vec<u8>,
+}
+
+impl PlaceOkMessage {
+    pub fn new(hash: Vec<u8>) -> PlaceOkMessage {
+        PlaceOkMessage {
+            hash,
+        }
+    }
+}
+
+impl Message for PlaceOkMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::PlaceOk
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        self.hash.clone()
+    }
+}
+
+/// A message for List requests
+///
+/// The server replies to it with a `ListingMessage` holding the first `limit` stored hashes
+/// that come after `cursor` and whose hex encoding starts with `prefix`. A limit of 0 lets
+/// the server pick the size of the page.
+#[derive(Debug)]
+pub struct ListMessage {
+    pub prefix: String,
+    pub cursor: Option<Vec<u8>>,
+    pub limit: u32,
+}
+
+impl ListMessage {
+    pub fn new(prefix: String, cursor: Option<Vec<u8>>, limit: u32) -> ListMessage {
+        ListMessage {
+            prefix,
+            cursor,
+            limit,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ListMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let prefix = String::from_utf8(read_hash(&mut cursor)?)
+            .or(Err("Prefix is not valid utf8"))?;
+        let after = read_hash(&mut cursor)?;
+        let limit = cursor.read_u32::<LittleEndian>()
+            .or(Err("Could not read limit from buffer"))?;
+        Ok(ListMessage {
+            prefix,
+            cursor: if after.is_empty() { None } else { Some(after) },
+            limit,
+        })
+    }
+}
+
+impl Message for ListMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::List
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, self.prefix.as_bytes());
+        write_hash(&mut v, self.cursor.as_ref().map_or(&[], |c| c.as_slice()));
+        v.write_u32::<LittleEndian>(self.limit).unwrap();
+        v
+    }
+}
+
+/// A reply holding a page of stored hashes and the size of their data, in ascending order.
+///
+/// If more hashes follow, `cursor` is set to the cursor of the request for the next page.
+#[derive(Debug)]
+pub struct ListingMessage {
+    pub entries: Vec<(Vec<u8>, u64)>,
+    pub cursor: Option<Vec<u8>>,
+}
+
+impl ListingMessage {
+    pub fn new(entries: Vec<(Vec<u8>, u64)>, cursor: Option<Vec<u8>>) -> ListingMessage {
+        ListingMessage {
+            entries,
+            cursor,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ListingMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let count = cursor.read_u32::<LittleEndian>()
+            .or(Err("Could not read entry count from buffer"))?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let hash = read_hash(&mut cursor)?;
+            let size = cursor.read_u64::<LittleEndian>()
+                .or(Err("Could not read size from buffer"))?;
+            entries.push((hash, size));
+        }
+        let next = read_hash(&mut cursor)?;
+        Ok(ListingMessage {
+            entries,
+            cursor: if next.is_empty() { None } else { Some(next) },
+        })
+    }
+}
+
+impl Message for ListingMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Listing
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        v.write_u32::<LittleEndian>(self.entries.len() as u32).unwrap();
+        for (hash, size) in &self.entries {
+            write_hash(&mut v, hash);
+            v.write_u64::<LittleEndian>(*size).unwrap();
+        }
+        write_hash(&mut v, self.cursor.as_ref().map_or(&[], |c| c.as_slice()));
+        v
+    }
+}
+
+/// A message for Resolve requests, which look up the full hashes an abbreviated one may
+/// stand for.
+///
+/// The server replies to it with a `ResolvedMessage`.
+#[derive(Debug)]
+pub struct ResolveMessage {
+    /// The hex encoding of the start of a hash
+    pub prefix: String,
+}
+
+impl ResolveMessage {
+    pub fn new(prefix: String) -> ResolveMessage {
+        ResolveMessage {
+            prefix,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ResolveMessage, String> {
+        let prefix = String::from_utf8(buf).or(Err("Prefix is not valid utf8"))?;
+        Ok(ResolveMessage {
+            prefix,
+        })
+    }
+}
+
+impl Message for ResolveMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Resolve
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        self.prefix.clone().into_bytes()
+    }
+}
+
+/// A reply holding the stored hashes that start with the prefix of a Resolve request.
+///
+/// A prefix is unique when a single hash is sent back. When it is ambiguous only a few of
+/// the candidates are sent, in ascending order.
+#[derive(Debug)]
+pub struct ResolvedMessage {
+    pub hashes: Vec<Vec<u8>>,
+}
+
+impl ResolvedMessage {
+    pub fn new(hashes: Vec<Vec<u8>>) -> ResolvedMessage {
+        ResolvedMessage {
+            hashes,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ResolvedMessage, String> {
+        Ok(ResolvedMessage {
+            hashes: read_hashes(&mut Cursor::new(buf))?,
+        })
+    }
+}
+
+impl Message for ResolvedMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Resolved
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hashes(&mut v, &self.hashes);
+        v
+    }
+}
+
+/// A message for Upload requests, which begin an upload of `size` bytes under `hash`, or
+/// resume the upload already begun for it.
+///
+/// Uploads are sent in pieces with `AppendMessage`s and stored by a `CommitMessage`, so
+/// that an interrupted upload can go on from where it stopped, even from another
+/// connection. The server replies with an `UploadStatusMessage` telling how much of the
+/// data it already has, or with a `PlaceOkMessage` if the data is already stored.
+#[derive(Debug)]
+pub struct UploadMessage {
+    pub hash: Vec<u8>,
+    pub size: u64,
+}
+
+impl UploadMessage {
+    pub fn new(hash: Vec<u8>, size: u64) -> UploadMessage {
+        UploadMessage {
+            hash,
+            size,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<UploadMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let hash = read_hash(&mut cursor)?;
+        let size = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read size from buffer"))?;
+        Ok(UploadMessage {
+            hash,
+            size,
+        })
+    }
+}
+
+impl Message for UploadMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Upload
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, &self.hash);
+        v.write_u64::<LittleEndian>(self.size).unwrap();
+        v
+    }
+}
+
+/// A reply telling how many bytes of an upload the server has received
+#[derive(Debug)]
+pub struct UploadStatusMessage {
+    pub hash: Vec<u8>,
+    pub offset: u64,
+}
+
+impl UploadStatusMessage {
+    pub fn new(hash: Vec<u8>, offset: u64) -> UploadStatusMessage {
+        UploadStatusMessage {
+            hash,
+            offset,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<UploadStatusMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let hash = read_hash(&mut cursor)?;
+        let offset = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read offset from buffer"))?;
+        Ok(UploadStatusMessage {
+            hash,
+            offset,
+        })
+    }
+}
+
+impl Message for UploadStatusMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::UploadStatus
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, &self.hash);
+        v.write_u64::<LittleEndian>(self.offset).unwrap();
+        v
+    }
+}
+
+/// A message for Append requests, which add data to an upload
+///
+/// Like a `PlaceMessage`, it is followed by `datasize` bytes of raw data. `offset` must be
+/// the number of bytes the server already has, and the server replies with an
+/// `UploadStatusMessage`. If the connection is closed midway, the data received until then
+/// is kept.
+#[derive(Debug)]
+pub struct AppendMessage {
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    pub datasize: u64,
+}
+
+impl AppendMessage {
+    pub fn new(hash: Vec<u8>, offset: u64, datasize: u64) -> AppendMessage {
+        AppendMessage {
+            hash,
+            offset,
+            datasize,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<AppendMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let hash = read_hash(&mut cursor)?;
+        let offset = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read offset from buffer"))?;
+        let datasize = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read datasize from buffer"))?;
+        Ok(AppendMessage {
+            hash,
+            offset,
+            datasize,
+        })
+    }
+}
+
+impl Message for AppendMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Append
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, &self.hash);
+        v.write_u64::<LittleEndian>(self.offset).unwrap();
+        v.write_u64::<LittleEndian>(self.datasize).unwrap();
+        v
+    }
+}
+
+/// A message for Commit requests, which store the data of a complete upload
+///
+/// The server replies with a `PlaceOkMessage`, or with an error if the data does not hash
+/// to the hash of the upload, in which case the upload is dropped.
+#[derive(Debug)]
+pub struct CommitMessage {
+    pub hash: Vec<u8>,
+}
+
+impl CommitMessage {
+    pub fn new(hash: Vec<u8>) -> CommitMessage {
+        CommitMessage {
+            hash,
+        }
+    }
+}
+
+impl Message for CommitMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Commit
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        self.hash.clone()
+    }
+}
+
+/// A message for FetchRange requests, which fetch at most `length` bytes of the data stored
+/// under `hash`, starting at `offset`.
+///
+/// The server replies with a `DataMessage` holding the bytes found there, which are fewer
+/// than `length` at the end of the data, or with a `NotFoundMessage`.
+#[derive(Debug)]
+pub struct FetchRangeMessage {
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FetchRangeMessage {
+    pub fn new(hash: Vec<u8>, offset: u64, length: u64) -> FetchRangeMessage {
+        FetchRangeMessage {
+            hash,
+            offset,
+            length,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<FetchRangeMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let hash = read_hash(&mut cursor)?;
+        let offset = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read offset from buffer"))?;
+        let length = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read length from buffer"))?;
+        Ok(FetchRangeMessage {
+            hash,
+            offset,
+            length,
+        })
+    }
+}
+
+impl Message for FetchRangeMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::FetchRange
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, &self.hash);
+        v.write_u64::<LittleEndian>(self.offset).unwrap();
+        v.write_u64::<LittleEndian>(self.length).unwrap();
+        v
+    }
+}
+
+/// Reads the id and address of a node.
+fn read_contact(cursor: &mut Cursor<Vec<u8>>) -> Result<Contact, String> {
+    let id = read_hash(cursor)?;
+    let addr = parse_addr(read_hash(cursor)?)?;
+    Ok(Contact::new(id, addr))
+}
+
+fn parse_addr(buf: Vec<u8>) -> Result<SocketAddr, String> {
+    let addr = String::from_utf8(buf)
+        .or(Err("Address is not valid utf8"))?;
+    addr.parse()
+        .map_err(|_| format!("Invalid node address {:?}", addr))
+}
+
+fn write_contact(v: &mut Vec<u8>, contact: &Contact) {
+    write_hash(v, &contact.id);
+    write_hash(v, contact.addr.to_string().as_bytes());
+}
+
+/// Reads the node a request comes from. Requests that do not come from a node, such as
+/// those of clients, carry an empty id and address.
+fn read_sender(cursor: &mut Cursor<Vec<u8>>) -> Result<Option<Contact>, String> {
+    let id = read_hash(cursor)?;
+    let addr = read_hash(cursor)?;
+    if id.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Contact::new(id, parse_addr(addr)?)))
+}
+
+fn write_sender(v: &mut Vec<u8>, sender: &Option<Contact>) {
+    match sender {
+        Some(contact) => write_contact(v, contact),
+        None => {
+            write_hash(v, &[]);
+            write_hash(v, &[]);
+        },
+    }
+}
+
+/// Reads a list of nodes prefixed by their count.
+fn read_contacts(cursor: &mut Cursor<Vec<u8>>) -> Result<Vec<Contact>, String> {
+    let count = cursor.read_u32::<LittleEndian>()
+        .or(Err("Could not read node count from buffer"))?;
+    let mut contacts = Vec::new();
+    for _ in 0..count {
+        contacts.push(read_contact(cursor)?);
+    }
+    Ok(contacts)
+}
+
+fn write_contacts(v: &mut Vec<u8>, contacts: &[Contact]) {
+    v.write_u32::<LittleEndian>(contacts.len() as u32).unwrap();
+    for contact in contacts {
+        write_contact(v, contact);
+    }
+}
+
+/// A message for FindNode requests, which look for the nodes of the overlay closest to
+/// `target`.
+///
+/// The server replies with a `NodesMessage`. Nodes send their own contact along, so that
+/// the server can add them to its routing table.
+#[derive(Debug)]
+pub struct FindNodeMessage {
+    pub sender: Option<Contact>,
+    pub target: Vec<u8>,
+}
+
+impl FindNodeMessage {
+    pub fn new(sender: Option<Contact>, target: Vec<u8>) -> FindNodeMessage {
+        FindNodeMessage {
+            sender,
+            target,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<FindNodeMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let sender = read_sender(&mut cursor)?;
+        let target = read_hash(&mut cursor)?;
+        Ok(FindNodeMessage {
+            sender,
+            target,
+        })
+    }
+}
+
+impl Message for FindNodeMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::FindNode
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_sender(&mut v, &self.sender);
+        write_hash(&mut v, &self.target);
+        v
+    }
+}
+
+/// A message for FindValue requests, which look for the data stored under `hash` in the
+/// overlay.
+///
+/// If the server stores the data, it replies with a `DataMessage` holding at most `length`
+/// bytes of it, starting at `offset`. Otherwise it replies with a `NodesMessage` naming the
+/// nodes closer to the hash, and the nodes known to store it.
+#[derive(Debug)]
+pub struct FindValueMessage {
+    pub sender: Option<Contact>,
+    pub hash: Vec<u8>,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FindValueMessage {
+    pub fn new(sender: Option<Contact>, hash: Vec<u8>, offset: u64, length: u64) -> FindValueMessage {
+        FindValueMessage {
+            sender,
+            hash,
+            offset,
+            length,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<FindValueMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let sender = read_sender(&mut cursor)?;
+        let hash = read_hash(&mut cursor)?;
+        let offset = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read offset from buffer"))?;
+        let length = cursor.read_u64::<LittleEndian>()
+            .or(Err("Could not read length from buffer"))?;
+        Ok(FindValueMessage {
+            sender,
+            hash,
+            offset,
+            length,
+        })
+    }
+}
+
+impl Message for FindValueMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::FindValue
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_sender(&mut v, &self.sender);
+        write_hash(&mut v, &self.hash);
+        v.write_u64::<LittleEndian>(self.offset).unwrap();
+        v.write_u64::<LittleEndian>(self.length).unwrap();
+        v
+    }
+}
+
+/// A message for Store requests, by which `sender` lets the nodes closest to `hash` know
+/// that it stores the data of `hash`.
+///
+/// The server replies with a `PlaceOkMessage`.
+#[derive(Debug)]
+pub struct StoreMessage {
+    pub sender: Contact,
+    pub hash: Vec<u8>,
+}
+
+impl StoreMessage {
+    pub fn new(sender: Contact, hash: Vec<u8>) -> StoreMessage {
+        StoreMessage {
+            sender,
+            hash,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<StoreMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let sender = read_contact(&mut cursor)?;
+        let hash = read_hash(&mut cursor)?;
+        Ok(StoreMessage {
+            sender,
+            hash,
+        })
+    }
+}
+
+impl Message for StoreMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Store
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_contact(&mut v, &self.sender);
+        write_hash(&mut v, &self.hash);
+        v
+    }
+}
+
+/// A reply naming the nodes the server knows closest to the target of a lookup, and the
+/// nodes it knows to store the data looked for, if any.
+///
+/// `sender` is the server itself, so that nodes learn the id of the nodes they contact.
+#[derive(Debug)]
+pub struct NodesMessage {
+    pub sender: Contact,
+    pub nodes: Vec<Contact>,
+    pub providers: Vec<Contact>,
+}
+
+impl NodesMessage {
+    pub fn new(sender: Contact, nodes: Vec<Contact>, providers: Vec<Contact>) -> NodesMessage {
+        NodesMessage {
+            sender,
+            nodes,
+            providers,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<NodesMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let sender = read_contact(&mut cursor)?;
+        let nodes = read_contacts(&mut cursor)?;
+        let providers = read_contacts(&mut cursor)?;
+        Ok(NodesMessage {
+            sender,
+            nodes,
+            providers,
+        })
+    }
+}
+
+impl Message for NodesMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Nodes
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_contact(&mut v, &self.sender);
+        write_contacts(&mut v, &self.nodes);
+        write_contacts(&mut v, &self.providers);
+        v
+    }
+}
+
+/// A message for Delete requests
+///
+/// The server replies to it with a `DeletedMessage`, or with a `NotFoundMessage` if nothing
+/// was stored under the hash.
+#[derive(Debug)]
+pub struct DeleteMessage {
+    pub hash: Vec<u8>,
+    /// Whether only the copy of the server is deleted, as a node deleting its own copy
+    /// asks the nodes holding the other copies
+    pub replica: bool,
+}
+
+impl DeleteMessage {
+    pub fn new(hash: Vec<u8>) -> DeleteMessage {
+        DeleteMessage {
+            hash,
+            replica: false,
+        }
+    }
+
+    /// A request for the server to delete its copy of the data of `hash`, and no other.
+    pub fn replica(hash: Vec<u8>) -> DeleteMessage {
+        DeleteMessage {
+            hash,
+            replica: true,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<DeleteMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let hash = read_hash(&mut cursor)?;
+        let replica = cursor.read_u8()
+            .or(Err("Could not read replica flag from buffer"))? != 0;
+        Ok(DeleteMessage {
+            hash,
+            replica,
+        })
+    }
+}
+
+impl Message for DeleteMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Delete
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(2 + self.hash.len() + 1);
+        write_hash(&mut v, &self.hash);
+        v.write_u8(self.replica as u8).unwrap();
+        v
+    }
+}
+
+/// A reply reporting that the data stored under a hash was deleted
+#[derive(Debug)]
+pub struct DeletedMessage {
+    pub hash: Vec<u8>,
+}
+
+impl DeletedMessage {
+    pub fn new(hash: Vec<u8>) -> DeletedMessage {
+        DeletedMessage {
+            hash,
+        }
+    }
+}
+
+impl Message for DeletedMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Deleted
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        self.hash.clone()
+    }
+}
+
+/// A reply reporting that a request could not be served
+#[derive(Debug)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl ErrorMessage {
+    pub fn new<S: Into<String>>(code: ErrorCode, reason: S) -> ErrorMessage {
+        ErrorMessage {
+            code,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<ErrorMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let code = cursor.read_u16::<LittleEndian>()
+            .or(Err("Could not read error code from buffer"))?
+            .into();
+        let start = cursor.position() as usize;
+        let reason = String::from_utf8_lossy(&cursor.into_inner()[start..]).into_owned();
+        Ok(ErrorMessage {
+            code,
+            reason,
+        })
+    }
+}
+
+impl Message for ErrorMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Error
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(2 + self.reason.len());
+        v.write_u16::<LittleEndian>(self.code.into()).unwrap();
+        v.extend(self.reason.as_bytes());
+        v
+    }
+}
+
+/// A message for Hello requests, by which a client asks to authenticate
+///
+/// The server replies with a `ChallengeMessage`.
+#[derive(Debug, Default)]
+pub struct HelloMessage;
+
+impl HelloMessage {
+    pub fn new() -> HelloMessage {
+        HelloMessage
+    }
+}
+
+impl Message for HelloMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Hello
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        Vec::new()
+    }
+}
+
+/// A reply holding the nonce a client proves it holds a token with
+#[derive(Debug)]
+pub struct ChallengeMessage {
+    pub nonce: Vec<u8>,
+}
+
+impl ChallengeMessage {
+    pub fn new(nonce: Vec<u8>) -> ChallengeMessage {
+        ChallengeMessage {
+            nonce,
+        }
+    }
+}
+
+impl Message for ChallengeMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Challenge
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        self.nonce.clone()
+    }
+}
+
+/// A message for Authenticate requests, which answer the last challenge of the server with
+/// the HMAC of its nonce made with the secret of the token `name`
+///
+/// The server replies with an `AuthenticatedMessage`, or with an error if it does not
+/// accept the token.
+#[derive(Debug)]
+pub struct AuthenticateMessage {
+    pub name: String,
+    pub mac: Vec<u8>,
+}
+
+impl AuthenticateMessage {
+    pub fn new(name: String, mac: Vec<u8>) -> AuthenticateMessage {
+        AuthenticateMessage {
+            name,
+            mac,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<AuthenticateMessage, String> {
+        let mut cursor = Cursor::new(buf);
+        let name = String::from_utf8(read_hash(&mut cursor)?)
+            .or(Err("Token name is not valid utf8"))?;
+        let mac = read_hash(&mut cursor)?;
+        Ok(AuthenticateMessage {
+            name,
+            mac,
+        })
+    }
+}
+
+impl Message for AuthenticateMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Authenticate
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_hash(&mut v, self.name.as_bytes());
+        write_hash(&mut v, &self.mac);
+        v
+    }
+}
+
+/// A reply reporting what the token a client authenticated with allows it to do
+#[derive(Debug)]
+pub struct AuthenticatedMessage {
+    pub permissions: Permissions,
+}
+
+impl AuthenticatedMessage {
+    pub fn new(permissions: Permissions) -> AuthenticatedMessage {
+        AuthenticatedMessage {
+            permissions,
+        }
+    }
+
+    pub fn try_from(buf: Vec<u8>) -> Result<AuthenticatedMessage, String> {
+        let bits = Cursor::new(buf).read_u8()
+            .or(Err("Could not read permissions from buffer"))?;
+        Ok(AuthenticatedMessage::new(Permissions::from_bits(bits)))
+    }
+}
+
+impl Message for AuthenticatedMessage {
+    fn get_type(&self) -> MessageType {
+        MessageType::Authenticated
+    }
+
+    fn get_contents(&self) -> Vec<u8> {
+        vec![self.permissions.bits()]
+    }
+}
+
+/// A decoded kitap message, or a piece of the data that follows a Place, Append or Replicate
+/// message.
+#[derive(Debug)]
+pub enum Frame {
+    Place(PlaceMessage),
+    Fetch(FetchMessage),
+    NotFound(NotFoundMessage),
+    PlaceOk(PlaceOkMessage),
+    Error(ErrorMessage),
+    Data(DataMessage),
+    Delete(DeleteMessage),
+    Deleted(DeletedMessage),
+    Stat(StatMessage),
+    Stats(StatsMessage),
+    List(ListMessage),
+    Listing(ListingMessage),
+    Resolve(ResolveMessage),
+    Resolved(ResolvedMessage),
+    Upload(UploadMessage),
+    UploadStatus(UploadStatusMessage),
+    Append(AppendMessage),
+    Commit(CommitMessage),
+    FetchRange(FetchRangeMessage),
+    FindNode(FindNodeMessage),
+    FindValue(FindValueMessage),
+    Store(StoreMessage),
+    Nodes(NodesMessage),
+    Replicate(ReplicateMessage),
+    Hello(HelloMessage),
+    Challenge(ChallengeMessage),
+    Authenticate(AuthenticateMessage),
+    Authenticated(AuthenticatedMessage),
+    /// Raw data placed by the last Place, Append or Replicate message
+    Body(Bytes),
+    /// A message that could not be parsed. Its bytes have been consumed, so the stream can
+    /// still be used.
+    Invalid(String),
+}
+
+impl Frame {
+    fn try_from(typ: MessageType, buf: Vec<u8>) -> Result<Frame, String> {
+        match typ {
+            MessageType::Place => PlaceMessage::try_from(buf).map(Frame::Place),
+            MessageType::Fetch => FetchMessage::try_from(buf).map(Frame::Fetch),
+            MessageType::NotFound => Ok(Frame::NotFound(NotFoundMessage::new(buf))),
+            MessageType::PlaceOk => Ok(Frame::PlaceOk(PlaceOkMessage::new(buf))),
+            MessageType::Error => ErrorMessage::try_from(buf).map(Frame::Error),
+            MessageType::Data => DataMessage::try_from(buf).map(Frame::Data),
+            MessageType::Delete => DeleteMessage::try_from(buf).map(Frame::Delete),
+            MessageType::Deleted => Ok(Frame::Deleted(DeletedMessage::new(buf))),
+            MessageType::Stat => StatMessage::try_from(buf).map(Frame::Stat),
+            MessageType::Stats => StatsMessage::try_from(buf).map(Frame::Stats),
+            MessageType::List => ListMessage::try_from(buf).map(Frame::List),
+            MessageType::Listing => ListingMessage::try_from(buf).map(Frame::Listing),
+            MessageType::Resolve => ResolveMessage::try_from(buf).map(Frame::Resolve),
+            MessageType::Resolved => ResolvedMessage::try_from(buf).map(Frame::Resolved),
+            MessageType::Upload => UploadMessage::try_from(buf).map(Frame::Upload),
+            MessageType::UploadStatus => UploadStatusMessage::try_from(buf).map(Frame::UploadStatus),
+            MessageType::Append => AppendMessage::try_from(buf).map(Frame::Append),
+            MessageType::Commit => Ok(Frame::Commit(CommitMessage::new(buf))),
+            MessageType::FetchRange => FetchRangeMessage::try_from(buf).map(Frame::FetchRange),
+            MessageType::FindNode => FindNodeMessage::try_from(buf).map(Frame::FindNode),
+            MessageType::FindValue => FindValueMessage::try_from(buf).map(Frame::FindValue),
+            MessageType::Store => StoreMessage::try_from(buf).map(Frame::Store),
+            MessageType::Nodes => NodesMessage::try_from(buf).map(Frame::Nodes),
+            MessageType::Replicate => ReplicateMessage::try_from(buf).map(Frame::Replicate),
+            MessageType::Hello => Ok(Frame::Hello(HelloMessage::new())),
+            MessageType::Challenge => Ok(Frame::Challenge(ChallengeMessage::new(buf))),
+            MessageType::Authenticate => AuthenticateMessage::try_from(buf).map(Frame::Authenticate),
+            MessageType::Authenticated => AuthenticatedMessage::try_from(buf).map(Frame::Authenticated),
+            MessageType::Unknown => Err("Unknown message type".to_string()),
+        }
+    }
+
+    fn as_message(&self) -> Option<&dyn Message> {
+        match self {
+            Frame::Place(m) => Some(m),
+            Frame::Fetch(m) => Some(m),
+            Frame::NotFound(m) => Some(m),
+            Frame::PlaceOk(m) => Some(m),
+            Frame::Error(m) => Some(m),
+            Frame::Data(m) => Some(m),
+            Frame::Delete(m) => Some(m),
+            Frame::Deleted(m) => Some(m),
+            Frame::Stat(m) => Some(m),
+            Frame::Stats(m) => Some(m),
+            Frame::List(m) => Some(m),
+            Frame::Listing(m) => Some(m),
+            Frame::Resolve(m) => Some(m),
+            Frame::Resolved(m) => Some(m),
+            Frame::Upload(m) => Some(m),
+            Frame::UploadStatus(m) => Some(m),
+            Frame::Append(m) => Some(m),
+            Frame::Commit(m) => Some(m),
+            Frame::FetchRange(m) => Some(m),
+            Frame::FindNode(m) => Some(m),
+            Frame::FindValue(m) => Some(m),
+            Frame::Store(m) => Some(m),
+            Frame::Nodes(m) => Some(m),
+            Frame::Replicate(m) => Some(m),
+            Frame::Hello(m) => Some(m),
+            Frame::Challenge(m) => Some(m),
+            Frame::Authenticate(m) => Some(m),
+            Frame::Authenticated(m) => Some(m),
+            Frame::Body(_) | Frame::Invalid(_) => None,
+        }
+    }
+}
+
+/// Encodes and decodes kitap messages to and from a byte stream.
+///
+/// Frames are paired with the id of the request they belong to. The data sent with a
+/// Place, Append or Replicate message is not framed, so after decoding one the codec hands
+/// out the following `datasize` bytes as `Frame::Body` pieces, with the id of the message.
+#[derive(Debug)]
+pub struct KitapCodec {
+    body_id: RequestId,
+    body_remaining: u64,
+    max_frame_len: usize,
+}
+
+impl Default for KitapCodec {
+    fn default() -> KitapCodec {
+        KitapCodec::new()
+    }
+}
+
+/// The longest message a `KitapCodec` decodes unless told otherwise.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 1024 * 1024 * 1024;
+
+/// The most data a `DataMessage` can carry and still be decoded by a default codec: hash
+/// length (2) + hash + data. Servers refuse to fetch larger blobs at once, with
+/// `ErrorCode::TooLarge`, so that clients fetch ranges of them instead.
+pub const MAX_DATA_LEN: u64 = (DEFAULT_MAX_FRAME_LEN - 2 - HASH_SIZE) as u64;
+
+impl KitapCodec {
+    pub fn new() -> KitapCodec {
+        KitapCodec::with_max_frame_len(DEFAULT_MAX_FRAME_LEN)
+    }
+
+    /// Creates a codec that refuses to decode messages longer than `max_frame_len`, so that
+    /// peers cannot make it buffer arbitrary amounts of data.
+    pub fn with_max_frame_len(max_frame_len: usize) -> KitapCodec {
+        KitapCodec {
+            body_id: 0,
+            body_remaining: 0,
+            max_frame_len,
+        }
+    }
+}
+
+impl Decoder for KitapCodec {
+    type Item = (RequestId, Frame);
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<(RequestId, Frame)>, io::Error> {
+        if self.body_remaining > 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            let n = cmp::min(buf.len() as u64, self.body_remaining) as usize;
+            self.body_remaining -= n as u64;
+            return Ok(Some((self.body_id, Frame::Body(buf.split_to(n).freeze()))));
+        }
+        if buf.len() < MSG_HEADER_LEN {
+            return Ok(None);
+        }
+        let mut header = &buf[..MSG_HEADER_LEN];
+        let typ = header.read_u16::<LittleEndian>()?.into();
+        let id = header.read_u32::<LittleEndian>()?;
+        let len = header.read_u64::<LittleEndian>()?;
+        let too_long = || io::Error::new(io::ErrorKind::InvalidData, format!("Message of {} bytes is too long", len));
+        if len > self.max_frame_len as u64 {
+            return Err(too_long());
+        }
+        let frame_len = usize::try_from(len).ok()
+            .and_then(|len| len.checked_add(MSG_HEADER_LEN))
+            .ok_or_else(too_long)?;
+        if buf.len() < frame_len {
+            buf.reserve(frame_len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(MSG_HEADER_LEN);
+        let contents = buf.split_to(frame_len - MSG_HEADER_LEN).to_vec();
+        let frame = match Frame::try_from(typ, contents) {
+            Ok(frame) => frame,
+            // the length of the data that follows is part of what could not be read, so
+            // there is no telling where the next message starts
+            Err(e) if typ == MessageType::Place || typ == MessageType::Append || typ == MessageType::Replicate => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            },
+            Err(e) => Frame::Invalid(e),
+        };
+        match frame {
+            Frame::Place(PlaceMessage { datasize, .. })
+            | Frame::Append(AppendMessage { datasize, .. })
+            | Frame::Replicate(ReplicateMessage { datasize, .. }) => {
+                self.body_id = id;
+                self.body_remaining = datasize;
+            },
+            _ => (),
+        }
+        Ok(Some((id, frame)))
+    }
+}
+
+impl Encoder for KitapCodec {
+    type Item = (RequestId, Frame);
+    type Error = io::Error;
+
+    /// Encodes `frame` as part of request `id`. Body frames are written as they are, so
+    /// they must directly follow the Place, Append or Replicate message they belong to.
+    fn encode(&mut self, (id, frame): (RequestId, Frame), buf: &mut BytesMut) -> Result<(), io::Error> {
+        match frame {
+            Frame::Body(data) => buf.extend_from_slice(&data),
+            Frame::Invalid(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+            frame => buf.extend_from_slice(&frame.as_message().unwrap().to_bytes(id)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(typ: MessageType, len: u64) -> BytesMut {
+        let mut buf = Vec::new();
+        buf.write_u16::<LittleEndian>(typ.into()).unwrap();
+        buf.write_u32::<LittleEndian>(7).unwrap();
+        buf.write_u64::<LittleEndian>(len).unwrap();
+        BytesMut::from(buf)
+    }
+
+    #[test]
+    fn refuses_lengths_over_the_limit() {
+        let mut codec = KitapCodec::new();
+        assert!(codec.decode(&mut header(MessageType::Data, u64::MAX)).is_err());
+        let mut codec = KitapCodec::with_max_frame_len(16);
+        assert!(codec.decode(&mut header(MessageType::Data, 17)).is_err());
+    }
+
+    #[test]
+    fn fails_on_unreadable_messages_followed_by_data() {
+        let mut codec = KitapCodec::new();
+        let mut buf = header(MessageType::Place, 3);
+        buf.extend_from_slice(b"abcdata");
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn hands_out_the_data_that_follows_a_place() {
+        let mut codec = KitapCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode((3, Frame::Place(PlaceMessage::new(vec![1; HASH_SIZE], 5))), &mut buf).unwrap();
+        buf.extend_from_slice(b"helloworld");
+        match codec.decode(&mut buf).unwrap() {
+            Some((3, Frame::Place(msg))) => assert_eq!(msg.datasize, 5),
+            frame => panic!("unexpected {:?}", frame),
+        }
+        match codec.decode(&mut buf).unwrap() {
+            Some((3, Frame::Body(data))) => assert_eq!(&data[..], b"hello"),
+            frame => panic!("unexpected {:?}", frame),
+        }
+        assert_eq!(&buf[..], b"world");
+    }
+
+    #[test]
+    fn agrees_on_the_protocol_version() {
+        let ours = Handshake::to_bytes(PROTOCOL_VERSION);
+        assert_eq!(&ours[..4], b"KTAP");
+        assert_eq!(Handshake::parse(ours), Handshake::Version(PROTOCOL_VERSION));
+        assert_eq!(check_server_handshake(ours), Ok(()));
+        assert!(check_server_handshake(Handshake::to_bytes(PROTOCOL_VERSION + 1)).is_err());
+        assert!(check_server_handshake(Handshake::to_bytes(2)).is_err());
+    }
+
+    #[test]
+    fn tells_version_1_clients_they_are_not_spoken() {
+        // version 1 clients open with the header of a message instead of a handshake
+        let mut first = [0; HANDSHAKE_LEN];
+        first[..2].copy_from_slice(&u16::from(MessageType::Place).to_le_bytes());
+        first[2..].copy_from_slice(&(HASH_SIZE as u32 + 8).to_le_bytes());
+        assert_eq!(Handshake::parse(first), Handshake::Legacy);
+        assert!(check_server_handshake(first).is_err());
+
+        let refusal = ErrorMessage::new(ErrorCode::UnsupportedVersion, "please upgrade").to_legacy_bytes();
+        let mut header = Cursor::new(&refusal[..LEGACY_MSG_HEADER_LEN]);
+        assert_eq!(MessageType::from(header.read_u16::<LittleEndian>().unwrap()), MessageType::Error);
+        let len = header.read_u32::<LittleEndian>().unwrap() as usize;
+        assert_eq!(len, refusal.len() - LEGACY_MSG_HEADER_LEN);
+        let msg = ErrorMessage::try_from(refusal[LEGACY_MSG_HEADER_LEN..].to_vec()).unwrap();
+        assert_eq!(msg.code, ErrorCode::UnsupportedVersion);
+        assert_eq!(msg.reason, "please upgrade");
+    }
+
+    #[test]
+    fn reads_place_messages_of_the_expected_length_only() {
+        let msg = PlaceMessage::new(vec![3; HASH_SIZE], u64::MAX);
+        let contents = msg.get_contents();
+        let read = PlaceMessage::try_from(contents.clone()).unwrap();
+        assert_eq!((read.hash, read.datasize), (vec![3; HASH_SIZE], u64::MAX));
+
+        assert!(PlaceMessage::try_from(contents[..contents.len() - 1].to_vec()).is_err());
+        assert!(PlaceMessage::try_from(contents[..HASH_SIZE].to_vec()).is_err());
+        assert!(PlaceMessage::try_from(Vec::new()).is_err());
+        let mut longer = contents;
+        longer.push(0);
+        assert!(PlaceMessage::try_from(longer).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use futures::future;
+use futures::sync::oneshot;
+use futures::{stream, Future, Stream};
+
+use hex::encode;
+
+use log::{debug, info};
+
+use crate::client::FoundValue;
+use crate::dht::{distance, Contact, Dht};
+use crate::tls::Connector;
+use crate::utils::BoxedFuture;
+
+/// How much of a blob is read from the store at a time while it is copied.
+const COPY_PIECE: u64 = 1024 * 1024;
+
+/// Reads at most `len` bytes of a stored blob, starting at `offset`.
+pub type ReadRange = Arc<dyn Fn(u64, u64) -> BoxedFuture<Vec<u8>, String> + Send + Sync>;
+
+/// Where the blobs placed on a node are copied to, and how many copies must be made before
+/// a place is acknowledged.
+///
+/// Each blob is copied to the `replicas - 1` nodes closest to its hash, taken from a static
+/// list of peers if one is given, and from the overlay otherwise. Since the closest nodes
+/// are the same wherever the blob is looked for, any node can tell where its copies are.
+#[derive(Debug, Clone)]
+pub struct Replication {
+    replicas: usize,
+    quorum: usize,
+    peers: Vec<Contact>,
+    dht: Dht,
+}
+
+impl Replication {
+    /// Keeps `replicas` copies of every blob, counting the one of this node, and
+    /// acknowledges a place once `quorum` of them are stored.
+    ///
+    /// The copies are made on `peers`, or on the nodes of the overlay of `dht` if there are
+    /// no peers. Peers other than this node must be enough to hold the copies.
+    pub fn new(replicas: usize, quorum: usize, peers: Vec<SocketAddr>, dht: Dht) -> Result<Replication, String> {
+        if replicas == 0 {
+            return Err("At least one replica is needed".to_string());
+        }
+        if quorum == 0 || quorum > replicas {
+            return Err(format!("The write quorum must be between 1 and {}", replicas));
+        }
+        let own = dht.contact().addr;
+        let peers = peers.into_iter()
+            .filter(|addr| *addr != own)
+            .map(Contact::from_addr)
+            .collect::<Vec<_>>();
+        if !peers.is_empty() && peers.len() < replicas - 1 {
+            return Err(format!("{} copies of each blob need {} peers besides this node, {} are given",
+                replicas, replicas - 1, peers.len()));
+        }
+        Ok(Replication {
+            replicas,
+            quorum,
+            peers,
+            dht,
+        })
+    }
+
+    /// The nodes the data of `hash` is copied to.
+    pub fn targets(&self, hash: &[u8]) -> BoxedFuture<Vec<SocketAddr>, ()> {
+        let count = self.replicas - 1;
+        if count == 0 {
+            return Box::new(future::ok(Vec::new()));
+        }
+        if !self.peers.is_empty() {
+            let mut peers = self.peers.clone();
+            peers.sort_by_key(|c| distance(&c.id, hash));
+            return Box::new(future::ok(peers.into_iter().take(count).map(|c| c.addr).collect()));
+        }
+        Box::new(self.dht.lookup_nodes(hash.to_vec())
+            .map(move |closest| closest.into_iter().take(count).map(|c| c.addr).collect()))
+    }
+
+    /// Copies the `len` bytes of `hash` to the nodes it belongs on, reading them from where
+    /// they are stored with `read`.
+    ///
+    /// Resolves to whether the quorum was met, as soon as it is. The copies go on in the
+    /// background after that.
+    pub fn replicate(&self, hash: Vec<u8>, len: u64, read: ReadRange) -> BoxedFuture<bool, ()> {
+        let needed = self.quorum - 1;
+        let (met, quorum) = oneshot::channel();
+        let mut met = Some(met);
+        if needed == 0 {
+            let _ = met.take().map(|met| met.send(()));
+        }
+        let connector = self.dht.connector().clone();
+        let copies = self.targets(&hash)
+            .map(move |targets| {
+                let copies = targets.into_iter()
+                    .map(move |addr| copy_to(&connector, addr, hash.clone(), len, pieces(read.clone(), len)));
+                stream::futures_unordered(copies)
+            })
+            .flatten_stream()
+            .fold((0, met), move |(copied, mut met), ok| {
+                let copied = if ok { copied + 1 } else { copied };
+                if copied >= needed {
+                    let _ = met.take().map(|met| met.send(()));
+                }
+                Ok((copied, met))
+            })
+            .map(|(copied, _)| debug!("Made {} copies", copied));
+        tokio::spawn(copies);
+        Box::new(quorum.then(|met| Ok(met.is_ok())))
+    }
+
+    /// Deletes the copies of the data of `hash` made on other nodes. Resolves to how many
+    /// copies were deleted.
+    ///
+    /// The copies are looked for on the nodes the data would be copied to now, so copies
+    /// left on nodes of the overlay that are no longer among the closest to the hash stay.
+    pub fn delete_copies(&self, hash: Vec<u8>) -> BoxedFuture<usize, ()> {
+        let connector = self.dht.connector().clone();
+        Box::new(self.targets(&hash)
+            .and_then(move |targets| {
+                let deletes: Vec<_> = targets.into_iter()
+                    .map(|addr| {
+                        let hash = hash.clone();
+                        connector.connect(&addr)
+                            .and_then(move |client| client.delete_replica(&hash))
+                            .then(move |res| match res {
+                                Ok(deleted) => Ok(deleted),
+                                Err(e) => {
+                                    info!("Could not delete the copy on {}: {}", addr, e);
+                                    Ok(false)
+                                },
+                            })
+                    })
+                    .collect();
+                future::join_all(deletes)
+            })
+            .map(|deleted| deleted.into_iter().filter(|d| *d).count()))
+    }
+
+    /// Asks the peers that should hold a copy of `hash` for at most `len` bytes of it,
+    /// starting at `offset`.
+    ///
+    /// Copies made on the nodes of the overlay are found by looking them up instead.
+    pub fn find_copy(&self, hash: Vec<u8>, offset: u64, len: u64) -> BoxedFuture<Option<Vec<u8>>, ()> {
+        if self.peers.is_empty() {
+            return Box::new(future::ok(None));
+        }
+        let connector = self.dht.connector().clone();
+        Box::new(self.targets(&hash)
+            .and_then(move |targets| stream::iter_ok::<_, ()>(targets)
+                .and_then(move |addr| {
+                    let hash = hash.clone();
+                    connector.connect(&addr)
+                        .and_then(move |client| client.find_value(None, &hash, offset, len))
+                        .then(move |res| match res {
+                            Ok(FoundValue::Data(data)) => Ok(Some(data)),
+                            Ok(FoundValue::Nodes(_)) => Ok(None),
+                            Err(e) => {
+                                debug!("Could not ask {} for a copy: {}", addr, e);
+                                Ok(None)
+                            },
+                        })
+                })
+                .filter_map(|data| data)
+                .into_future()
+                .map(|(data, _)| data)
+                .map_err(|_| ())))
+    }
+}
+
+/// The `len` bytes of a stored blob, read with `read` a piece at a time.
+///
+/// Fails if the blob is no longer stored, or is shorter than `len`.
+fn pieces(read: ReadRange, len: u64) -> impl Stream<Item = Bytes, Error = io::Error> {
+    stream::unfold(0, move |offset| {
+        if offset >= len {
+            return None;
+        }
+        Some(read(offset, cmp::min(COPY_PIECE, len - offset))
+            .map_err(io::Error::other)
+            .and_then(move |data| {
+                if data.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stored data is shorter than its length"));
+                }
+                let next = offset + data.len() as u64;
+                Ok((Bytes::from(data), next))
+            }))
+    })
+}
+
+/// Copies the `len` bytes of `pieces` to the node at `addr`, under `hash`. Resolves to
+/// whether the node stored them.
+fn copy_to<S>(connector: &Connector, addr: SocketAddr, hash: Vec<u8>, len: u64, pieces: S) -> impl Future<Item = bool, Error = ()>
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    connector.connect(&addr)
+        .and_then(move |client| {
+            client.replicate_pieces(&hash, len, pieces)
+                .map(move |_| debug!("Copied {} to {}", encode(&hash), addr))
+        })
+        .then(move |res| match res {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                info!("Could not copy data to {}: {}", addr, e);
+                Ok(false)
+            },
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener as StdTcpListener;
+    use std::sync::Mutex;
+
+    use tokio::runtime::Runtime;
+
+    use crate::client::tests::serve;
+    use crate::messages::{Frame, PlaceOkMessage};
+    use crate::store::tests::hash_of;
+
+    /// An address nothing listens on.
+    fn dead_addr() -> SocketAddr {
+        StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    fn replication(replicas: usize, quorum: usize, peers: Vec<SocketAddr>) -> Result<Replication, String> {
+        let own = Contact::from_addr(dead_addr());
+        Replication::new(replicas, quorum, peers, Dht::new(own, Connector::Plain))
+    }
+
+    /// Serves a peer that stores every copy sent to it, and counts them.
+    fn serve_peer(runtime: &mut Runtime, copies: Arc<Mutex<Vec<Vec<u8>>>>) -> SocketAddr {
+        let mut receiving: Option<(Vec<u8>, u64, Vec<u8>)> = None;
+        serve(runtime, move |id, frame| {
+            match frame {
+                Frame::Replicate(msg) => receiving = Some((msg.hash, msg.datasize, Vec::new())),
+                Frame::Body(data) => receiving.as_mut().unwrap().2.extend_from_slice(&data),
+                frame => panic!("unexpected {:?}", frame),
+            }
+            match receiving.take() {
+                Some((hash, len, data)) if data.len() as u64 == len => {
+                    copies.lock().unwrap().push(data);
+                    vec![(id, Frame::PlaceOk(PlaceOkMessage::new(hash)))]
+                },
+                copying => {
+                    receiving = copying;
+                    Vec::new()
+                },
+            }
+        })
+    }
+
+    /// Copies `data` to the peers of `replication`, resolving to whether the quorum was met.
+    fn replicate(runtime: &mut Runtime, replication: Replication, data: Vec<u8>) -> bool {
+        let hash = hash_of(&data);
+        let len = data.len() as u64;
+        let read: ReadRange = Arc::new(move |offset, len| {
+            let start = cmp::min(offset as usize, data.len());
+            let end = cmp::min(start + len as usize, data.len());
+            Box::new(future::ok(data[start..end].to_vec()))
+        });
+        runtime.block_on(future::lazy(move || replication.replicate(hash, len, read))).unwrap()
+    }
+
+    #[test]
+    fn needs_enough_peers_for_the_copies() {
+        let (own, peer) = (dead_addr(), dead_addr());
+        assert!(replication(0, 0, Vec::new()).is_err());
+        assert!(replication(2, 0, Vec::new()).is_err());
+        assert!(replication(2, 3, Vec::new()).is_err());
+        assert!(replication(1, 1, Vec::new()).is_ok());
+        // the copies are made on the overlay
+        assert!(replication(3, 2, Vec::new()).is_ok());
+
+        assert!(replication(2, 2, vec![peer]).is_ok());
+        assert!(replication(3, 2, vec![peer]).is_err());
+        assert!(replication(3, 2, vec![peer, dead_addr()]).is_ok());
+        // this node does not keep a copy for another
+        let dht = Dht::new(Contact::from_addr(own), Connector::Plain);
+        assert!(Replication::new(3, 2, vec![own, peer], dht.clone()).is_err());
+        assert!(Replication::new(2, 2, vec![own, peer], dht).is_ok());
+    }
+
+    #[test]
+    fn acknowledges_places_once_the_quorum_is_met() {
+        let mut runtime = Runtime::new().unwrap();
+        let copies = Arc::new(Mutex::new(Vec::new()));
+        let peer = serve_peer(&mut runtime, copies.clone());
+        let data: Vec<u8> = (0..COPY_PIECE + 10).map(|i| i as u8).collect();
+
+        let both = replication(3, 3, vec![peer, dead_addr()]).unwrap();
+        assert!(!replicate(&mut runtime, both, data.clone()));
+        let one = replication(3, 2, vec![peer, dead_addr()]).unwrap();
+        assert!(replicate(&mut runtime, one, data.clone()));
+        assert_eq!(*copies.lock().unwrap(), vec![data.clone(), data.clone()]);
+
+        // none of the copies has to be made
+        let none = replication(3, 1, vec![dead_addr(), dead_addr()]).unwrap();
+        assert!(replicate(&mut runtime, none, data));
+    }
+}
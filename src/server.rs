@@ -1,85 +1,983 @@
+use std::cmp;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::sync::Arc;
-use std::io::Cursor;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use clap::App;
+use clap::{App, Arg, ArgMatches};
 
 use hex::encode;
 
+use futures::stream::SplitStream;
+use futures::sync::mpsc;
+use futures::sync::mpsc::Sender;
+
+use tokio::codec::Framed;
 use tokio::io::{read_exact, write_all};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
+use tokio::prelude::future::Loop;
+use tokio::timer::Interval;
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use tokio_rustls::TlsAcceptor;
 
-use log::{info, debug, trace};
+use log::{info, debug, trace, warn};
 
+use kitap::dht::{Contact, Dht, REPUBLISH_INTERVAL};
 use kitap::mapper::{Mapper, MapperReply};
-use kitap::utils::{SharedBuffer, BoxedFuture};
-use kitap::utils::{create_base_app, setup_logging};
-use kitap::messages::{MessageType, PlaceMessage, NotFoundMessage, Message};
-use kitap::messages::{MSG_HEADER_LEN};
+use kitap::replication::{ReadRange, Replication};
+use kitap::store::{BlobStore, ChunkStore, DiskStore, LogStore, MemoryStore, SpoolFile};
+use kitap::tls;
+use kitap::tls::Connector;
+use kitap::auth;
+use kitap::auth::{Credentials, Permissions, Tokens};
+use kitap::upload::Uploads;
+use kitap::utils::BoxedFuture;
+use kitap::utils::{bind, create_base_app, hash_reader, resolve, resolve_addr, setup_logging};
+use kitap::hash::{KitapHash, KitapHasher, HASH_SIZE};
+use kitap::messages::{PlaceMessage, PlaceOkMessage, NotFoundMessage, Message};
+use kitap::messages::{DataMessage, FetchMessage, MAX_DATA_LEN};
+use kitap::messages::{DeleteMessage, DeletedMessage, StatMessage, StatsMessage};
+use kitap::messages::{ListMessage, ListingMessage, ResolveMessage, ResolvedMessage};
+use kitap::messages::{AppendMessage, CommitMessage, FetchRangeMessage, UploadMessage, UploadStatusMessage};
+use kitap::messages::{FindNodeMessage, FindValueMessage, StoreMessage};
+use kitap::messages::{ErrorCode, ErrorMessage};
+use kitap::messages::{Frame, KitapCodec, RequestId};
+use kitap::messages::{AuthenticateMessage, AuthenticatedMessage, ChallengeMessage, ReplicateMessage};
+use kitap::messages::{Handshake, HANDSHAKE_LEN, PROTOCOL_VERSION};
 
 type VecVecMapper = Mapper<Vec<u8>, Vec<u8>>;
 
-const ERROR: [u8; 9] = [5, 0, 0, 0, 69, 82, 82, 79, 82];
+/// The longest message accepted from clients. Placed data is not framed, so this only
+/// bounds the size of the requests themselves.
+const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// The most hashes sent back in one page of a listing.
+const MAX_LIST_PAGE: u32 = 1000;
+
+/// The most candidates sent back for an ambiguous prefix.
+const MAX_RESOLVE_CANDIDATES: usize = 16;
+
+/// How many replies may be queued for a connection before its requests stop being served.
+const REPLY_QUEUE_LEN: usize = 16;
+
+/// How often the uploads that stopped receiving data are looked for and dropped.
+const UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The directory of the data directory in which uploads are spooled.
+const UPLOAD_DIR: &str = ".uploads";
+
+/// A connection with a client, over plain TCP or over TLS.
+trait Transport: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Transport for T {}
+
+type FrameStream = SplitStream<Framed<Box<dyn Transport>, KitapCodec>>;
+
+/// Queues replies to be written to a connection, in the order they are ready.
+type ReplySender = Sender<(RequestId, Frame)>;
+
+/// What the requests of every connection are served with.
+#[derive(Clone)]
+struct Node {
+    mapper: Arc<VecVecMapper>,
+    uploads: Uploads,
+    dht: Dht,
+    replication: Replication,
+    /// The tokens clients must authenticate with, if any
+    tokens: Option<Arc<Tokens>>,
+}
+
+/// What the client of a connection has proven it may do.
+#[derive(Debug)]
+struct Session {
+    /// The challenge last sent to the client, until it answers it
+    nonce: Option<Vec<u8>>,
+    /// What the client may do, or `None` until it authenticates
+    permissions: Option<Permissions>,
+}
+
+impl Session {
+    /// The clients of a node that accepts no tokens may do anything without authenticating.
+    fn new(node: &Node) -> Session {
+        Session {
+            nonce: None,
+            permissions: if node.tokens.is_none() { Some(Permissions::ALL) } else { None },
+        }
+    }
+
+    /// Checks that the client may make the request `frame`.
+    fn authorize(&self, frame: &Frame) -> Result<(), ErrorMessage> {
+        let needed = required_permissions(frame);
+        match self.permissions {
+            _ if needed == Permissions::NONE => Ok(()),
+            None => Err(ErrorMessage::new(ErrorCode::Unauthenticated, "Authenticate with a token first")),
+            Some(permissions) if permissions.contains(needed) => Ok(()),
+            Some(permissions) => Err(ErrorMessage::new(ErrorCode::Forbidden, format!(
+                "The token allows {}, but the request needs {}", permissions, needed))),
+        }
+    }
+}
+
+/// What a client must be allowed to make the request `frame`.
+fn required_permissions(frame: &Frame) -> Permissions {
+    match frame {
+        Frame::Fetch(_) | Frame::FetchRange(_) | Frame::Stat(_) | Frame::List(_) | Frame::Resolve(_)
+        | Frame::FindNode(_) | Frame::FindValue(_) => Permissions::READ,
+        Frame::Place(_) | Frame::Upload(_) | Frame::Append(_) | Frame::Commit(_) | Frame::Replicate(_)
+        | Frame::Store(_) => Permissions::WRITE,
+        Frame::Delete(_) => Permissions::ADMIN,
+        // requests that cannot be served are refused by dispatch
+        _ => Permissions::NONE,
+    }
+}
+
+/// Sends a challenge for the client to prove it holds a token with.
+fn process_hello(session: &mut Session, id: RequestId, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    let reply = match auth::nonce() {
+        Ok(nonce) => {
+            session.nonce = Some(nonce.clone());
+            Frame::Challenge(ChallengeMessage::new(nonce))
+        },
+        Err(e) => Frame::Error(ErrorMessage::new(ErrorCode::Internal, e)),
+    };
+    Box::new(send_reply(replies, id, reply).map(|_| frames))
+}
+
+/// Checks the answer of the client to the last challenge it was sent, and grants it what
+/// its token allows.
+///
+/// Nodes that accept no tokens grant everything to any client.
+fn process_authenticate(node: &Node, session: &mut Session, id: RequestId, msg: AuthenticateMessage, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    let nonce = session.nonce.take();
+    let reply = match (&node.tokens, nonce) {
+        (None, _) => Frame::Authenticated(AuthenticatedMessage::new(Permissions::ALL)),
+        (Some(_), None) => {
+            Frame::Error(ErrorMessage::new(ErrorCode::Unauthenticated, "Ask for a challenge with a Hello request first"))
+        },
+        (Some(tokens), Some(nonce)) => {
+            session.permissions = tokens.verify(&msg.name, &nonce, &msg.mac);
+            match session.permissions {
+                Some(permissions) => {
+                    info!("Client authenticated as {}, allowed {}", msg.name, permissions);
+                    Frame::Authenticated(AuthenticatedMessage::new(permissions))
+                },
+                None => {
+                    info!("Client failed to authenticate as {}", msg.name);
+                    Frame::Error(ErrorMessage::new(ErrorCode::Unauthenticated, "Unknown token or wrong secret"))
+                },
+            }
+        },
+    };
+    Box::new(send_reply(replies, id, reply).map(|_| frames))
+}
+
+/// Refuses request `id` with `m`, reading the data that follows `frame` so that the next
+/// requests can be read.
+fn refuse(id: RequestId, frame: &Frame, m: ErrorMessage, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    let datasize = match frame {
+        Frame::Place(PlaceMessage { datasize, .. })
+        | Frame::Append(AppendMessage { datasize, .. })
+        | Frame::Replicate(ReplicateMessage { datasize, .. }) => *datasize,
+        _ => 0,
+    };
+    info!("Refusing request {}: {}", id, m.reason);
+    Box::new(receive_body(frames, io::sink(), datasize)
+        .and_then(move |(frames, _, _)| send_reply(replies, id, Frame::Error(m)).map(|_| frames)))
+}
+
+/// Serves the request `frame`, as far as the client of `session` may make it.
+fn serve(node: Node, session: &mut Session, id: RequestId, frame: Frame, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    match frame {
+        Frame::Hello(_) => process_hello(session, id, frames, replies),
+        Frame::Authenticate(msg) => process_authenticate(&node, session, id, msg, frames, replies),
+        frame => match session.authorize(&frame) {
+            Ok(()) => dispatch(node, id, frame, frames, replies),
+            Err(m) => refuse(id, &frame, m, frames, replies),
+        },
+    }
+}
+
+/// Builds the reply for an unexpected or failed reply of the mapper.
+fn mapper_error(reply: MapperReply<Vec<u8>, Vec<u8>>) -> Frame {
+    match reply {
+        MapperReply::Error(e) => Frame::Error(ErrorMessage::new(ErrorCode::Internal, e)),
+        _ => Frame::Error(ErrorMessage::new(ErrorCode::Internal, "Unexpected reply from mapper")),
+    }
+}
+
+/// Queues `reply` to request `id` to be written to the client.
+fn send_reply(replies: ReplySender, id: RequestId, reply: Frame) -> impl Future<Item = ReplySender, Error = String> {
+    replies.send((id, reply))
+        .map_err(|_| "Connection closed before the response was sent".to_string())
+}
 
-fn process_fetch(cloned_mapper: Arc<VecVecMapper>, key: Vec<u8>, wx: tokio::io::WriteHalf<TcpStream>) -> BoxedFuture<(), String> {
+/// Looks for the data of `key` on the other nodes, as it is not stored here: on the peers
+/// that should hold a copy of it first, then in the overlay.
+fn find_elsewhere(node: &Node, key: Vec<u8>) -> impl Future<Item = Option<Vec<u8>>, Error = ()> {
+    let dht = node.dht.clone();
+    node.replication.find_copy(key.clone(), 0, u64::MAX)
+        .and_then(move |data| match data {
+            Some(data) => future::Either::A(future::ok(Some(data))),
+            None => future::Either::B(dht.find_value(key, 0, u64::MAX)),
+        })
+}
+
+/// Looks for the data of `key` on the other nodes, and checks it against the hash.
+fn fetch_elsewhere(node: &Node, key: Vec<u8>) -> impl Future<Item = Frame, Error = String> {
+    find_elsewhere(node, key.clone())
+        .then(move |found| {
+            let data = match found {
+                Ok(Some(data)) => data,
+                _ => return Ok(Frame::NotFound(NotFoundMessage::new(key))),
+            };
+            let mut hasher = KitapHasher::new();
+            hasher.input(&data);
+            if hasher.result().as_slice() != key.as_slice() {
+                info!("Discarding data found for {} on another node, which does not match its hash", encode(&key));
+                return Ok(Frame::NotFound(NotFoundMessage::new(key)));
+            }
+            info!("Found {} on another node", encode(&key));
+            Ok(Frame::Data(DataMessage::new(key, Arc::new(data))))
+        })
+}
+
+/// Refuses to fetch data of `size` bytes at once if it does not fit in a single message.
+fn too_large(key: &[u8], size: u64) -> Option<Frame> {
+    if size <= MAX_DATA_LEN {
+        return None;
+    }
+    info!("Refusing to fetch {} bytes of {} at once", size, encode(key));
+    Some(Frame::Error(ErrorMessage::new(ErrorCode::TooLarge, format!(
+        "{} holds {} bytes, more than can be fetched at once, fetch ranges of it instead",
+        encode(key), size))))
+}
+
+/// Looks `key` up in the mapper, then on the other nodes, and replies with the result.
+///
+/// The size of the data is checked before it is read, so that data which could not be sent
+/// in a single message is not read at all.
+fn fetch_one(node: Node, id: RequestId, key: Vec<u8>, replies: ReplySender) -> impl Future<Item = ReplySender, Error = String> {
     let arc_key = Arc::new(key);
     info!("Received fetch message for key: {}", encode(arc_key.as_ref()));
-    Box::new(cloned_mapper.get(arc_key.clone())
+    let mapper = node.mapper.clone();
+    node.mapper.stat(arc_key.clone())
         .and_then(move |reply| {
             debug!("Got reply from mapper {:?}", reply);
-            let w = match reply {
-                MapperReply::Data(r) => {
-                    let mut v = Vec::with_capacity(4 + r.data.len());
-                    v.write_u32::<LittleEndian>(r.data.len() as u32).unwrap();
-                    v.extend(r.data.as_ref());
-                    trace!("Retrieved data {}", encode(&v));
-                    SharedBuffer::new(Arc::new(v))
+            let reply: BoxedFuture<Frame, String> = match reply {
+                MapperReply::Stat(stat) => match too_large(&arc_key, stat.size) {
+                    Some(refusal) => Box::new(future::ok(refusal)),
+                    None => Box::new(mapper.get(arc_key.clone())
+                        .map(move |reply| match reply {
+                            MapperReply::Data(r) => {
+                                trace!("Retrieved data {}", encode(r.data.as_ref()));
+                                Frame::Data(DataMessage::new(arc_key.to_vec(), r.data))
+                            },
+                            MapperReply::NotFound => Frame::NotFound(NotFoundMessage::new(arc_key.to_vec())),
+                            r => mapper_error(r),
+                        })),
                 },
-                MapperReply::NotFound => {
-                    let cloned_key = arc_key.clone();
-                    let m = NotFoundMessage::new(cloned_key.as_ref());
-                    let bytes = Arc::new(m.into_bytes());
-                    debug!("Sending: {:?}", bytes);
-                    SharedBuffer::new(bytes)
+                MapperReply::NotFound => Box::new(fetch_elsewhere(&node, arc_key.to_vec())
+                    .map(|found| match found {
+                        Frame::Data(msg) => too_large(&msg.hash, msg.data.len() as u64)
+                            .unwrap_or(Frame::Data(msg)),
+                        reply => reply,
+                    })),
+                r => Box::new(future::ok(mapper_error(r))),
+            };
+            reply.and_then(move |reply| send_reply(replies, id, reply))
+        })
+}
+
+fn process_fetch(node: Node, id: RequestId, msg: FetchMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    debug!("Fetching {} hashes", msg.hashes.len());
+    stream::iter_ok(msg.hashes)
+        .fold(replies, move |replies, key| fetch_one(node.clone(), id, key, replies))
+        .map(move |_| info!("Served request {}", id))
+}
+
+/// Describes every requested hash in a single reply, without reading their data.
+fn process_stat(cloned_mapper: Arc<VecVecMapper>, id: RequestId, msg: StatMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    debug!("Describing {} hashes", msg.hashes.len());
+    stream::iter_ok(msg.hashes)
+        .and_then(move |key| {
+            let key = Arc::new(key);
+            cloned_mapper.stat(key.clone())
+                .map(move |reply| (key.to_vec(), reply))
+        })
+        .collect()
+        .and_then(move |replies_of_mapper| {
+            let stats: Result<Vec<_>, Frame> = replies_of_mapper.into_iter()
+                .map(|(key, reply)| match reply {
+                    MapperReply::Stat(stat) => Ok((key, Some(stat))),
+                    MapperReply::NotFound => Ok((key, None)),
+                    r => Err(mapper_error(r)),
+                })
+                .collect();
+            let reply = match stats {
+                Ok(stats) => Frame::Stats(StatsMessage::new(stats)),
+                Err(e) => e,
+            };
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id))
+}
+
+fn process_list(cloned_mapper: Arc<VecVecMapper>, id: RequestId, msg: ListMessage, replies: ReplySender) -> BoxedFuture<(), String> {
+    debug!("Listing hashes starting with {:?}", msg.prefix);
+    if !msg.prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        let m = ErrorMessage::new(ErrorCode::BadRequest, format!("Invalid hex prefix {:?}", msg.prefix));
+        return Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| ()));
+    }
+    let limit = match msg.limit {
+        0 => MAX_LIST_PAGE,
+        limit => cmp::min(limit, MAX_LIST_PAGE),
+    };
+    Box::new(cloned_mapper.list(msg.prefix.to_lowercase(), msg.cursor, limit as usize)
+        .and_then(move |reply| {
+            let reply = match reply {
+                MapperReply::Listing(listing) => {
+                    let cursor = match listing.keys.last() {
+                        Some((key, _)) if listing.more => Some(key.clone()),
+                        _ => None,
+                    };
+                    let entries = listing.keys.into_iter()
+                        .map(|(key, stat)| (key, stat.size))
+                        .collect();
+                    Frame::Listing(ListingMessage::new(entries, cursor))
                 },
-                _ => SharedBuffer::new(Arc::new(ERROR.to_vec())),
+                r => mapper_error(r),
             };
-            write_all(wx, w)
-                .map(|_| info!("Sent response back to client"))
-                .map_err(|_| "Could not sent response".to_string())
-        }))
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id)))
+}
+
+fn process_resolve(cloned_mapper: Arc<VecVecMapper>, id: RequestId, msg: ResolveMessage, replies: ReplySender) -> BoxedFuture<(), String> {
+    debug!("Resolving {:?}", msg.prefix);
+    if msg.prefix.is_empty() || !msg.prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        let m = ErrorMessage::new(ErrorCode::BadRequest, format!("Invalid hex prefix {:?}", msg.prefix));
+        return Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| ()));
+    }
+    Box::new(cloned_mapper.list(msg.prefix.to_lowercase(), None, MAX_RESOLVE_CANDIDATES)
+        .and_then(move |reply| {
+            let reply = match reply {
+                MapperReply::Listing(listing) => {
+                    let hashes = listing.keys.into_iter().map(|(key, _)| key).collect();
+                    Frame::Resolved(ResolvedMessage::new(hashes))
+                },
+                r => mapper_error(r),
+            };
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id)))
+}
+
+/// Deletes the data stored under a hash, and the copies of it made on other nodes, so that
+/// a fetch does not find a copy and serve the data again.
+///
+/// The copies are deleted on the nodes the data is copied to from here, unless the request
+/// comes from a node deleting its own copy. Nodes of the overlay may still name this node
+/// as storing the data until its announcement expires, after `PROVIDER_TTL`, but it no
+/// longer serves the data.
+fn process_delete(node: Node, id: RequestId, msg: DeleteMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    info!("Received delete message for key: {}", encode(&msg.hash));
+    let key = msg.hash.clone();
+    let copies = if msg.replica {
+        future::Either::A(future::ok(0))
+    } else {
+        future::Either::B(node.replication.delete_copies(key.clone()))
+    };
+    node.mapper.remove(msg.hash)
+        .join(copies.map_err(|_| "Could not delete the copies".to_string()))
+        .and_then(move |(reply, copies)| {
+            debug!("Got reply from mapper {:?}, deleted {} copies", reply, copies);
+            let reply = match reply {
+                MapperReply::Ok => Frame::Deleted(DeletedMessage::new(key)),
+                MapperReply::NotFound if copies > 0 => Frame::Deleted(DeletedMessage::new(key)),
+                MapperReply::NotFound => Frame::NotFound(NotFoundMessage::new(key)),
+                r => mapper_error(r),
+            };
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id))
+}
+
+/// Receives `size` bytes of body frames from `frames` into `spool`, hashing them on the way.
+fn receive_body<W: Write>(frames: FrameStream, spool: W, size: u64) -> impl Future<Item = (FrameStream, W, KitapHash), Error = String> {
+    future::loop_fn((frames, spool, KitapHasher::new(), size), |(frames, mut spool, mut hasher, remaining)| {
+        if remaining == 0 {
+            return future::Either::A(future::ok(Loop::Break((frames, spool, hasher.result()))));
+        }
+        future::Either::B(frames.into_future()
+            .map_err(|(e, _)| format!("Could not read data: {}", e))
+            .and_then(move |(frame, frames)| {
+                let data = match frame {
+                    Some((_, Frame::Body(data))) => data,
+                    Some(_) => return Err("Unexpected message in placed data".to_string()),
+                    None => return Err("Connection closed before all data was received".to_string()),
+                };
+                spool.write_all(&data)
+                    .map_err(|e| format!("Could not spool data: {}", e))?;
+                hasher.input(&data);
+                Ok(Loop::Continue((frames, spool, hasher, remaining - data.len() as u64)))
+            }))
+    })
 }
 
-fn process_place(cloned_mapper: Arc<VecVecMapper>, buf: Vec<u8>, wx: tokio::io::WriteHalf<TcpStream>, rx: tokio::io::ReadHalf<TcpStream>) -> BoxedFuture<(), String> {
-    trace!("buf: {:?}, len: {}", encode(&buf), buf.len());
-    let msg = match PlaceMessage::try_from(buf) {
-        Ok(m) => m,
-        Err(s) => return Box::new(future::err(s)),
+/// Stores data that was verified, and replies once it is copied to enough nodes.
+///
+/// The data is only copied when `copy` is set, as replicas are not copied any further.
+fn store_placed(node: &Node, key: Vec<u8>, spool: SpoolFile, copy: bool) -> BoxedFuture<Frame, String> {
+    let (dht, replication) = (node.dht.clone(), node.replication.clone());
+    // copies are read from the store once the data is in it
+    let read: ReadRange = {
+        let (mapper, key) = (node.mapper.clone(), Arc::new(key.clone()));
+        Arc::new(move |offset, len| Box::new(mapper.get_range(key.clone(), offset, len)
+            .and_then(|reply| match reply {
+                MapperReply::Range(data) => Ok(data),
+                MapperReply::NotFound => Err("Copied data is no longer stored".to_string()),
+                MapperReply::Error(e) => Err(e),
+                r => Err(format!("Unexpected reply from mapper {:?}", r)),
+            })))
     };
+    let len = spool.len();
+    Box::new(node.mapper.set_spooled(key.clone(), spool)
+        .and_then(move |reply| {
+            debug!("Got reply from mapper {:?}", reply);
+            match reply {
+                MapperReply::Ok => tokio::spawn(dht.announce(key.clone())),
+                r => return future::Either::A(future::ok(mapper_error(r))),
+            };
+            if !copy {
+                return future::Either::A(future::ok(Frame::PlaceOk(PlaceOkMessage::new(key))));
+            }
+            future::Either::B(replication.replicate(key.clone(), len, read)
+                .then(move |met| Ok(match met {
+                    Ok(true) => Frame::PlaceOk(PlaceOkMessage::new(key)),
+                    _ => {
+                        info!("Could not copy {} to enough nodes", encode(&key));
+                        let m = ErrorMessage::new(ErrorCode::QuorumNotMet, "Data was stored, but not copied to enough nodes");
+                        Frame::Error(m)
+                    },
+                })))
+        }))
+}
+
+/// Receives the data of a Place request, and stores it in the background once it is
+/// verified, so that the next requests can be read meanwhile.
+///
+/// Replicate requests are received the same way, but are not copied to other nodes.
+fn process_place(node: Node, id: RequestId, msg: PlaceMessage, copy: bool, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
     info!("Received place message for key: {}", encode(&msg.hash));
     trace!("datasize {}", msg.datasize);
-    let mut data = Vec::with_capacity(msg.datasize);
-    data.resize(msg.datasize, 0);
-    Box::new(
-        read_exact(rx, data)
-        .map_err(|_| "Could not read data".to_string())
-        .and_then(move |(_, data)| cloned_mapper.set(msg.hash, data))
-        .and_then(|reply| {
+    let spool = match node.mapper.spool() {
+        Ok(spool) => spool,
+        Err(e) => {
+            // the data cannot be skipped, so the connection is closed after replying
+            let m = ErrorMessage::new(ErrorCode::Internal, format!("Could not spool data: {}", e));
+            return Box::new(send_reply(replies, id, Frame::Error(m))
+                .and_then(|_| Err("Could not spool data".to_string())));
+        },
+    };
+    Box::new(receive_body(frames, spool, msg.datasize)
+        .map(move |(frames, spool, hash)| {
+            if hash.as_slice() != msg.hash.as_slice() {
+                info!("Rejecting data that hashes to {}", encode(hash));
+                let m = ErrorMessage::new(ErrorCode::HashMismatch, format!("Data hashes to {}", encode(hash)));
+                tokio::spawn(send_reply(replies, id, Frame::Error(m))
+                    .map(|_| ())
+                    .map_err(|e| info!("{}", e)));
+                return frames;
+            }
+            tokio::spawn(store_placed(&node, msg.hash, spool, copy)
+                .and_then(move |reply| send_reply(replies, id, reply))
+                .map(move |_| info!("Served request {}", id))
+                .map_err(|e| info!("{}", e)));
+            frames
+        }))
+}
+
+/// Sends back the requested range of the data stored under a hash.
+///
+/// If the data is not stored here, the whole of it is fetched from the other nodes and
+/// checked against the hash before the range is taken from it, since a range alone cannot
+/// be checked.
+fn process_fetch_range(node: Node, id: RequestId, msg: FetchRangeMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    info!("Received fetch message for {} bytes at {} of key: {}", msg.length, msg.offset, encode(&msg.hash));
+    let (offset, length) = (msg.offset, msg.length);
+    let key = Arc::new(msg.hash);
+    node.mapper.get_range(key.clone(), offset, length)
+        .and_then(move |reply| {
+            let reply: BoxedFuture<Frame, String> = match reply {
+                MapperReply::Range(data) => Box::new(future::ok(Frame::Data(DataMessage::new(key.to_vec(), Arc::new(data))))),
+                MapperReply::NotFound => Box::new(fetch_elsewhere(&node, key.to_vec())
+                    .map(move |found| match found {
+                        Frame::Data(msg) => {
+                            let size = msg.data.len() as u64;
+                            let start = cmp::min(offset, size) as usize;
+                            let end = cmp::min(offset.saturating_add(length), size) as usize;
+                            Frame::Data(DataMessage::new(msg.hash, Arc::new(msg.data[start..end].to_vec())))
+                        },
+                        reply => reply,
+                    })),
+                r => Box::new(future::ok(mapper_error(r))),
+            };
+            reply.and_then(move |reply| send_reply(replies, id, reply))
+        })
+        .map(move |_| info!("Served request {}", id))
+}
+
+/// Begins or resumes an upload, unless its data is already stored.
+fn process_upload(cloned_mapper: Arc<VecVecMapper>, uploads: Uploads, id: RequestId, msg: UploadMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    info!("Received upload message for key: {}", encode(&msg.hash));
+    trace!("size {}", msg.size);
+    cloned_mapper.stat(Arc::new(msg.hash.clone()))
+        .and_then(move |reply| {
             debug!("Got reply from mapper {:?}", reply);
-            let w = match reply {
-                MapperReply::Ok => SharedBuffer::new(Arc::new(String::from("ITSOK").into_bytes())),
-                _ => SharedBuffer::new(Arc::new(String::from("ERROR").into_bytes())),
+            let reply = match reply {
+                MapperReply::Stat(_) => Frame::PlaceOk(PlaceOkMessage::new(msg.hash)),
+                MapperReply::NotFound => match uploads.begin(&msg.hash, msg.size) {
+                    Ok(offset) => Frame::UploadStatus(UploadStatusMessage::new(msg.hash, offset)),
+                    Err(m) => Frame::Error(m),
+                },
+                r => mapper_error(r),
             };
-            write_all(wx, w)
-                .map(|_| info!("Sent response back to client"))
-                .map_err(|_| "Could not sent response".to_string())
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id))
+}
+
+/// Receives the data of an Append request into its upload.
+///
+/// Data that cannot be added to the upload is still read, so that the next requests can be.
+fn process_append(uploads: Uploads, id: RequestId, msg: AppendMessage, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    info!("Received append message for key: {}", encode(&msg.hash));
+    trace!("offset {} datasize {}", msg.offset, msg.datasize);
+    let file = match uploads.append(&msg.hash, msg.offset, msg.datasize) {
+        Ok(file) => file,
+        Err(m) => {
+            info!("Refusing data: {}", m.reason);
+            return Box::new(receive_body(frames, io::sink(), msg.datasize)
+                .and_then(move |(frames, _, _)| send_reply(replies, id, Frame::Error(m)).map(|_| frames)));
+        },
+    };
+    Box::new(receive_body(frames, file, msg.datasize)
+        .and_then(move |(frames, file, _)| {
+            let m = UploadStatusMessage::new(msg.hash, file.received());
+            // the upload can only be committed once the file is back in it
+            drop(file);
+            send_reply(replies, id, Frame::UploadStatus(m))
+                .map(move |_| {
+                    info!("Served request {}", id);
+                    frames
+                })
         }))
 }
 
+/// Stores the data of a complete upload, once it is verified.
+fn process_commit(node: Node, id: RequestId, msg: CommitMessage, replies: ReplySender) -> BoxedFuture<(), String> {
+    info!("Received commit message for key: {}", encode(&msg.hash));
+    let spool = match node.uploads.commit(&msg.hash) {
+        Ok(spool) => spool,
+        Err(m) => return Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| ())),
+    };
+    let hash = match spool.reader().and_then(hash_reader) {
+        Ok(hash) => hash,
+        Err(e) => {
+            let m = ErrorMessage::new(ErrorCode::Internal, format!("Could not read uploaded data: {}", e));
+            return Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| ()));
+        },
+    };
+    if hash.as_slice() != msg.hash.as_slice() {
+        info!("Rejecting data that hashes to {}", encode(hash));
+        let m = ErrorMessage::new(ErrorCode::HashMismatch, format!("Data hashes to {}", encode(hash)));
+        return Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| ()));
+    }
+    Box::new(store_placed(&node, msg.hash, spool, true)
+        .and_then(move |reply| send_reply(replies, id, reply))
+        .map(move |_| info!("Served request {}", id)))
+}
+
+/// Refuses the requests of nodes whose id is not the hash of their address.
+fn check_sender(sender: &Contact) -> Result<(), Frame> {
+    if sender.is_genuine() {
+        return Ok(());
+    }
+    info!("Node {} does not go by the id of its address", sender.addr);
+    let reason = format!("The id of node {} is not the hash of its address", sender.addr);
+    Err(Frame::Error(ErrorMessage::new(ErrorCode::BadRequest, reason)))
+}
+
+/// Answers a node looking for the nodes closest to a target.
+fn process_find_node(dht: Dht, id: RequestId, msg: FindNodeMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    debug!("Received find node message for target: {}", encode(&msg.target));
+    if let Some(sender) = msg.sender {
+        if let Err(refusal) = check_sender(&sender) {
+            return future::Either::A(send_reply(replies, id, refusal).map(|_| ()));
+        }
+        dht.observe(sender);
+    }
+    future::Either::B(send_reply(replies, id, Frame::Nodes(dht.neighbours(&msg.target)))
+        .map(move |_| info!("Served request {}", id)))
+}
+
+/// Answers a node looking for the data stored under a hash, with the data if it is stored
+/// here, or with the nodes closer to the hash otherwise.
+fn process_find_value(cloned_mapper: Arc<VecVecMapper>, dht: Dht, id: RequestId, msg: FindValueMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    debug!("Received find value message for key: {}", encode(&msg.hash));
+    if let Some(sender) = msg.sender {
+        if let Err(refusal) = check_sender(&sender) {
+            return future::Either::A(send_reply(replies, id, refusal).map(|_| ()));
+        }
+        dht.observe(sender);
+    }
+    let key = Arc::new(msg.hash);
+    future::Either::B(cloned_mapper.get_range(key.clone(), msg.offset, msg.length)
+        .and_then(move |reply| {
+            let reply = match reply {
+                MapperReply::Range(data) => Frame::Data(DataMessage::new(key.to_vec(), Arc::new(data))),
+                MapperReply::NotFound => Frame::Nodes(dht.neighbours(&key)),
+                r => mapper_error(r),
+            };
+            send_reply(replies, id, reply)
+        })
+        .map(move |_| info!("Served request {}", id)))
+}
+
+/// Records that the node sending the request stores the data of a hash.
+fn process_store(dht: Dht, id: RequestId, msg: StoreMessage, replies: ReplySender) -> impl Future<Item = (), Error = String> {
+    debug!("Node {} stores key: {}", msg.sender.addr, encode(&msg.hash));
+    let reply = if msg.hash.len() != HASH_SIZE {
+        let reason = format!("Hash has length {}, expected {}", msg.hash.len(), HASH_SIZE);
+        Frame::Error(ErrorMessage::new(ErrorCode::BadRequest, reason))
+    } else if let Err(refusal) = check_sender(&msg.sender) {
+        refusal
+    } else {
+        dht.observe(msg.sender.clone());
+        dht.add_provider(msg.hash.clone(), msg.sender);
+        Frame::PlaceOk(PlaceOkMessage::new(msg.hash))
+    };
+    send_reply(replies, id, reply)
+        .map(move |_| info!("Served request {}", id))
+}
+
+/// Serves a single request, returning the stream once the next request can be read from it.
+fn dispatch(node: Node, id: RequestId, frame: Frame, frames: FrameStream, replies: ReplySender) -> BoxedFuture<FrameStream, String> {
+    debug!("Received request {}: {:?}", id, frame);
+    match frame {
+        Frame::Place(msg) => process_place(node, id, msg, true, frames, replies),
+        Frame::Replicate(msg) => {
+            let msg = PlaceMessage::new(msg.hash, msg.datasize);
+            process_place(node, id, msg, false, frames, replies)
+        },
+        Frame::Append(msg) => process_append(node.uploads, id, msg, frames, replies),
+        Frame::Fetch(msg) => {
+            tokio::spawn(process_fetch(node, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::FetchRange(msg) => {
+            tokio::spawn(process_fetch_range(node, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::FindNode(msg) => {
+            tokio::spawn(process_find_node(node.dht, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::FindValue(msg) => {
+            tokio::spawn(process_find_value(node.mapper, node.dht, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Store(msg) => {
+            tokio::spawn(process_store(node.dht, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Upload(msg) => {
+            tokio::spawn(process_upload(node.mapper, node.uploads, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Commit(msg) => {
+            tokio::spawn(process_commit(node, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Stat(msg) => {
+            tokio::spawn(process_stat(node.mapper, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::List(msg) => {
+            tokio::spawn(process_list(node.mapper, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Resolve(msg) => {
+            tokio::spawn(process_resolve(node.mapper, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        Frame::Delete(msg) => {
+            tokio::spawn(process_delete(node, id, msg, replies).map_err(|e| info!("{}", e)));
+            Box::new(future::ok(frames))
+        },
+        frame => {
+            let e = match frame {
+                Frame::Invalid(e) => e,
+                frame => format!("Unexpected message {:?}", frame),
+            };
+            info!("Malformed request: {}", e);
+            let m = ErrorMessage::new(ErrorCode::BadRequest, e);
+            Box::new(send_reply(replies, id, Frame::Error(m)).map(|_| frames))
+        },
+    }
+}
+
 fn create_parser() -> App<'static, 'static> {
     create_base_app("kitapd")
+        .arg(
+            Arg::with_name("store")
+                .long("--store")
+                .help("The storage backend. `disk`, `log` and `chunked` persist blobs in the data directory, and `chunked` stores the content they share once")
+                .takes_value(true)
+                .possible_values(&["memory", "disk", "log", "chunked"])
+                .default_value("memory"),
+        )
+        .arg(
+            Arg::with_name("datadir")
+                .long("--datadir")
+                .help("Directory in which blobs are persisted")
+                .takes_value(true)
+                .required_ifs(&[("store", "disk"), ("store", "log"), ("store", "chunked")]),
+        )
+        .arg(
+            Arg::with_name("advertise")
+                .long("--advertise")
+                .help("The address other nodes reach this one at, as HOST:PORT. Defaults to the first address listened on")
+                .takes_value(true)
+                .value_name("HOST:PORT"),
+        )
+        .arg(
+            Arg::with_name("bootstrap")
+                .long("--bootstrap")
+                .help("A node of the overlay to join it through, as HOST:PORT. May be given multiple times")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("replicas")
+                .long("--replicas")
+                .help("How many nodes keep a copy of each placed blob, counting this one")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("write_quorum")
+                .long("--write-quorum")
+                .help("How many copies of a placed blob must be stored before the place is acknowledged. Defaults to a majority of the replicas")
+                .takes_value(true)
+                .value_name("W"),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .long("--peer")
+                .help("A node to copy placed blobs to, as HOST:PORT. May be given multiple times, and must be given at least --replicas - 1 times if it is given at all. Without peers, blobs are copied to the nodes of the overlay closest to them")
+                .takes_value(true)
+                .value_name("HOST:PORT")
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("tokens")
+                .long("--tokens")
+                .help("Only serve the clients that authenticate with one of the tokens in this file, and only the requests their token allows. Each line holds the name of a token, its secret and its comma separated permissions among read, write and admin, as in `ci s3cret read,write`")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("--token")
+                .help("The token to authenticate with on the other nodes, as NAME:SECRET")
+                .takes_value(true)
+                .value_name("NAME:SECRET")
+                .env("KITAPD_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("--tls-cert")
+                .help("Serve clients over TLS, with the certificate chain in this PEM file. It is also presented to the nodes that ask for a client certificate")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls_key"),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("--tls-key")
+                .help("The private key of the certificate given with --tls-cert, in a PEM file")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls_cert"),
+        )
+        .arg(
+            Arg::with_name("tls_client_ca")
+                .long("--tls-client-ca")
+                .help("Only serve the clients that present a certificate issued by one of the authorities in this PEM file")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls_cert"),
+        )
+        .arg(
+            Arg::with_name("tls_ca")
+                .long("--tls-ca")
+                .help("Reach the other nodes over TLS, trusting the certificates issued by the authorities in this PEM file")
+                .takes_value(true)
+                .value_name("FILE")
+                .requires("tls_name"),
+        )
+        .arg(
+            Arg::with_name("tls_name")
+                .long("--tls-name")
+                .help("The host name the certificates of the other nodes must be issued for")
+                .takes_value(true)
+                .value_name("NAME")
+                .requires("tls_ca"),
+        )
+}
+
+/// Announces every blob stored here to the overlay, a page of them at a time.
+fn announce_stored(cloned_mapper: Arc<VecVecMapper>, dht: Dht) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn(None, move |cursor| {
+        let dht = dht.clone();
+        cloned_mapper.list(String::new(), cursor, MAX_LIST_PAGE as usize)
+            .and_then(move |reply| {
+                let listing = match reply {
+                    MapperReply::Listing(listing) => listing,
+                    _ => return future::Either::A(future::err("Unexpected reply from mapper".to_string())),
+                };
+                let next = match listing.keys.last() {
+                    Some((key, _)) if listing.more => Some(key.clone()),
+                    _ => None,
+                };
+                future::Either::B(stream::iter_ok(listing.keys)
+                    .for_each(move |(key, _)| dht.announce(key).then(|_| Ok(())))
+                    .map(move |_| match next {
+                        Some(cursor) => Loop::Continue(Some(cursor)),
+                        None => Loop::Break(()),
+                    }))
+            })
+    })
+    .map_err(|e| info!("Could not announce the stored blobs: {}", e))
+}
+
+/// Opens the storage backend selected in the command line.
+fn open_store(matches: &ArgMatches) -> Result<Box<dyn BlobStore<Vec<u8>, Vec<u8>>>, String> {
+    let datadir = matches.value_of("datadir");
+    let store: Box<dyn BlobStore<Vec<u8>, Vec<u8>>> = match matches.value_of("store") {
+        Some("disk") => Box::new(DiskStore::open(datadir.unwrap()).map_err(|e| e.to_string())?),
+        Some("log") => Box::new(LogStore::open(datadir.unwrap()).map_err(|e| e.to_string())?),
+        Some("chunked") => Box::new(ChunkStore::open(datadir.unwrap()).map_err(|e| e.to_string())?),
+        _ => Box::new(MemoryStore::new()),
+    };
+    Ok(store)
+}
+
+/// Opens the registry of uploads. Uploads are spooled in the data directory when the store
+/// persists blobs, so that they can be resumed after a restart.
+fn open_uploads(matches: &ArgMatches, spool_dir: &Path) -> Result<Uploads, String> {
+    match (matches.value_of("store"), matches.value_of("datadir")) {
+        (Some("disk"), Some(dir)) | (Some("log"), Some(dir)) | (Some("chunked"), Some(dir)) => {
+            Uploads::open(Path::new(dir).join(UPLOAD_DIR)).map_err(|e| e.to_string())
+        },
+        _ => Ok(Uploads::new(spool_dir)),
+    }
+}
+
+/// Exchanges protocol versions with a client.
+///
+/// Clients that speak another version of the protocol are sent an error they can decode
+/// before the connection is dropped.
+fn server_handshake<S: AsyncRead + AsyncWrite>(sock: S) -> impl Future<Item = S, Error = String> {
+    read_exact(sock, [0; HANDSHAKE_LEN])
+        .map_err(|e| format!("something bad happened during the handshake: {}", e))
+        .and_then(|(sock, buf)| {
+            let reply = match Handshake::parse(buf) {
+                Handshake::Version(PROTOCOL_VERSION) => None,
+                Handshake::Version(v) => {
+                    info!("Client speaks protocol version {}", v);
+                    Some(Handshake::to_bytes(PROTOCOL_VERSION).to_vec())
+                },
+                Handshake::Legacy => {
+                    info!("Client speaks protocol version 1");
+                    let m = ErrorMessage::new(ErrorCode::UnsupportedVersion, format!(
+                        "kitapd speaks version {} of the protocol, please upgrade your client",
+                        PROTOCOL_VERSION));
+                    Some(m.to_legacy_bytes())
+                },
+            };
+            match reply {
+                None => future::Either::A(write_all(sock, Handshake::to_bytes(PROTOCOL_VERSION))
+                    .map(|(sock, _)| sock)
+                    .map_err(|e| format!("something bad happened during the handshake: {}", e))),
+                Some(bytes) => future::Either::B(write_all(sock, bytes)
+                    .then(|_| Err("Unsupported protocol version".to_string()))),
+            }
+        })
+}
+
+/// Sets up TLS over `sock` with `acceptor`, unless clients are served over plain TCP.
+fn secure(sock: TcpStream, acceptor: Option<&TlsAcceptor>) -> BoxedFuture<Box<dyn Transport>, String> {
+    match acceptor {
+        Some(acceptor) => Box::new(acceptor.accept(sock)
+            .map(|sock| Box::new(sock) as Box<dyn Transport>)
+            .map_err(|e| format!("TLS handshake failed: {}", e))),
+        None => Box::new(future::ok(Box::new(sock) as Box<dyn Transport>)),
+    }
+}
+
+/// Serves the requests sent over `sock` until the client closes it.
+///
+/// Requests are read one after the other, but are served concurrently, so their replies
+/// are written as soon as they are ready rather than in the order of the requests.
+fn handle_connection(sock: TcpStream, acceptor: Option<&TlsAcceptor>, node: Node) -> impl Future<Item = (), Error = ()> {
+    secure(sock, acceptor)
+        .and_then(server_handshake)
+        .and_then(|sock| {
+            let (sink, frames) = Framed::new(sock, KitapCodec::with_max_frame_len(MAX_FRAME_LEN)).split();
+            let (replies, queued) = mpsc::channel(REPLY_QUEUE_LEN);
+            // the connection is closed once every reply is written, and no request is left
+            tokio::spawn(sink.send_all(queued.map_err(|_| io::Error::other("reply queue failed")))
+                .map(|_| debug!("Connection closed"))
+                .map_err(|e| info!("Could not send response: {}", e)));
+            let session = Session::new(&node);
+            future::loop_fn((frames, session), move |(frames, mut session)| {
+                let node = node.clone();
+                let replies = replies.clone();
+                frames.into_future()
+                    .map_err(|(e, _)| format!("something bad happened when reading a request: {}", e))
+                    .and_then(move |(frame, frames)| match frame {
+                        Some((id, frame)) => future::Either::A(serve(node, &mut session, id, frame, frames, replies)
+                            .map(move |frames| Loop::Continue((frames, session)))),
+                        None => future::Either::B(future::ok(Loop::Break(()))),
+                    })
+            })
+        })
+        .map_err(|e| info!("{}", e))
+        .map(|_| info!("Client disconnected"))
+}
+
+/// Serves the clients that connect to `listener`, each over a connection of its own.
+fn accept(listener: TcpListener, acceptor: Option<TlsAcceptor>, node: Node) -> impl Future<Item = (), Error = ()> {
+    // Pull out a stream of sockets for incoming connections
+    listener
+        .incoming()
+        .map_err(|e| debug!("accept failed = {:?}", e))
+        .for_each(move |sock| {
+            info!("Connected with {}", sock.peer_addr().unwrap());
+            tokio::spawn(handle_connection(sock, acceptor.as_ref(), node.clone()));
+            Ok(())
+        })
 }
 
 fn main() {
@@ -91,64 +989,398 @@ fn main() {
 
     info!("Starting up kitapd!");
 
-    let mut mapper = Mapper::new();
-    // Bind the server's socket.
-    let addr = "127.0.0.1:12345".parse().unwrap();
-    let listener = TcpListener::bind(&addr).expect("unable to bind TCP listener");
+    let store = open_store(&matches).expect("unable to open store");
+    info!("Using {} store", matches.value_of("store").unwrap());
+    let mut mapper = Mapper::with_store(store);
+    let uploads = open_uploads(&matches, mapper.spool_dir()).expect("unable to open uploads");
+
+    // Bind the server's sockets.
+    let port = matches.value_of("port").unwrap();
+    let mut listeners = Vec::new();
+    for host in matches.values_of("host").unwrap() {
+        for addr in resolve(host, port).expect("unable to resolve listen address") {
+            let listener = bind(&addr).expect("unable to bind TCP listener");
+            info!("Listening on {}", addr);
+            listeners.push(listener);
+        }
+    }
+
+    let advertised = match matches.value_of("advertise") {
+        Some(addr) => resolve_addr(addr).expect("unable to resolve advertised address"),
+        None => listeners[0].local_addr().expect("unable to get listen address"),
+    };
+    if advertised.ip().is_unspecified() {
+        warn!("Other nodes cannot reach this one at {}, use --advertise", advertised);
+    }
+    let acceptor = matches.value_of("tls_cert").map(|cert| {
+        tls::acceptor(cert, matches.value_of("tls_key").unwrap(), matches.value_of("tls_client_ca"))
+            .expect("invalid TLS settings")
+    });
+    let mut connector = match matches.value_of("tls_ca") {
+        Some(ca) => {
+            let identity = matches.value_of("tls_cert").map(|cert| (cert, matches.value_of("tls_key").unwrap()));
+            Connector::tls(ca, identity, matches.value_of("tls_name").unwrap()).expect("invalid TLS settings")
+        },
+        None => Connector::Plain,
+    };
+    if let Some(token) = matches.value_of("token") {
+        connector = connector.with_credentials(Credentials::parse(token).expect("invalid token"));
+    }
+    let tokens = matches.value_of("tokens").map(|path| Arc::new(Tokens::load(path).expect("unable to read tokens")));
+    if let Some(tokens) = &tokens {
+        info!("Serving the clients that authenticate with one of {} tokens", tokens.len());
+    }
+    if acceptor.is_some() {
+        info!("Serving clients over TLS");
+    }
 
-    tokio::run(future::lazy(|| {
+    let dht = Dht::new(Contact::from_addr(advertised), connector);
+    info!("Joining the overlay as node {} at {}", encode(&dht.contact().id), advertised);
+    let bootstrap: Vec<SocketAddr> = matches.values_of("bootstrap")
+        .map(|addrs| addrs.map(|addr| resolve_addr(addr).expect("unable to resolve bootstrap address")).collect())
+        .unwrap_or_default();
+
+    let replicas: usize = matches.value_of("replicas").unwrap().parse().expect("invalid number of replicas");
+    let quorum: usize = match matches.value_of("write_quorum") {
+        Some(quorum) => quorum.parse().expect("invalid write quorum"),
+        None => replicas / 2 + 1,
+    };
+    let peers: Vec<SocketAddr> = matches.values_of("peer")
+        .map(|addrs| addrs.map(|addr| resolve_addr(addr).expect("unable to resolve peer address")).collect())
+        .unwrap_or_default();
+    let replication = Replication::new(replicas, quorum, peers, dht.clone()).expect("invalid replication settings");
+    info!("Keeping {} copies of each blob, placing them once {} are stored", replicas, quorum);
+
+    tokio::run(future::lazy(move || {
 
         let hashmap_thread = mapper.receive().unwrap();
         let shared_mapper = Arc::new(mapper);
-        tokio::spawn(hashmap_thread);
+        // the store blocks on its files, so it is kept off the threads serving connections
+        thread::Builder::new()
+            .name("mapper".to_string())
+            .spawn(move || hashmap_thread.wait())
+            .expect("unable to spawn the mapper thread");
         debug!("Mapper spawned");
 
-        // Pull out a stream of sockets for incoming connections
-        let server = listener
-            .incoming()
-            .map_err(|e| debug!("accept failed = {:?}", e))
-            .for_each(move |sock| {
-                info!("Connected with {}", sock.peer_addr().unwrap());
-                let cloned_mapper = shared_mapper.clone();
-                let (rx, wx) = sock.split();
-                let mut buf = Vec::with_capacity(MSG_HEADER_LEN);
-                buf.resize(MSG_HEADER_LEN, 0);
-                let task = read_exact(rx, buf)
-                    .map_err(|_| "something bad happened when reading the header".to_string())
-                    .and_then(move |(rx, b)| {
-                        let mut cursor = Cursor::new(b);
-                        // XXX should these unwraps be handled? The only way they can fail is if
-                        // cursor has less bytes than needed, but then this closure would not
-                        // execute, as the previous read_exact would not have returned a value
-                        let req_type = cursor.read_u16::<LittleEndian>().unwrap().into();
-                        let length = cursor.read_u32::<LittleEndian>().unwrap() as usize;
-                        let mut buf = Vec::with_capacity(length);
-                        buf.resize(length, 0);
-                        debug!("req_type: {:?}, length: {}", req_type, length);
-                        read_exact(rx, buf)
-                            .map(move |(rx, buf)| (rx, buf, req_type))
-                            .map_err(|_| "something bad happened when reading the body".to_string())
-                    })
-                    .and_then(|(rx, b, req_type)| {
-                        let r = match req_type {
-                            MessageType::Place => {
-                                process_place(cloned_mapper, b, wx, rx)
-                            },
-                            MessageType::Fetch => {
-                                process_fetch(cloned_mapper, b, wx)
-                            }
-                            _ => Box::new(future::err("Unkown message type".to_string()))
-                        };
-                        r
-                    })
-                    .map_err(|e| info!("{}", e))
-                    .map(|_| info!("request served"));
-                tokio::spawn(task);
+        // blobs are announced once the overlay is joined, and every so often after that,
+        // when the announcements of the other nodes that were not renewed are forgotten
+        let republished = shared_mapper.clone();
+        let announcer = dht.clone();
+        tokio::spawn(dht.bootstrap(bootstrap)
+            .and_then(move |_| Interval::new(Instant::now(), REPUBLISH_INTERVAL)
+                .map_err(|e| info!("Republishing timer failed: {}", e))
+                .for_each(move |_| {
+                    announcer.expire();
+                    announce_stored(republished.clone(), announcer.clone()).then(|_| Ok(()))
+                })));
+
+        let swept = uploads.clone();
+        tokio::spawn(Interval::new(Instant::now() + UPLOAD_SWEEP_INTERVAL, UPLOAD_SWEEP_INTERVAL)
+            .map_err(|e| info!("Upload sweeping timer failed: {}", e))
+            .for_each(move |_| {
+                swept.sweep();
                 Ok(())
-            });
+            }));
 
-        tokio::spawn(server);
+        let node = Node {
+            mapper: shared_mapper.clone(),
+            uploads,
+            dht: dht.clone(),
+            replication,
+            tokens,
+        };
+        for listener in listeners {
+            tokio::spawn(accept(listener, acceptor.clone(), node.clone()));
+        }
         debug!("Server spawned");
         future::ok(())
     }));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    use std::fs;
+    use std::io::{Cursor, Read};
+
+    use kitap::messages::MessageType;
+
+    use kitap::client::{Client, ClientError};
+
+    /// Binds a listener to an ephemeral port of the loopback interface.
+    fn listener() -> TcpListener {
+        bind(&"127.0.0.1:0".parse().unwrap()).unwrap()
+    }
+
+    /// Serves the clients of `listener` on `runtime` as kitapd does, keeping blobs in memory
+    /// and copying them to `replicas - 1` of `peers`, all of which must store them.
+    fn start(runtime: &mut Runtime, listener: TcpListener, replicas: usize, peers: Vec<SocketAddr>, tokens: Option<Tokens>) -> Node {
+        let addr = listener.local_addr().unwrap();
+        let mut mapper: VecVecMapper = Mapper::new();
+        let receive = mapper.receive().unwrap();
+        thread::spawn(move || receive.wait());
+        let mapper = Arc::new(mapper);
+        let uploads = Uploads::new(mapper.spool_dir());
+        let dht = Dht::new(Contact::from_addr(addr), Connector::Plain);
+        let replication = Replication::new(replicas, replicas, peers, dht.clone()).unwrap();
+        let node = Node {
+            mapper,
+            uploads,
+            dht,
+            replication,
+            tokens: tokens.map(Arc::new),
+        };
+        let serving = node.clone();
+        runtime.block_on(future::lazy(move || {
+            tokio::spawn(accept(listener, None, serving));
+            Ok::<_, ()>(())
+        })).unwrap();
+        node
+    }
+
+    /// Connects a client to `node`.
+    fn connect(runtime: &mut Runtime, node: &Node) -> Client {
+        runtime.block_on(Client::connect(&node.dht.contact().addr)).unwrap()
+    }
+
+    /// The tokens in a file holding `text`.
+    fn tokens(name: &str, text: &str) -> Tokens {
+        let path = std::env::temp_dir().join(format!("kitapd-test-{}-{}", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let tokens = Tokens::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        tokens
+    }
+
+    fn hash_of(data: &[u8]) -> Vec<u8> {
+        let mut hasher = KitapHasher::new();
+        hasher.input(data);
+        hasher.result().to_vec()
+    }
+
+    /// The code of the error a request failed with, if it failed on the server.
+    fn refusal<T: std::fmt::Debug>(res: Result<T, ClientError>) -> ErrorCode {
+        match res {
+            Err(ClientError::Server { code, .. }) => code,
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn refuses_places_whose_data_does_not_match_their_hash() {
+        let mut runtime = Runtime::new().unwrap();
+        let node = start(&mut runtime, listener(), 1, Vec::new(), None);
+        let client = connect(&mut runtime, &node);
+        let data = b"placed under the hash of other data".to_vec();
+        let hash = hash_of(b"other data");
+
+        let place = client.place_reader(&hash, data.len() as u64, Cursor::new(data.clone()));
+        assert_eq!(refusal(runtime.block_on(place)), ErrorCode::HashMismatch);
+        assert!(!runtime.block_on(client.exists(&hash)).unwrap());
+        assert!(!runtime.block_on(client.exists(&hash_of(&data))).unwrap());
+
+        // the connection goes on serving requests
+        let placed = runtime.block_on(client.place(data.clone())).unwrap();
+        assert_eq!(runtime.block_on(client.fetch(&placed)).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn refuses_uploads_whose_data_does_not_match_their_hash() {
+        let mut runtime = Runtime::new().unwrap();
+        let node = start(&mut runtime, listener(), 1, Vec::new(), None);
+        let client = connect(&mut runtime, &node);
+        let data = b"uploaded in two parts, the second of them corrupted".to_vec();
+        let hash = hash_of(&data);
+        let (first, second) = data.split_at(20);
+        let mut corrupted = second.to_vec();
+        corrupted[0] ^= 1;
+
+        assert_eq!(runtime.block_on(client.begin_upload(&hash, data.len() as u64)).unwrap(), Some(0));
+        let append = client.append(&hash, 0, first.len() as u64, Cursor::new(first.to_vec()));
+        assert_eq!(runtime.block_on(append).unwrap(), first.len() as u64);
+        let append = client.append(&hash, first.len() as u64, corrupted.len() as u64, Cursor::new(corrupted));
+        assert_eq!(runtime.block_on(append).unwrap(), data.len() as u64);
+        assert_eq!(refusal(runtime.block_on(client.commit(&hash))), ErrorCode::HashMismatch);
+        assert!(!runtime.block_on(client.exists(&hash)).unwrap());
+
+        // the upload was dropped, and starts over
+        assert_eq!(runtime.block_on(client.begin_upload(&hash, data.len() as u64)).unwrap(), Some(0));
+        let append = client.append(&hash, 0, data.len() as u64, Cursor::new(data.clone()));
+        assert_eq!(runtime.block_on(append).unwrap(), data.len() as u64);
+        runtime.block_on(client.commit(&hash)).unwrap();
+        assert_eq!(runtime.block_on(client.fetch(&hash)).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn tells_version_1_clients_to_upgrade() {
+        let mut runtime = Runtime::new().unwrap();
+        let node = start(&mut runtime, listener(), 1, Vec::new(), None);
+        let mut sock = std::net::TcpStream::connect(node.dht.contact().addr).unwrap();
+        // the header of a version 1 place, which had no handshake
+        let mut header = Vec::new();
+        header.extend_from_slice(&u16::from(MessageType::Place).to_le_bytes());
+        header.extend_from_slice(&(HASH_SIZE as u32 + 8).to_le_bytes());
+        sock.write_all(&header).unwrap();
+
+        let mut reply = Vec::new();
+        sock.read_to_end(&mut reply).unwrap();
+        assert_eq!(&reply[..2], &u16::from(MessageType::Error).to_le_bytes());
+        assert_eq!(&reply[2..6], &(reply.len() as u32 - 6).to_le_bytes());
+        let msg = ErrorMessage::try_from(reply[6..].to_vec()).unwrap();
+        assert_eq!(msg.code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn refuses_to_fetch_data_too_large_for_a_message_at_once() {
+        let key = vec![0; HASH_SIZE];
+        assert!(too_large(&key, MAX_DATA_LEN).is_none());
+        match too_large(&key, MAX_DATA_LEN + 1) {
+            Some(Frame::Error(msg)) => assert_eq!(msg.code, ErrorCode::TooLarge),
+            refusal => panic!("unexpected {:?}", refusal),
+        }
+    }
+
+    #[test]
+    fn tells_unauthenticated_clients_from_forbidden_requests() {
+        let hash = vec![0; HASH_SIZE];
+        let fetch = Frame::Fetch(FetchMessage::new(vec![hash.clone()]));
+        let place = Frame::Place(PlaceMessage::new(hash.clone(), 0));
+        let delete = Frame::Delete(DeleteMessage::new(hash.clone()));
+        let invalid = Frame::Invalid("unreadable".to_string());
+        let authorized = |permissions, frame| {
+            let session = Session {
+                nonce: None,
+                permissions,
+            };
+            session.authorize(frame).map_err(|m| m.code)
+        };
+
+        assert_eq!(authorized(None, &fetch), Err(ErrorCode::Unauthenticated));
+        assert_eq!(authorized(None, &place), Err(ErrorCode::Unauthenticated));
+        assert_eq!(authorized(None, &delete), Err(ErrorCode::Unauthenticated));
+        // requests that cannot be served are refused as malformed rather than unauthorized
+        assert_eq!(authorized(None, &invalid), Ok(()));
+
+        assert_eq!(authorized(Some(Permissions::NONE), &fetch), Err(ErrorCode::Forbidden));
+        assert_eq!(authorized(Some(Permissions::READ), &fetch), Ok(()));
+        assert_eq!(authorized(Some(Permissions::READ), &place), Err(ErrorCode::Forbidden));
+        assert_eq!(authorized(Some(Permissions::WRITE), &fetch), Err(ErrorCode::Forbidden));
+        assert_eq!(authorized(Some(Permissions::READ | Permissions::WRITE), &place), Ok(()));
+        assert_eq!(authorized(Some(Permissions::READ | Permissions::WRITE), &delete), Err(ErrorCode::Forbidden));
+        assert_eq!(authorized(Some(Permissions::ALL), &delete), Ok(()));
+    }
+
+    #[test]
+    fn refuses_requests_the_token_does_not_allow() {
+        let mut runtime = Runtime::new().unwrap();
+        let tokens = tokens("refusals", "reader r3ad read\nwriter wr1te read,write\n");
+        let node = start(&mut runtime, listener(), 1, Vec::new(), Some(tokens));
+        let client = connect(&mut runtime, &node);
+        let data = b"refused at first".to_vec();
+        let hash = hash_of(&data);
+
+        // the data of refused places is read, so the requests that follow can be
+        let place = client.place_reader(&hash, data.len() as u64, Cursor::new(data.clone()));
+        assert_eq!(refusal(runtime.block_on(place)), ErrorCode::Unauthenticated);
+        assert_eq!(refusal(runtime.block_on(client.exists(&hash))), ErrorCode::Unauthenticated);
+
+        let wrong = Credentials::parse("reader:guess").unwrap();
+        assert_eq!(refusal(runtime.block_on(client.authenticate(&wrong))), ErrorCode::Unauthenticated);
+        let reader = Credentials::parse("reader:r3ad").unwrap();
+        assert_eq!(runtime.block_on(client.authenticate(&reader)).unwrap(), Permissions::READ);
+        let place = client.place_reader(&hash, data.len() as u64, Cursor::new(data.clone()));
+        assert_eq!(refusal(runtime.block_on(place)), ErrorCode::Forbidden);
+        assert!(!runtime.block_on(client.exists(&hash)).unwrap());
+
+        let writer = Credentials::parse("writer:wr1te").unwrap();
+        assert_eq!(runtime.block_on(client.authenticate(&writer)).unwrap(), Permissions::READ | Permissions::WRITE);
+        runtime.block_on(client.place(data)).unwrap();
+        assert!(runtime.block_on(client.exists(&hash)).unwrap());
+        assert_eq!(refusal(runtime.block_on(client.delete(&hash))), ErrorCode::Forbidden);
+    }
+
+    #[test]
+    fn finds_blobs_announced_on_the_loopback_overlay() {
+        let mut runtime = Runtime::new().unwrap();
+        let nodes: Vec<Node> = (0..6).map(|_| start(&mut runtime, listener(), 1, Vec::new(), None)).collect();
+        let seed = nodes[0].dht.contact().addr;
+        for node in &nodes[1..] {
+            runtime.block_on(node.dht.bootstrap(vec![seed])).unwrap();
+        }
+        let (holder, seeker) = (connect(&mut runtime, &nodes[5]), connect(&mut runtime, &nodes[3]));
+
+        let data = b"stored on a single node of the overlay".to_vec();
+        let hash = runtime.block_on(holder.place(data.clone())).unwrap();
+        runtime.block_on(nodes[5].dht.announce(hash.to_vec())).unwrap();
+        let holder = nodes[5].dht.contact();
+        assert!(nodes[..5].iter().any(|node| node.dht.providers(&hash).contains(holder)));
+
+        assert_eq!(runtime.block_on(nodes[3].dht.find_value(hash.to_vec(), 7, 9)).unwrap(), Some(data[7..16].to_vec()));
+        assert_eq!(runtime.block_on(seeker.fetch_range(&hash, 7, 9)).unwrap(), Some(data[7..16].to_vec()));
+        assert_eq!(runtime.block_on(seeker.fetch(&hash)).unwrap(), Some(data));
+        assert!(!runtime.block_on(seeker.exists(&hash)).unwrap());
+    }
+
+    #[test]
+    fn refuses_nodes_that_do_not_go_by_the_id_of_their_address() {
+        let mut runtime = Runtime::new().unwrap();
+        let node = start(&mut runtime, listener(), 1, Vec::new(), None);
+        let client = connect(&mut runtime, &node);
+        let hash = vec![5; HASH_SIZE];
+        let genuine = Contact::from_addr(listener().local_addr().unwrap());
+        // placed next to the hash, to hear about it
+        let forged = Contact::new(hash.clone(), genuine.addr);
+
+        assert_eq!(refusal(runtime.block_on(client.find_node(Some(forged.clone()), &hash))), ErrorCode::BadRequest);
+        assert_eq!(refusal(runtime.block_on(client.find_value(Some(forged.clone()), &hash, 0, 1))), ErrorCode::BadRequest);
+        assert_eq!(refusal(runtime.block_on(client.store(forged, &hash))), ErrorCode::BadRequest);
+        assert!(node.dht.providers(&hash).is_empty());
+        assert!(node.dht.closest(&hash, 1).is_empty());
+
+        runtime.block_on(client.store(genuine.clone(), &hash)).unwrap();
+        assert_eq!(node.dht.providers(&hash), vec![genuine.clone()]);
+        assert_eq!(runtime.block_on(client.find_node(None, &hash)).unwrap().nodes, vec![genuine]);
+    }
+
+    #[test]
+    fn deletes_the_copies_of_deleted_blobs() {
+        let mut runtime = Runtime::new().unwrap();
+        let (first, second) = (listener(), listener());
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let first = start(&mut runtime, first, 2, vec![second_addr], None);
+        let second = start(&mut runtime, second, 2, vec![first_addr], None);
+        let (first, second) = (connect(&mut runtime, &first), connect(&mut runtime, &second));
+
+        let data = b"copied to both nodes, deleted from both".to_vec();
+        let hash = runtime.block_on(first.place(data.clone())).unwrap();
+        assert_eq!(runtime.block_on(second.fetch(&hash)).unwrap(), Some(data));
+
+        assert!(runtime.block_on(first.delete(&hash)).unwrap());
+        assert_eq!(runtime.block_on(first.fetch(&hash)).unwrap(), None);
+        assert_eq!(runtime.block_on(second.fetch(&hash)).unwrap(), None);
+        assert!(!runtime.block_on(first.exists(&hash)).unwrap());
+        assert!(!runtime.block_on(second.exists(&hash)).unwrap());
+        assert!(!runtime.block_on(second.delete(&hash)).unwrap());
+    }
+
+    #[test]
+    fn deletes_copies_that_are_not_stored_here() {
+        let mut runtime = Runtime::new().unwrap();
+        let (first, second) = (listener(), listener());
+        let second_addr = second.local_addr().unwrap();
+        let first = start(&mut runtime, first, 2, vec![second_addr], None);
+        let second = start(&mut runtime, second, 1, Vec::new(), None);
+        let (first, second) = (connect(&mut runtime, &first), connect(&mut runtime, &second));
+
+        // the copy is found when the data is fetched from the first node
+        let hash = runtime.block_on(second.place(b"only stored on the peer".to_vec())).unwrap();
+        assert!(runtime.block_on(first.fetch(&hash)).unwrap().is_some());
+
+        assert!(runtime.block_on(first.delete(&hash)).unwrap());
+        assert_eq!(runtime.block_on(first.fetch(&hash)).unwrap(), None);
+        assert!(!runtime.block_on(second.exists(&hash)).unwrap());
+    }
+}
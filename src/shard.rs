@@ -0,0 +1,413 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::future;
+use futures::{stream, Future, Stream};
+
+use tokio::io::AsyncRead;
+
+use crate::client::{Client, ClientError};
+use crate::hash::{KitapHash, KitapHasher};
+use crate::store::BlobStat;
+use crate::tls::Connector;
+use crate::utils::BoxedFuture;
+
+/// How many points each server has on a ring by default.
+pub const DEFAULT_VNODES: usize = 128;
+
+/// Where the hash of `key` falls on a ring: the big endian number its first 8 bytes make.
+fn position(key: &[u8]) -> u64 {
+    key.iter().chain([0; 8].iter()).take(8).fold(0, |pos, b| (pos << 8) | u64::from(*b))
+}
+
+/// Spreads hashes over a set of servers by consistent hashing.
+///
+/// Each server is given `vnodes` points on a ring of 64 bit positions, and a hash belongs
+/// to the server of the first point at or after its own position. Adding or removing a
+/// server only moves the hashes between its points and the ones before them, about a
+/// share of `1 / servers` of all hashes. The points only depend on the addresses of the
+/// servers, so clients given the same servers, in any order, agree on where each hash is.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    servers: Vec<SocketAddr>,
+    /// Sorted by position
+    points: Vec<(u64, SocketAddr)>,
+}
+
+impl HashRing {
+    pub fn new(servers: Vec<SocketAddr>, vnodes: usize) -> Result<HashRing, String> {
+        if servers.is_empty() {
+            return Err("At least one server is needed".to_string());
+        }
+        if vnodes == 0 {
+            return Err("Each server needs at least one point on the ring".to_string());
+        }
+        let mut servers = servers;
+        servers.sort();
+        servers.dedup();
+        let mut points: Vec<(u64, SocketAddr)> = servers.iter()
+            .flat_map(|addr| (0..vnodes).map(move |i| {
+                let mut hasher = KitapHasher::new();
+                hasher.input(format!("{}-{}", addr, i));
+                (position(&hasher.result()), *addr)
+            }))
+            .collect();
+        points.sort();
+        Ok(HashRing {
+            servers,
+            points,
+        })
+    }
+
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// The server `hash` belongs to.
+    pub fn server_for(&self, hash: &[u8]) -> SocketAddr {
+        let pos = position(hash);
+        let i = self.points.partition_point(|(point, _)| *point < pos);
+        self.points.get(i).unwrap_or(&self.points[0]).1
+    }
+}
+
+/// A client of several independent kitap servers, which keeps each hash on the server a
+/// `HashRing` assigns it to.
+///
+/// A connection is made to each server the first time a request is routed to it, and is
+/// kept for the requests that follow. The requests that are not about a single hash, such
+/// as listing or resolving an abbreviated hash, are sent to every server.
+#[derive(Clone)]
+pub struct ShardedClient {
+    ring: HashRing,
+    connector: Connector,
+    clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
+}
+
+impl ShardedClient {
+    /// Routes requests with `ring`, connecting to the servers through `connector`.
+    pub fn new(ring: HashRing, connector: Connector) -> ShardedClient {
+        ShardedClient {
+            ring,
+            connector,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// A connection to the server at `addr`, made now unless there already is one.
+    fn connect(&self, addr: SocketAddr) -> BoxedFuture<Client, ClientError> {
+        if let Some(client) = self.clients.lock().unwrap().get(&addr) {
+            if !client.is_closed() {
+                return Box::new(future::ok(client.clone()));
+            }
+        }
+        let clients = self.clients.clone();
+        Box::new(self.connector.connect(&addr)
+            .map_err(move |e| match e {
+                ClientError::Io(e) => ClientError::Io(std::io::Error::new(e.kind(), format!("{}: {}", addr, e))),
+                e => e,
+            })
+            .map(move |client| {
+                clients.lock().unwrap().insert(addr, client.clone());
+                client
+            }))
+    }
+
+    /// A connection to the server `hash` belongs to.
+    pub fn client_for(&self, hash: &[u8]) -> BoxedFuture<Client, ClientError> {
+        self.connect(self.ring.server_for(hash))
+    }
+
+    /// A connection to every server.
+    fn all_clients(&self) -> impl Future<Item = Vec<Client>, Error = ClientError> {
+        let connects: Vec<_> = self.ring.servers().iter().map(|addr| self.connect(*addr)).collect();
+        future::join_all(connects)
+    }
+
+    /// Stores `data` on the server its hash belongs to, returning the hash.
+    pub fn place(&self, data: Vec<u8>) -> impl Future<Item = KitapHash, Error = ClientError> {
+        let mut hasher = KitapHasher::new();
+        hasher.input(&data);
+        let hash = hasher.result();
+        let len = data.len() as u64;
+        self.client_for(&hash)
+            .and_then(move |client| client.place_reader(&hash, len, Cursor::new(data)))
+            .map(move |_| hash)
+    }
+
+    /// Stores the `len` bytes read from `reader` under `hash`, on the server it belongs to.
+    pub fn place_reader<R>(&self, hash: &[u8], len: u64, reader: R) -> impl Future<Item = (), Error = ClientError>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let hash = hash.to_vec();
+        self.client_for(&hash)
+            .and_then(move |client| client.place_reader(&hash, len, reader))
+    }
+
+    /// Fetches the data stored under `hash`, if there is any.
+    pub fn fetch(&self, hash: &[u8]) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let hash = hash.to_vec();
+        self.client_for(&hash)
+            .and_then(move |client| client.fetch(&hash))
+    }
+
+    /// Fetches at most `len` bytes of the data stored under `hash`, starting at `offset`.
+    pub fn fetch_range(&self, hash: &[u8], offset: u64, len: u64) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let hash = hash.to_vec();
+        self.client_for(&hash)
+            .and_then(move |client| client.fetch_range(&hash, offset, len))
+    }
+
+    /// Deletes the data stored under `hash`, and the copies the server made of it on other
+    /// nodes, returning whether there was any.
+    pub fn delete(&self, hash: &[u8]) -> impl Future<Item = bool, Error = ClientError> {
+        let hash = hash.to_vec();
+        self.client_for(&hash)
+            .and_then(move |client| client.delete(&hash))
+    }
+
+    /// Describes the data stored under each of `hashes`, asking each server about the
+    /// hashes that belong to it at once.
+    ///
+    /// The stats are returned in the order of `hashes`, with `None` for the hashes under
+    /// which nothing is stored.
+    pub fn stat(&self, hashes: Vec<Vec<u8>>) -> impl Future<Item = Vec<Option<BlobStat>>, Error = ClientError> {
+        let mut groups: HashMap<SocketAddr, Vec<usize>> = HashMap::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            groups.entry(self.ring.server_for(hash)).or_default().push(i);
+        }
+        let count = hashes.len();
+        let requests: Vec<_> = groups.into_iter()
+            .map(|(addr, indices)| {
+                let group = indices.iter().map(|i| hashes[*i].clone()).collect();
+                self.connect(addr)
+                    .and_then(move |client| client.stat(group))
+                    .map(move |stats| indices.into_iter().zip(stats))
+            })
+            .collect();
+        future::join_all(requests).map(move |groups| {
+            let mut stats = vec![None; count];
+            for (i, stat) in groups.into_iter().flatten() {
+                stats[i] = stat;
+            }
+            stats
+        })
+    }
+
+    /// Lists all the hashes whose hex encoding starts with `prefix`, on every server.
+    ///
+    /// The hashes are sorted on each server, but those of one server are listed after
+    /// those of the other.
+    pub fn list_all(&self, prefix: &str) -> impl Stream<Item = (Vec<u8>, u64), Error = ClientError> {
+        let prefix = prefix.to_string();
+        let clients: Vec<_> = self.ring.servers().iter().map(|addr| self.connect(*addr)).collect();
+        stream::iter_ok(clients)
+            .and_then(|client| client)
+            .map(move |client| client.list_all(&prefix))
+            .flatten()
+    }
+
+    /// Finds the hash stored on any of the servers whose hex encoding starts with `prefix`,
+    /// if there is one.
+    ///
+    /// Fails with `ClientError::Ambiguous` if more than one hash starts with it.
+    pub fn resolve(&self, prefix: &str) -> impl Future<Item = Option<Vec<u8>>, Error = ClientError> {
+        let prefix = prefix.to_string();
+        self.all_clients()
+            .and_then({
+                let prefix = prefix.clone();
+                move |clients| {
+                    let resolves: Vec<_> = clients.into_iter()
+                        .map(|client| client.resolve(&prefix).then(|res| match res {
+                            Ok(hash) => Ok(hash.into_iter().collect()),
+                            Err(ClientError::Ambiguous { candidates, .. }) => Ok(candidates),
+                            Err(e) => Err(e),
+                        }))
+                        .collect();
+                    future::join_all(resolves)
+                }
+            })
+            .and_then(move |found: Vec<Vec<Vec<u8>>>| {
+                let mut hashes: Vec<Vec<u8>> = found.into_iter().flatten().collect();
+                hashes.sort();
+                hashes.dedup();
+                match hashes.len() {
+                    0 => Ok(None),
+                    1 => Ok(hashes.pop()),
+                    _ => Err(ClientError::Ambiguous {
+                        prefix,
+                        candidates: hashes,
+                    }),
+                }
+            })
+    }
+
+    /// Checks whether the server `hash` belongs to stores anything under it.
+    pub fn exists(&self, hash: &[u8]) -> impl Future<Item = bool, Error = ClientError> {
+        self.stat(vec![hash.to_vec()]).map(|stats| stats[0].is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    use crate::client::tests::serve;
+    use crate::messages::{Frame, RequestId, ResolvedMessage, StatsMessage};
+    use crate::store::tests::hash_of;
+
+    fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+        ports.iter().map(|port| SocketAddr::from(([10, 0, 0, 1], *port))).collect()
+    }
+
+    fn sample(count: u32) -> Vec<Vec<u8>> {
+        (0..count).map(|i| hash_of(&i.to_le_bytes())).collect()
+    }
+
+    #[test]
+    fn places_hashes_whatever_the_order_of_the_servers() {
+        let ring = HashRing::new(addrs(&[1, 2, 3]), DEFAULT_VNODES).unwrap();
+        let shuffled = HashRing::new(addrs(&[3, 1, 2, 1, 3]), DEFAULT_VNODES).unwrap();
+        assert_eq!(shuffled.servers(), ring.servers());
+        assert_eq!(shuffled.points, ring.points);
+        for hash in sample(1000) {
+            assert_eq!(shuffled.server_for(&hash), ring.server_for(&hash));
+        }
+        assert!(HashRing::new(Vec::new(), DEFAULT_VNODES).is_err());
+        assert!(HashRing::new(addrs(&[1]), 0).is_err());
+    }
+
+    #[test]
+    fn moves_a_share_of_the_hashes_to_an_added_server() {
+        let ring = HashRing::new(addrs(&[1, 2, 3, 4]), DEFAULT_VNODES).unwrap();
+        let grown = HashRing::new(addrs(&[1, 2, 3, 4, 5]), DEFAULT_VNODES).unwrap();
+        let added = addrs(&[5])[0];
+        let hashes = sample(10000);
+        let mut moved = 0;
+        for hash in &hashes {
+            if grown.server_for(hash) != ring.server_for(hash) {
+                // hashes only move to the new server
+                assert_eq!(grown.server_for(hash), added);
+                moved += 1;
+            }
+        }
+        // about 1 / 5 of them
+        assert!(moved > hashes.len() / 10 && moved < hashes.len() * 3 / 10, "moved {} hashes", moved);
+    }
+
+    #[test]
+    fn wraps_around_past_the_last_point() {
+        let ring = HashRing::new(addrs(&[1, 2]), 1).unwrap();
+        let (first, last) = (ring.points[0], ring.points[1]);
+        assert_ne!(first.1, last.1);
+        let at = |pos: u64| {
+            let mut hash = pos.to_be_bytes().to_vec();
+            hash.resize(64, 0xff);
+            hash
+        };
+        assert_eq!(ring.server_for(&at(0)), first.1);
+        assert_eq!(ring.server_for(&at(first.0)), first.1);
+        assert_eq!(ring.server_for(&at(first.0 + 1)), last.1);
+        assert_eq!(ring.server_for(&at(last.0)), last.1);
+        assert_eq!(ring.server_for(&at(last.0 + 1)), first.1);
+        assert_eq!(ring.server_for(&at(u64::MAX)), first.1);
+    }
+
+    /// A server that describes the data of the hashes it is asked about as `size` bytes
+    /// long, unless their first byte is a multiple of 3, and records the hashes asked about.
+    fn stat_server(size: u64, asked: Arc<Mutex<Vec<Vec<Vec<u8>>>>>) -> impl FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> {
+        move |id, frame| match frame {
+            Frame::Stat(msg) => {
+                asked.lock().unwrap().push(msg.hashes.clone());
+                let stats = msg.hashes.into_iter()
+                    .map(|hash| {
+                        let stat = if hash[0] % 3 == 0 { None } else { Some(BlobStat { size, stored_at: 0 }) };
+                        (hash, stat)
+                    })
+                    .collect();
+                vec![(id, Frame::Stats(StatsMessage::new(stats)))]
+            },
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn asks_each_server_about_its_hashes_at_once() {
+        let mut runtime = Runtime::new().unwrap();
+        let asked = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let servers = vec![
+            serve(&mut runtime, stat_server(1, asked.0.clone())),
+            serve(&mut runtime, stat_server(2, asked.1.clone())),
+        ];
+        let client = ShardedClient::new(HashRing::new(servers.clone(), DEFAULT_VNODES).unwrap(), Connector::Plain);
+        let hashes = sample(20);
+
+        let stats = runtime.block_on(client.stat(hashes.clone())).unwrap();
+        assert_eq!(stats.len(), hashes.len());
+        for (hash, stat) in hashes.iter().zip(stats) {
+            let size = if client.ring().server_for(hash) == servers[0] { 1 } else { 2 };
+            let expected = if hash[0] % 3 == 0 { None } else { Some(size) };
+            assert_eq!(stat.map(|stat| stat.size), expected);
+        }
+        for (asked, server) in [asked.0, asked.1].iter().zip(servers) {
+            let owned: Vec<_> = hashes.iter().filter(|hash| client.ring().server_for(hash) == server).cloned().collect();
+            assert_eq!(*asked.lock().unwrap(), vec![owned]);
+        }
+    }
+
+    /// A server that resolves each prefix to the hashes `hashes` gives for it.
+    fn resolve_server(hashes: fn(&str) -> Vec<Vec<u8>>) -> impl FnMut(RequestId, Frame) -> Vec<(RequestId, Frame)> {
+        move |id, frame| match frame {
+            Frame::Resolve(msg) => vec![(id, Frame::Resolved(ResolvedMessage::new(hashes(&msg.prefix))))],
+            frame => panic!("unexpected {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn resolves_prefixes_on_every_server() {
+        let mut runtime = Runtime::new().unwrap();
+        let servers = vec![
+            serve(&mut runtime, resolve_server(|prefix| match prefix {
+                "aa" | "bb" => vec![vec![0xaa; 64]],
+                "cc" => vec![vec![0xcc; 64]],
+                "dd" => vec![vec![0xdd; 64], vec![0xde; 64]],
+                _ => Vec::new(),
+            })),
+            serve(&mut runtime, resolve_server(|prefix| match prefix {
+                "aa" => vec![vec![0xaa; 64]],
+                "bb" => vec![vec![0xbb; 64]],
+                "dd" => vec![vec![0xdc; 64]],
+                _ => Vec::new(),
+            })),
+        ];
+        let client = ShardedClient::new(HashRing::new(servers, DEFAULT_VNODES).unwrap(), Connector::Plain);
+        let mut resolve = |prefix: &str| runtime.block_on(client.resolve(prefix));
+
+        // copies of a hash on several servers are the same hash
+        assert_eq!(resolve("aa").unwrap(), Some(vec![0xaa; 64]));
+        assert_eq!(resolve("cc").unwrap(), Some(vec![0xcc; 64]));
+        assert_eq!(resolve("ee").unwrap(), None);
+        match resolve("bb") {
+            Err(ClientError::Ambiguous { prefix, candidates }) => {
+                assert_eq!(prefix, "bb");
+                assert_eq!(candidates, vec![vec![0xaa; 64], vec![0xbb; 64]]);
+            },
+            res => panic!("unexpected {:?}", res),
+        }
+        match resolve("dd") {
+            Err(ClientError::Ambiguous { candidates, .. }) => {
+                assert_eq!(candidates, vec![vec![0xdc; 64], vec![0xdd; 64], vec![0xde; 64]]);
+            },
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod append_log;
+mod chunked;
+mod disk;
+
+pub use self::append_log::LogStore;
+pub use self::chunked::ChunkStore;
+pub use self::disk::DiskStore;
+
+/// The storage backend driven by the mapper.
+///
+/// A store is owned by a single mapper, so implementations do not need to be
+/// synchronised.
+pub trait BlobStore<K, T>: Send + Sync + fmt::Debug {
+    /// Returns the data stored under `key`, if any.
+    fn get(&self, key: &K) -> io::Result<Option<Arc<T>>>;
+
+    /// Returns at most `len` bytes of the data stored under `key`, starting at `offset`, if
+    /// any data is stored. Fewer bytes are returned at the end of the data.
+    ///
+    /// By default the whole data is read, so stores that can read a part of it should.
+    fn get_range(&self, key: &K, offset: u64, len: u64) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsRef<[u8]>,
+    {
+        Ok(self.get(key)?.map(|data| {
+            let data = (*data).as_ref();
+            let (start, end) = clamp_range(data.len() as u64, offset, len);
+            data[start as usize..end as usize].to_vec()
+        }))
+    }
+
+    /// Stores `data` under `key`, replacing any previous data.
+    fn put(&mut self, key: K, data: T) -> io::Result<()>;
+
+    /// Checks whether any data is stored under `key`.
+    fn contains(&self, key: &K) -> bool;
+
+    /// Describes the data stored under `key`, if any, without reading it.
+    fn stat(&self, key: &K) -> io::Result<Option<BlobStat>>;
+
+    /// Removes the data stored under `key`. Returns whether there was anything to remove.
+    fn delete(&mut self, key: &K) -> io::Result<bool>;
+
+    /// Iterates over all the keys in the store.
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a K> + 'a>;
+
+    /// Stores the contents of `spool` under `key`, replacing any previous data.
+    fn put_spooled(&mut self, key: K, spool: SpoolFile) -> io::Result<()>;
+
+    /// The directory in which blobs should be spooled before being stored.
+    fn spool_dir(&self) -> PathBuf {
+        env::temp_dir()
+    }
+}
+
+/// What a store knows about a blob, apart from its data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlobStat {
+    /// The length of the data in bytes
+    pub size: u64,
+    /// When the data was stored, in seconds since the unix epoch
+    pub stored_at: u64,
+}
+
+/// Returns where the range of `len` bytes at `offset` starts and ends within data of `size`
+/// bytes.
+pub(crate) fn clamp_range(size: u64, offset: u64, len: u64) -> (u64, u64) {
+    let start = cmp::min(offset, size);
+    let end = cmp::min(offset.saturating_add(len), size);
+    (start, end)
+}
+
+/// The current time in seconds since the unix epoch.
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A blob being received.
+///
+/// Blobs are written to a temporary file while they are received, so they do not have to
+/// be held in memory. The file is removed when the spool is dropped, unless it has been
+/// persisted.
+#[derive(Debug)]
+pub struct SpoolFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    persisted: bool,
+}
+
+impl SpoolFile {
+    /// Creates a new, empty spool file in `dir`.
+    pub fn create<P: AsRef<Path>>(dir: P) -> io::Result<SpoolFile> {
+        fs::create_dir_all(dir.as_ref())?;
+        let n = SPOOL_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = dir.as_ref().join(format!("kitap-{}-{}.spool", process::id(), n));
+        let file = File::create(&path)?;
+        Ok(SpoolFile {
+            path,
+            file,
+            len: 0,
+            persisted: false,
+        })
+    }
+
+    /// Opens the spool file at `path` to add to the data already in it, creating it if
+    /// needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SpoolFile> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(SpoolFile {
+            path,
+            file,
+            len,
+            persisted: false,
+        })
+    }
+
+    /// The number of bytes spooled so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Opens the spooled data for reading.
+    pub fn reader(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+
+    /// Reads all of the spooled data in memory.
+    pub fn read_all(&self) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.len as usize);
+        self.reader()?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Leaves the spooled data in its file, where it can be opened again.
+    pub fn keep(mut self) {
+        self.persisted = true;
+    }
+
+    /// Moves the spooled data to `dest`. Both need to be in the same filesystem.
+    pub fn persist<P: AsRef<Path>>(mut self, dest: P) -> io::Result<()> {
+        self.file.sync_all()?;
+        fs::rename(&self.path, dest)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Write for SpoolFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A store that keeps everything in memory. Its contents are lost when it is dropped.
+#[derive(Debug)]
+pub struct MemoryStore<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    map: HashMap<K, (Arc<T>, u64)>,
+}
+
+impl<K, T> Default for MemoryStore<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    fn default() -> MemoryStore<K, T> {
+        MemoryStore::new()
+    }
+}
+
+impl<K, T> MemoryStore<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq,
+{
+    pub fn new() -> MemoryStore<K, T> {
+        MemoryStore {
+            map: HashMap::new(),
+        }
+    }
+}
+
+impl<K, T> BlobStore<K, T> for MemoryStore<K, T>
+where
+    K: std::hash::Hash + std::cmp::Eq + Send + Sync + fmt::Debug,
+    T: From<Vec<u8>> + AsRef<[u8]> + Send + Sync + fmt::Debug,
+{
+    fn get(&self, key: &K) -> io::Result<Option<Arc<T>>> {
+        Ok(self.map.get(key).map(|(data, _)| data.clone()))
+    }
+
+    fn put(&mut self, key: K, data: T) -> io::Result<()> {
+        self.map.insert(key, (Arc::new(data), unix_time()));
+        Ok(())
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn stat(&self, key: &K) -> io::Result<Option<BlobStat>> {
+        Ok(self.map.get(key).map(|(data, stored_at)| BlobStat {
+            size: (**data).as_ref().len() as u64,
+            stored_at: *stored_at,
+        }))
+    }
+
+    fn delete(&mut self, key: &K) -> io::Result<bool> {
+        Ok(self.map.remove(key).is_some())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a K> + 'a> {
+        Box::new(self.map.keys())
+    }
+
+    fn put_spooled(&mut self, key: K, spool: SpoolFile) -> io::Result<()> {
+        let data = spool.read_all()?;
+        self.put(key, T::from(data))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::hash::KitapHasher;
+
+    /// A directory for a test, removed with everything in it when dropped.
+    pub(crate) struct TestDir(PathBuf);
+
+    impl TestDir {
+        pub(crate) fn new(name: &str) -> TestDir {
+            let n = SPOOL_COUNTER.fetch_add(1, Ordering::SeqCst);
+            let path = env::temp_dir().join(format!("kitap-test-{}-{}-{}", name, process::id(), n));
+            fs::create_dir_all(&path).unwrap();
+            TestDir(path)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    pub(crate) fn hash_of(data: &[u8]) -> Vec<u8> {
+        let mut hasher = KitapHasher::new();
+        hasher.input(data);
+        hasher.result().to_vec()
+    }
+
+    /// Spools `data` in the spool directory of `store`.
+    pub(crate) fn spooled(store: &dyn BlobStore<Vec<u8>, Vec<u8>>, data: &[u8]) -> SpoolFile {
+        let mut spool = SpoolFile::create(store.spool_dir()).unwrap();
+        spool.write_all(data).unwrap();
+        spool
+    }
+
+    /// Checks that `store` keeps, replaces and deletes blobs.
+    pub(crate) fn check_put_get_delete(store: &mut dyn BlobStore<Vec<u8>, Vec<u8>>) {
+        let (a, b) = (b"some data".to_vec(), b"other data".to_vec());
+        let (ka, kb) = (hash_of(&a), hash_of(&b));
+        assert!(!store.contains(&ka));
+        assert!(store.get(&ka).unwrap().is_none());
+        assert!(store.stat(&ka).unwrap().is_none());
+
+        store.put(ka.clone(), a.clone()).unwrap();
+        let spool = spooled(store, &b);
+        store.put_spooled(kb.clone(), spool).unwrap();
+        assert!(store.contains(&ka) && store.contains(&kb));
+        assert_eq!(*store.get(&ka).unwrap().unwrap(), a);
+        assert_eq!(*store.get(&kb).unwrap().unwrap(), b);
+        assert_eq!(store.stat(&kb).unwrap().unwrap().size, b.len() as u64);
+        let mut keys: Vec<_> = store.iter().cloned().collect();
+        keys.sort();
+        let mut expected = vec![ka.clone(), kb.clone()];
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        store.put(ka.clone(), b"replaced".to_vec()).unwrap();
+        assert_eq!(*store.get(&ka).unwrap().unwrap(), b"replaced".to_vec());
+
+        assert!(store.delete(&ka).unwrap());
+        assert!(!store.delete(&ka).unwrap());
+        assert!(!store.contains(&ka));
+        assert!(store.get(&ka).unwrap().is_none());
+        assert!(store.contains(&kb));
+    }
+
+    /// Checks that ranges read from `store` are clamped to the end of the data.
+    pub(crate) fn check_ranges(store: &mut dyn BlobStore<Vec<u8>, Vec<u8>>) {
+        let data: Vec<u8> = (0..100u8).collect();
+        let key = hash_of(&data);
+        store.put(key.clone(), data.clone()).unwrap();
+        assert_eq!(store.get_range(&key, 10, 5).unwrap().unwrap(), data[10..15].to_vec());
+        assert_eq!(store.get_range(&key, 0, 1000).unwrap().unwrap(), data);
+        assert_eq!(store.get_range(&key, 90, 20).unwrap().unwrap(), data[90..].to_vec());
+        assert_eq!(store.get_range(&key, 100, 10).unwrap().unwrap(), Vec::<u8>::new());
+        assert_eq!(store.get_range(&key, 500, 10).unwrap().unwrap(), Vec::<u8>::new());
+        assert_eq!(store.get_range(&key, 50, u64::MAX).unwrap().unwrap(), data[50..].to_vec());
+        assert!(store.get_range(&hash_of(b"missing"), 0, 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn clamps_ranges_to_the_data() {
+        assert_eq!(clamp_range(10, 2, 3), (2, 5));
+        assert_eq!(clamp_range(10, 8, 5), (8, 10));
+        assert_eq!(clamp_range(10, 12, 5), (10, 10));
+        assert_eq!(clamp_range(10, 5, u64::MAX), (5, 10));
+    }
+
+    #[test]
+    fn memory_store_puts_gets_and_deletes() {
+        let dir = TestDir::new("memory");
+        let mut store: MemoryStore<Vec<u8>, Vec<u8>> = MemoryStore::new();
+        // spool in a directory of the test rather than directly in the temp dir
+        let (a, ka) = (b"spooled".to_vec(), hash_of(b"spooled"));
+        let mut spool = SpoolFile::create(dir.path()).unwrap();
+        spool.write_all(&a).unwrap();
+        store.put_spooled(ka.clone(), spool).unwrap();
+        assert_eq!(*store.get(&ka).unwrap().unwrap(), a);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(store.delete(&ka).unwrap());
+        check_put_get_delete(&mut store);
+    }
+
+    #[test]
+    fn memory_store_clamps_ranges() {
+        check_ranges(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn spool_files_are_removed_unless_persisted() {
+        let dir = TestDir::new("spool");
+        let mut spool = SpoolFile::create(dir.path()).unwrap();
+        spool.write_all(b"data").unwrap();
+        assert_eq!(spool.len(), 4);
+        assert_eq!(spool.read_all().unwrap(), b"data".to_vec());
+        drop(spool);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let mut spool = SpoolFile::create(dir.path()).unwrap();
+        spool.write_all(b"data").unwrap();
+        spool.persist(dir.path().join("kept")).unwrap();
+        assert_eq!(fs::read(dir.path().join("kept")).unwrap(), b"data".to_vec());
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use log::{debug, warn};
+
+use crate::store::{clamp_range, unix_time, BlobStat, BlobStore, SpoolFile};
+
+const LOG_NAME: &str = "blobs.log";
+
+/// kind (1) + timestamp (8) + key length (4) + data length (8)
+const RECORD_HEADER_LEN: u64 = 21;
+
+const RECORD_PUT: u8 = 0;
+const RECORD_DELETE: u8 = 1;
+
+#[derive(Debug)]
+struct Entry {
+    offset: u64,
+    len: u64,
+    stored_at: u64,
+}
+
+/// Blob storage in a single append-only log file.
+///
+/// Every put or delete appends a record to the log, and nothing is ever rewritten.
+/// The log is replayed when the store is opened to rebuild the index of the
+/// blobs it holds, so writes are cheap but space of replaced or deleted blobs is
+/// never reclaimed.
+#[derive(Debug)]
+pub struct LogStore {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    index: HashMap<Vec<u8>, Entry>,
+}
+
+impl LogStore {
+    /// Opens the log that lives in `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<LogStore> {
+        fs::create_dir_all(dir.as_ref())?;
+        let path = dir.as_ref().join(LOG_NAME);
+        let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let (index, len) = LogStore::replay(&file)?;
+        if len < file.metadata()?.len() {
+            warn!("Discarding incomplete record at the end of {}", path.display());
+            file.set_len(len)?;
+        }
+        debug!("Found {} blobs in {}", index.len(), path.display());
+        Ok(LogStore {
+            path,
+            file,
+            len,
+            index,
+        })
+    }
+
+    /// Reads all the records of the log, returning the resulting index and the length of
+    /// the log up to the last complete record.
+    fn replay(file: &File) -> io::Result<(HashMap<Vec<u8>, Entry>, u64)> {
+        let total = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut index = HashMap::new();
+        let mut pos = 0;
+        while pos + RECORD_HEADER_LEN <= total {
+            let kind = reader.read_u8()?;
+            let timestamp = reader.read_u64::<LittleEndian>()?;
+            let key_len = u64::from(reader.read_u32::<LittleEndian>()?);
+            let data_len = reader.read_u64::<LittleEndian>()?;
+            let offset = pos + RECORD_HEADER_LEN + key_len;
+            if offset.saturating_add(data_len) > total {
+                break;
+            }
+            let mut key = vec![0; key_len as usize];
+            reader.read_exact(&mut key)?;
+            reader.seek(SeekFrom::Current(data_len as i64))?;
+            match kind {
+                RECORD_PUT => {
+                    index.insert(key, Entry { offset, len: data_len, stored_at: timestamp });
+                },
+                RECORD_DELETE => {
+                    index.remove(&key);
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown record kind")),
+            }
+            pos = offset + data_len;
+        }
+        Ok((index, pos))
+    }
+
+    /// Appends a record, returning the entry of its data.
+    fn append(&mut self, kind: u8, key: &[u8], data: &mut dyn Read, len: u64) -> io::Result<Entry> {
+        let timestamp = unix_time();
+        let mut header = Vec::with_capacity(RECORD_HEADER_LEN as usize + key.len());
+        header.write_u8(kind)?;
+        header.write_u64::<LittleEndian>(timestamp)?;
+        header.write_u32::<LittleEndian>(key.len() as u32)?;
+        header.write_u64::<LittleEndian>(len)?;
+        header.extend(key);
+        let written = self.file.write_all(&header)
+            .and_then(|_| io::copy(&mut data.take(len), &mut self.file))
+            .and_then(|copied| {
+                if copied == len {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data is shorter than its length"))
+                }
+            })
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // drop the partial record, so that the next one is appended after a complete one
+            self.file.set_len(self.len)?;
+            return Err(e);
+        }
+        let offset = self.len + header.len() as u64;
+        self.len = offset + len;
+        Ok(Entry { offset, len, stored_at: timestamp })
+    }
+}
+
+impl BlobStore<Vec<u8>, Vec<u8>> for LogStore {
+    fn get(&self, key: &Vec<u8>) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let entry = match self.index.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(entry.offset))?;
+        let mut data = vec![0; entry.len as usize];
+        f.read_exact(&mut data)?;
+        Ok(Some(Arc::new(data)))
+    }
+
+    fn get_range(&self, key: &Vec<u8>, offset: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+        let entry = match self.index.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let (start, end) = clamp_range(entry.len, offset, len);
+        let mut f = File::open(&self.path)?;
+        f.seek(SeekFrom::Start(entry.offset + start))?;
+        let mut data = vec![0; (end - start) as usize];
+        f.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn put(&mut self, key: Vec<u8>, data: Vec<u8>) -> io::Result<()> {
+        let len = data.len() as u64;
+        let entry = self.append(RECORD_PUT, &key, &mut data.as_slice(), len)?;
+        self.index.insert(key, entry);
+        Ok(())
+    }
+
+    fn contains(&self, key: &Vec<u8>) -> bool {
+        self.index.contains_key(key)
+    }
+
+    fn stat(&self, key: &Vec<u8>) -> io::Result<Option<BlobStat>> {
+        Ok(self.index.get(key).map(|entry| BlobStat {
+            size: entry.len,
+            stored_at: entry.stored_at,
+        }))
+    }
+
+    fn delete(&mut self, key: &Vec<u8>) -> io::Result<bool> {
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(RECORD_DELETE, key, &mut io::empty(), 0)?;
+        self.index.remove(key);
+        Ok(true)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        Box::new(self.index.keys())
+    }
+
+    fn put_spooled(&mut self, key: Vec<u8>, spool: SpoolFile) -> io::Result<()> {
+        let len = spool.len();
+        let entry = self.append(RECORD_PUT, &key, &mut spool.reader()?, len)?;
+        self.index.insert(key, entry);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::{check_put_get_delete, check_ranges, hash_of, TestDir};
+
+    #[test]
+    fn puts_gets_and_deletes() {
+        let dir = TestDir::new("log");
+        check_put_get_delete(&mut LogStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn clamps_ranges() {
+        let dir = TestDir::new("log-range");
+        check_ranges(&mut LogStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn replays_the_log_on_open() {
+        let dir = TestDir::new("log-reopen");
+        let (a, b) = (b"kept".to_vec(), b"deleted".to_vec());
+        {
+            let mut store = LogStore::open(dir.path()).unwrap();
+            store.put(hash_of(&a), b"replaced".to_vec()).unwrap();
+            store.put(hash_of(&a), a.clone()).unwrap();
+            store.put(hash_of(&b), b.clone()).unwrap();
+            store.delete(&hash_of(&b)).unwrap();
+        }
+        let store = LogStore::open(dir.path()).unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(*store.get(&hash_of(&a)).unwrap().unwrap(), a);
+        assert!(!store.contains(&hash_of(&b)));
+    }
+
+    #[test]
+    fn discards_an_incomplete_record_on_open() {
+        let dir = TestDir::new("log-truncated");
+        let a = b"complete".to_vec();
+        let len = {
+            let mut store = LogStore::open(dir.path()).unwrap();
+            store.put(hash_of(&a), a.clone()).unwrap();
+            store.put(hash_of(b"cut"), b"cut short".to_vec()).unwrap();
+            store.len
+        };
+        let file = OpenOptions::new().write(true).open(dir.path().join(LOG_NAME)).unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut store = LogStore::open(dir.path()).unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(*store.get(&hash_of(&a)).unwrap().unwrap(), a);
+        // records are appended after the last complete one
+        store.put(hash_of(b"next"), b"next".to_vec()).unwrap();
+        drop(store);
+        let store = LogStore::open(dir.path()).unwrap();
+        assert_eq!(*store.get(&hash_of(b"next")).unwrap().unwrap(), b"next".to_vec());
+    }
+}
@@ -0,0 +1,525 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use hex::{decode, encode};
+
+use log::{debug, warn};
+
+use crate::hash::{KitapHasher, HASH_SIZE};
+use crate::store::{clamp_range, unix_time, BlobStat, BlobStore, SpoolFile};
+
+const CHUNK_DIR: &str = "chunks";
+const MANIFEST_DIR: &str = "manifests";
+const SPOOL_DIR: &str = ".spool";
+
+/// No boundary is looked for before a chunk is this long.
+const MIN_CHUNK: usize = 16 * 1024;
+/// Chunks are cut here even if no boundary was found.
+const MAX_CHUNK: usize = 256 * 1024;
+/// A boundary is found where the low 16 bits of the rolling hash are zero, which makes
+/// chunks 64KiB long on average.
+const BOUNDARY_MASK: u64 = (1 << 16) - 1;
+
+/// Random values mixed into the rolling hash for every byte value.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so that the table is the same for every build
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6b69_7461_7021_c0de;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Returns the length of the first chunk of `data`, if a boundary is found in it.
+///
+/// The rolling hash shifts every byte out after 64 more, so only the bytes just before
+/// `MIN_CHUNK` are hashed to know its value there.
+fn boundary(data: &[u8]) -> Option<usize> {
+    let end = cmp::min(data.len(), MAX_CHUNK);
+    let start = MIN_CHUNK.saturating_sub(64);
+    if end < MIN_CHUNK {
+        return None;
+    }
+    let mut hash: u64 = 0;
+    for (i, byte) in data[start..end].iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let len = start + i + 1;
+        if len >= MIN_CHUNK && hash & BOUNDARY_MASK == 0 {
+            return Some(len);
+        }
+    }
+    None
+}
+
+/// Splits the data read from `reader` into content-defined chunks, passing each to
+/// `on_chunk`.
+///
+/// Boundaries are placed where a rolling hash of the last bytes matches a pattern, so an
+/// edit only changes the chunks around it, and the rest of the data is cut the same way.
+fn split<R, F>(mut reader: R, mut on_chunk: F) -> io::Result<()>
+where
+    R: Read,
+    F: FnMut(&[u8]) -> io::Result<()>,
+{
+    let mut buf = vec![0; MAX_CHUNK];
+    let mut filled = 0;
+    let mut eof = false;
+    loop {
+        // a chunk is only cut once the buffer is full or the data is over, so that every
+        // boundary it could have is looked for
+        while !eof && filled < buf.len() {
+            match reader.read(&mut buf[filled..]) {
+                Ok(0) => eof = true,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            return Ok(());
+        }
+        let len = boundary(&buf[..filled]).unwrap_or(filled);
+        on_chunk(&buf[..len])?;
+        buf.copy_within(len..filled, 0);
+        filled -= len;
+    }
+}
+
+/// What is known about a stored blob.
+#[derive(Debug)]
+struct Manifest {
+    stored_at: u64,
+    size: u64,
+    chunks: Vec<Vec<u8>>,
+}
+
+impl Manifest {
+    fn read(path: &Path) -> io::Result<Manifest> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let stored_at = reader.read_u64::<LittleEndian>()?;
+        let size = reader.read_u64::<LittleEndian>()?;
+        let count = reader.read_u32::<LittleEndian>()?;
+        let mut chunks = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut chunk = vec![0; HASH_SIZE];
+            reader.read_exact(&mut chunk)?;
+            chunks.push(chunk);
+        }
+        Ok(Manifest {
+            stored_at,
+            size,
+            chunks,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(20 + self.chunks.len() * HASH_SIZE);
+        v.write_u64::<LittleEndian>(self.stored_at).unwrap();
+        v.write_u64::<LittleEndian>(self.size).unwrap();
+        v.write_u32::<LittleEndian>(self.chunks.len() as u32).unwrap();
+        for chunk in &self.chunks {
+            v.extend(chunk);
+        }
+        v
+    }
+}
+
+/// Deduplicating blob storage on disk.
+///
+/// Blobs are split into content-defined chunks, and every distinct chunk is kept once in
+/// its own file, named after the hex encoding of its hash. Each blob is kept as a manifest
+/// listing its chunks, so blobs that share most of their content share most of their
+/// storage. Chunks are counted by the manifests that use them, and removed when none do.
+#[derive(Debug)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    manifests: HashMap<Vec<u8>, Manifest>,
+    refs: HashMap<Vec<u8>, usize>,
+}
+
+impl ChunkStore {
+    /// Opens the store that lives in `dir`, creating it if needed.
+    ///
+    /// Chunks that no manifest uses, left behind by interrupted writes, are removed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<ChunkStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(CHUNK_DIR))?;
+        fs::create_dir_all(dir.join(MANIFEST_DIR))?;
+        // spooled blobs that were never stored
+        let _ = fs::remove_dir_all(dir.join(SPOOL_DIR));
+
+        let mut manifests = HashMap::new();
+        let mut refs = HashMap::new();
+        for key in ChunkStore::scan(&dir.join(MANIFEST_DIR))? {
+            let manifest = Manifest::read(&dir.join(MANIFEST_DIR).join(encode(&key)))?;
+            for chunk in &manifest.chunks {
+                *refs.entry(chunk.clone()).or_insert(0) += 1;
+            }
+            manifests.insert(key, manifest);
+        }
+        for chunk in ChunkStore::scan(&dir.join(CHUNK_DIR))? {
+            if !refs.contains_key(&chunk) {
+                debug!("Removing unused chunk {}", encode(&chunk));
+                fs::remove_file(dir.join(CHUNK_DIR).join(encode(&chunk)))?;
+            }
+        }
+        debug!("Found {} blobs made of {} chunks in {}", manifests.len(), refs.len(), dir.display());
+        Ok(ChunkStore {
+            dir,
+            manifests,
+            refs,
+        })
+    }
+
+    /// Returns the hashes that the files of `dir` are named after.
+    fn scan(dir: &Path) -> io::Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            match name.to_str().map(decode) {
+                Some(Ok(key)) if key.len() == HASH_SIZE => keys.push(key),
+                // leftovers of interrupted writes
+                _ if name.to_string_lossy().starts_with('.') => (),
+                _ => warn!("Skipping unknown file {:?} in store", name),
+            }
+        }
+        Ok(keys)
+    }
+
+    fn chunk_path(&self, chunk: &[u8]) -> PathBuf {
+        self.dir.join(CHUNK_DIR).join(encode(chunk))
+    }
+
+    fn manifest_path(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(MANIFEST_DIR).join(encode(key))
+    }
+
+    /// Writes `data` to `path` through a temporary file, so a crash never leaves a partial
+    /// file behind.
+    fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+        let mut tmp = path.to_path_buf();
+        tmp.set_file_name(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+        let mut f = File::create(&tmp)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    /// Chunks the data read from `reader` and stores it under `key`.
+    ///
+    /// If storing fails, the chunks written for it are removed again.
+    fn put_reader<R: Read>(&mut self, key: Vec<u8>, reader: R) -> io::Result<()> {
+        let mut written = HashSet::new();
+        let stored = self.write_blob(key, reader, &mut written);
+        if stored.is_err() {
+            for chunk in written {
+                if let Err(e) = fs::remove_file(self.chunk_path(&chunk)) {
+                    warn!("Could not remove chunk {}: {}", encode(&chunk), e);
+                }
+            }
+        }
+        stored
+    }
+
+    /// Chunks the data read from `reader` and stores it under `key`, adding the chunks
+    /// that were not stored yet to `written` as they are written.
+    fn write_blob<R: Read>(&mut self, key: Vec<u8>, reader: R, written: &mut HashSet<Vec<u8>>) -> io::Result<()> {
+        let mut chunks = Vec::new();
+        let mut size = 0;
+        split(reader, |chunk| {
+            let mut hasher = KitapHasher::new();
+            hasher.input(chunk);
+            let hash = hasher.result().to_vec();
+            // chunks are only written once, whichever blobs they belong to
+            if !self.refs.contains_key(&hash) && !written.contains(&hash) {
+                ChunkStore::write_file(&self.chunk_path(&hash), chunk)?;
+                written.insert(hash.clone());
+            }
+            size += chunk.len() as u64;
+            chunks.push(hash);
+            Ok(())
+        })?;
+        debug!("Stored {} as {} chunks, {} of them new", encode(&key), chunks.len(), written.len());
+
+        let manifest = Manifest {
+            stored_at: unix_time(),
+            size,
+            chunks,
+        };
+        ChunkStore::write_file(&self.manifest_path(&key), &manifest.to_bytes())?;
+        written.clear();
+        for chunk in &manifest.chunks {
+            *self.refs.entry(chunk.clone()).or_insert(0) += 1;
+        }
+        if let Some(old) = self.manifests.insert(key, manifest) {
+            self.release(old)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the references of a manifest that is no longer stored, removing the chunks
+    /// no other manifest uses.
+    fn release(&mut self, manifest: Manifest) -> io::Result<()> {
+        for chunk in manifest.chunks {
+            let count = self.refs.get_mut(&chunk).expect("chunk of a manifest is not counted");
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(&chunk);
+                fs::remove_file(self.chunk_path(&chunk))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BlobStore<Vec<u8>, Vec<u8>> for ChunkStore {
+    fn get(&self, key: &Vec<u8>) -> io::Result<Option<Arc<Vec<u8>>>> {
+        let manifest = match self.manifests.get(key) {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        let mut data = Vec::with_capacity(manifest.size as usize);
+        for chunk in &manifest.chunks {
+            File::open(self.chunk_path(chunk))?.read_to_end(&mut data)?;
+        }
+        Ok(Some(Arc::new(data)))
+    }
+
+    /// Only the chunks that overlap the range are read.
+    fn get_range(&self, key: &Vec<u8>, offset: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+        let manifest = match self.manifests.get(key) {
+            Some(manifest) => manifest,
+            None => return Ok(None),
+        };
+        let (start, end) = clamp_range(manifest.size, offset, len);
+        let mut data = Vec::with_capacity((end - start) as usize);
+        let mut pos = 0;
+        for chunk in &manifest.chunks {
+            if pos >= end {
+                break;
+            }
+            let path = self.chunk_path(chunk);
+            let chunk_len = fs::metadata(&path)?.len();
+            if pos + chunk_len > start {
+                let mut f = File::open(path)?;
+                let skip = start.saturating_sub(pos);
+                f.seek(SeekFrom::Start(skip))?;
+                f.take(cmp::min(end, pos + chunk_len) - pos - skip).read_to_end(&mut data)?;
+            }
+            pos += chunk_len;
+        }
+        Ok(Some(data))
+    }
+
+    fn put(&mut self, key: Vec<u8>, data: Vec<u8>) -> io::Result<()> {
+        self.put_reader(key, data.as_slice())
+    }
+
+    fn contains(&self, key: &Vec<u8>) -> bool {
+        self.manifests.contains_key(key)
+    }
+
+    fn stat(&self, key: &Vec<u8>) -> io::Result<Option<BlobStat>> {
+        Ok(self.manifests.get(key).map(|manifest| BlobStat {
+            size: manifest.size,
+            stored_at: manifest.stored_at,
+        }))
+    }
+
+    fn delete(&mut self, key: &Vec<u8>) -> io::Result<bool> {
+        let manifest = match self.manifests.remove(key) {
+            Some(manifest) => manifest,
+            None => return Ok(false),
+        };
+        fs::remove_file(self.manifest_path(key))?;
+        self.release(manifest)?;
+        Ok(true)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        Box::new(self.manifests.keys())
+    }
+
+    fn put_spooled(&mut self, key: Vec<u8>, spool: SpoolFile) -> io::Result<()> {
+        self.put_reader(key, spool.reader()?)
+    }
+
+    fn spool_dir(&self) -> PathBuf {
+        self.dir.join(SPOOL_DIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::{check_put_get_delete, check_ranges, hash_of, TestDir};
+
+    /// Data that does not repeat, so that it is cut at content-defined boundaries.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    fn chunk_count(dir: &TestDir) -> usize {
+        fs::read_dir(dir.path().join(CHUNK_DIR)).unwrap().count()
+    }
+
+    #[test]
+    fn puts_gets_and_deletes() {
+        let dir = TestDir::new("chunks");
+        check_put_get_delete(&mut ChunkStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn clamps_ranges() {
+        let dir = TestDir::new("chunks-range");
+        check_ranges(&mut ChunkStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn reads_ranges_across_chunks() {
+        let dir = TestDir::new("chunks-span");
+        let mut store = ChunkStore::open(dir.path()).unwrap();
+        let data = noise(1024 * 1024, 1);
+        let key = hash_of(&data);
+        store.put(key.clone(), data.clone()).unwrap();
+        assert!(store.manifests[&key].chunks.len() > 1);
+        assert_eq!(store.get_range(&key, 100_000, 300_000).unwrap().unwrap(), data[100_000..400_000].to_vec());
+        assert_eq!(store.get_range(&key, 1000, 10_000_000).unwrap().unwrap(), data[1000..].to_vec());
+    }
+
+    #[test]
+    fn cuts_where_the_rolling_hash_matches() {
+        let data = noise(2 * 1024 * 1024, 5);
+        let mut expected = Vec::new();
+        let mut chunk = Vec::new();
+        let mut hash: u64 = 0;
+        for byte in &data {
+            chunk.push(*byte);
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if (chunk.len() >= MIN_CHUNK && hash & BOUNDARY_MASK == 0) || chunk.len() >= MAX_CHUNK {
+                expected.push(std::mem::take(&mut chunk));
+                hash = 0;
+            }
+        }
+        expected.push(chunk);
+
+        let mut chunks = Vec::new();
+        // reads are short, so chunks are cut across them
+        split(io::Read::chain(&data[..1000], &data[1000..]), |chunk| {
+            chunks.push(chunk.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn removes_new_chunks_when_storing_fails() {
+        let dir = TestDir::new("chunks-fail");
+        let mut store = ChunkStore::open(dir.path()).unwrap();
+        let a = noise(1024 * 1024, 6);
+        store.put(hash_of(&a), a.clone()).unwrap();
+        let stored = chunk_count(&dir);
+
+        // the new chunks before the failure are written, and one of them is shared
+        let mut b = a[..300 * 1024].to_vec();
+        b.extend(noise(1024 * 1024, 7));
+        let failing = io::Read::chain(&b[..], FailingReader);
+        assert!(store.put_reader(hash_of(b"failed"), failing).is_err());
+        assert_eq!(chunk_count(&dir), stored);
+        assert!(!store.contains(&hash_of(b"failed")));
+        assert_eq!(*store.get(&hash_of(&a)).unwrap().unwrap(), a);
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("read failed"))
+        }
+    }
+
+    #[test]
+    fn splits_the_same_data_the_same_way() {
+        let data = noise(1024 * 1024, 2);
+        let mut chunks = Vec::new();
+        split(data.as_slice(), |chunk| {
+            assert!(chunk.len() <= MAX_CHUNK);
+            chunks.push(chunk.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks[..chunks.len() - 1].iter().all(|chunk| chunk.len() >= MIN_CHUNK));
+
+        // an edit at the start only changes the chunks around it
+        let mut edited = b"prefix".to_vec();
+        edited.extend(&data);
+        let mut edited_chunks = Vec::new();
+        split(edited.as_slice(), |chunk| {
+            edited_chunks.push(chunk.to_vec());
+            Ok(())
+        }).unwrap();
+        assert_eq!(chunks[1..], edited_chunks[1..]);
+    }
+
+    #[test]
+    fn shares_chunks_between_blobs() {
+        let dir = TestDir::new("chunks-dedup");
+        let mut store = ChunkStore::open(dir.path()).unwrap();
+        let a = noise(1024 * 1024, 3);
+        let mut b = a.clone();
+        b.extend(b"appended");
+        store.put(hash_of(&a), a.clone()).unwrap();
+        let alone = chunk_count(&dir);
+        store.put(hash_of(&b), b.clone()).unwrap();
+        assert!(chunk_count(&dir) <= alone + 1);
+
+        store.delete(&hash_of(&a)).unwrap();
+        assert_eq!(*store.get(&hash_of(&b)).unwrap().unwrap(), b);
+        store.delete(&hash_of(&b)).unwrap();
+        assert_eq!(chunk_count(&dir), 0);
+    }
+
+    #[test]
+    fn keeps_blobs_across_open() {
+        let dir = TestDir::new("chunks-reopen");
+        let (a, b) = (noise(300 * 1024, 4), b"deleted".to_vec());
+        {
+            let mut store = ChunkStore::open(dir.path()).unwrap();
+            store.put(hash_of(&a), a.clone()).unwrap();
+            store.put(hash_of(&b), b.clone()).unwrap();
+            store.delete(&hash_of(&b)).unwrap();
+        }
+        // a chunk left behind by an interrupted write
+        let orphan = hash_of(b"orphan");
+        fs::write(dir.path().join(CHUNK_DIR).join(encode(&orphan)), b"orphan").unwrap();
+
+        let store = ChunkStore::open(dir.path()).unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(*store.get(&hash_of(&a)).unwrap().unwrap(), a);
+        assert!(!store.contains(&hash_of(&b)));
+        assert!(!store.chunk_path(&orphan).exists());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use hex::{decode, encode};
+
+use log::{debug, warn};
+
+use crate::hash::HASH_SIZE;
+use crate::store::{BlobStat, BlobStore, SpoolFile};
+
+const SPOOL_DIR: &str = ".spool";
+
+/// Blob storage on disk.
+///
+/// Every blob is kept in its own file inside the store's directory. The file is named
+/// after the hex encoding of the blob's hash, so the directory itself is the index. It
+/// is scanned once when the store is opened.
+#[derive(Debug)]
+pub struct DiskStore {
+    dir: PathBuf,
+    keys: HashSet<Vec<u8>>,
+}
+
+impl DiskStore {
+    /// Opens the store that lives in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<DiskStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        // spooled blobs that were never stored
+        let _ = fs::remove_dir_all(dir.join(SPOOL_DIR));
+        let keys = DiskStore::scan(&dir)?;
+        debug!("Found {} blobs in {}", keys.len(), dir.display());
+        Ok(DiskStore {
+            dir,
+            keys,
+        })
+    }
+
+    /// Returns the keys of all the blobs found in `dir`.
+    ///
+    /// Files whose name is not a hex encoded hash are skipped.
+    fn scan(dir: &Path) -> io::Result<HashSet<Vec<u8>>> {
+        let mut keys = HashSet::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            let name = entry.file_name();
+            match name.to_str().map(decode) {
+                Some(Ok(key)) if key.len() == HASH_SIZE => {
+                    keys.insert(key);
+                },
+                // leftovers of interrupted writes
+                _ if name.to_string_lossy().starts_with('.') => (),
+                _ => warn!("Skipping unknown file {:?} in store", name),
+            }
+        }
+        Ok(keys)
+    }
+
+    fn path_of(&self, key: &[u8]) -> PathBuf {
+        self.dir.join(encode(key))
+    }
+}
+
+impl BlobStore<Vec<u8>, Vec<u8>> for DiskStore {
+    fn get(&self, key: &Vec<u8>) -> io::Result<Option<Arc<Vec<u8>>>> {
+        if !self.keys.contains(key) {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        File::open(self.path_of(key))?.read_to_end(&mut data)?;
+        Ok(Some(Arc::new(data)))
+    }
+
+    fn get_range(&self, key: &Vec<u8>, offset: u64, len: u64) -> io::Result<Option<Vec<u8>>> {
+        if !self.keys.contains(key) {
+            return Ok(None);
+        }
+        let mut f = File::open(self.path_of(key))?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        f.take(len).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Writes `data` under `key`.
+    ///
+    /// The data is first written to a temporary file which is then renamed, so a crash
+    /// never leaves a partially written blob behind.
+    fn put(&mut self, key: Vec<u8>, data: Vec<u8>) -> io::Result<()> {
+        let path = self.path_of(&key);
+        let tmp = self.dir.join(format!(".{}.tmp", encode(&key)));
+        let mut f = File::create(&tmp)?;
+        f.write_all(&data)?;
+        f.sync_all()?;
+        fs::rename(tmp, path)?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    fn contains(&self, key: &Vec<u8>) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Blobs are stored when their file is renamed into place, which keeps its modification
+    /// time, so this is when the data finished being received.
+    fn stat(&self, key: &Vec<u8>) -> io::Result<Option<BlobStat>> {
+        if !self.keys.contains(key) {
+            return Ok(None);
+        }
+        let metadata = fs::metadata(self.path_of(key))?;
+        let stored_at = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Some(BlobStat {
+            size: metadata.len(),
+            stored_at,
+        }))
+    }
+
+    fn delete(&mut self, key: &Vec<u8>) -> io::Result<bool> {
+        if !self.keys.remove(key) {
+            return Ok(false);
+        }
+        fs::remove_file(self.path_of(key))?;
+        Ok(true)
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Vec<u8>> + 'a> {
+        Box::new(self.keys.iter())
+    }
+
+    fn put_spooled(&mut self, key: Vec<u8>, spool: SpoolFile) -> io::Result<()> {
+        spool.persist(self.path_of(&key))?;
+        self.keys.insert(key);
+        Ok(())
+    }
+
+    /// Blobs are spooled inside the store's directory, so that storing them is just a rename.
+    fn spool_dir(&self) -> PathBuf {
+        self.dir.join(SPOOL_DIR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::{check_put_get_delete, check_ranges, hash_of, TestDir};
+
+    #[test]
+    fn puts_gets_and_deletes() {
+        let dir = TestDir::new("disk");
+        check_put_get_delete(&mut DiskStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn clamps_ranges() {
+        let dir = TestDir::new("disk-range");
+        check_ranges(&mut DiskStore::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn keeps_blobs_across_open() {
+        let dir = TestDir::new("disk-reopen");
+        let (a, b) = (b"kept".to_vec(), b"deleted".to_vec());
+        {
+            let mut store = DiskStore::open(dir.path()).unwrap();
+            store.put(hash_of(&a), a.clone()).unwrap();
+            store.put(hash_of(&b), b.clone()).unwrap();
+            store.delete(&hash_of(&b)).unwrap();
+        }
+        fs::write(dir.path().join("unknown"), b"skipped").unwrap();
+        let store = DiskStore::open(dir.path()).unwrap();
+        assert_eq!(store.iter().count(), 1);
+        assert_eq!(*store.get(&hash_of(&a)).unwrap().unwrap(), a);
+        assert!(!store.contains(&hash_of(&b)));
+    }
+}
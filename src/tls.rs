@@ -0,0 +1,251 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::Future;
+
+use tokio::net::TcpStream;
+
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, Certificate, NoClientAuth, PrivateKey};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::auth::Credentials;
+use crate::client::{Client, ClientError};
+use crate::utils::BoxedFuture;
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Could not open {}: {}", path, e))
+}
+
+/// Reads the certificates in the PEM file at `path`.
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let certs = pemfile::certs(&mut open(path)?).map_err(|_| format!("Could not read the certificates in {}", path))?;
+    if certs.is_empty() {
+        return Err(format!("There is no certificate in {}", path));
+    }
+    Ok(certs)
+}
+
+/// Reads the private key in the PEM file at `path`, in PKCS #8 or PKCS #1 format.
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let invalid = || format!("Could not read the private key in {}", path);
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(|_| invalid())?;
+    }
+    keys.pop().ok_or_else(|| format!("There is no private key in {}", path))
+}
+
+/// Reads the certificates of the authorities in the PEM file at `path`.
+fn load_roots(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    let (valid, _) = roots.add_pem_file(&mut open(path)?)
+        .map_err(|_| format!("Could not read the certificates in {}", path))?;
+    if valid == 0 {
+        return Err(format!("There is no usable certificate in {}", path));
+    }
+    Ok(roots)
+}
+
+/// Accepts TLS connections, presenting the certificate chain in the PEM file at `cert`,
+/// whose private key is in `key`.
+///
+/// If `client_ca` is given, clients must present a certificate issued by one of the
+/// authorities in it.
+pub fn acceptor(cert: &str, key: &str, client_ca: Option<&str>) -> Result<TlsAcceptor, String> {
+    let verifier = match client_ca {
+        Some(path) => AllowAnyAuthenticatedClient::new(load_roots(path)?),
+        None => NoClientAuth::new(),
+    };
+    let mut config = ServerConfig::new(verifier);
+    config.set_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| format!("Invalid certificate or key: {}", e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The name servers are checked against, if it is a host name.
+pub(crate) fn server_name(name: &str) -> Result<DNSNameRef<'_>, String> {
+    DNSNameRef::try_from_ascii_str(name)
+        .map_err(|_| format!("{} is not a host name, server certificates can only be checked against one", name))
+}
+
+/// The settings of clients that trust the server certificates issued by one of the
+/// authorities in the PEM file at `ca`, and present the certificate chain and private key
+/// files of `identity` to the servers that ask for one.
+pub(crate) fn client_config(ca: &str, identity: Option<(&str, &str)>) -> Result<Arc<ClientConfig>, String> {
+    let mut config = ClientConfig::new();
+    config.root_store = load_roots(ca)?;
+    if let Some((cert, key)) = identity {
+        config.set_single_client_cert(load_certs(cert)?, load_key(key)?);
+    }
+    Ok(Arc::new(config))
+}
+
+/// How clients reach servers: over plain TCP, or over TLS.
+#[derive(Clone)]
+pub enum Connector {
+    Plain,
+    /// The certificates of the servers must be issued for `name`
+    Tls { connector: TlsConnector, name: String },
+    /// Connects as `transport` does, then proves to the servers it holds `credentials`
+    Authenticated { transport: Box<Connector>, credentials: Credentials },
+}
+
+impl Connector {
+    /// Connects over TLS, trusting the server certificates issued for `name` by one of the
+    /// authorities in the PEM file at `ca`.
+    ///
+    /// `identity` is the certificate chain and private key files presented to the servers
+    /// that ask for a client certificate.
+    pub fn tls(ca: &str, identity: Option<(&str, &str)>, name: &str) -> Result<Connector, String> {
+        server_name(name)?;
+        Ok(Connector::Tls {
+            connector: TlsConnector::from(client_config(ca, identity)?),
+            name: name.to_string(),
+        })
+    }
+
+    /// Authenticates every connection made as this connector does with `credentials`.
+    pub fn with_credentials(self, credentials: Credentials) -> Connector {
+        let transport = match self {
+            Connector::Authenticated { transport, .. } => transport,
+            transport => Box::new(transport),
+        };
+        Connector::Authenticated {
+            transport,
+            credentials,
+        }
+    }
+
+    /// Connects to the server at `addr`, agrees on the protocol with it and authenticates
+    /// if there are credentials.
+    pub fn connect(&self, addr: &SocketAddr) -> BoxedFuture<Client, ClientError> {
+        match self {
+            Connector::Plain => Box::new(Client::connect(addr)),
+            Connector::Tls { connector, name } => {
+                let (connector, name) = (connector.clone(), name.clone());
+                Box::new(TcpStream::connect(addr)
+                    .and_then(move |sock| {
+                        // the name was checked when the connector was made
+                        let name = DNSNameRef::try_from_ascii_str(&name).unwrap();
+                        connector.connect(name, sock)
+                    })
+                    .map_err(ClientError::Io)
+                    .and_then(Client::open))
+            },
+            Connector::Authenticated { transport, credentials } => {
+                let credentials = credentials.clone();
+                Box::new(transport.connect(addr)
+                    .and_then(move |client| client.authenticate(&credentials).map(|_| client)))
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connector::Plain => write!(f, "Plain"),
+            Connector::Tls { name, .. } => write!(f, "Tls({})", name),
+            Connector::Authenticated { transport, credentials } => write!(f, "{:?} as {}", transport, credentials.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    use crate::client::tests::serve_over;
+    use crate::messages::{Frame, NotFoundMessage};
+
+    /// The path of a file generated by `testdata/tls/generate.sh`.
+    fn testdata(name: &str) -> String {
+        format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Serves a loopback port over TLS if there is an `acceptor`, answering every fetch
+    /// with NotFound.
+    fn serve(runtime: &mut Runtime, acceptor: Option<TlsAcceptor>) -> SocketAddr {
+        serve_over(runtime, acceptor, |id, frame| match frame {
+            Frame::Fetch(mut msg) => vec![(id, Frame::NotFound(NotFoundMessage::new(msg.hashes.remove(0))))],
+            frame => panic!("unexpected {:?}", frame),
+        })
+    }
+
+    /// Whether a fetch made through `connector` is answered by the server at `addr`.
+    fn answered(runtime: &mut Runtime, connector: &Connector, addr: &SocketAddr) -> bool {
+        runtime.block_on(connector.connect(addr).and_then(|client| client.fetch(&[0; 64]))).is_ok()
+    }
+
+    #[test]
+    fn explains_why_certificates_and_keys_cannot_be_loaded() {
+        let missing = testdata("missing.pem");
+        assert!(load_certs(&missing).unwrap_err().starts_with("Could not open"));
+        assert!(load_key(&missing).unwrap_err().starts_with("Could not open"));
+        assert!(load_roots(&missing).unwrap_err().starts_with("Could not open"));
+
+        let key = testdata("server.key");
+        assert_eq!(load_certs(&key).unwrap_err(), format!("There is no certificate in {}", key));
+        assert_eq!(load_roots(&key).unwrap_err(), format!("There is no usable certificate in {}", key));
+        let cert = testdata("server.pem");
+        assert_eq!(load_key(&cert).unwrap_err(), format!("There is no private key in {}", cert));
+
+        assert_eq!(load_certs(&cert).unwrap().len(), 1);
+        assert!(load_key(&key).is_ok());
+        assert!(load_key(&testdata("rsa.key")).is_ok());
+        assert!(acceptor(&cert, &cert, None).is_err());
+        assert!(acceptor(&cert, &key, Some(&key)).is_err());
+        assert!(Connector::tls(&testdata("ca.pem"), None, "127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn connects_over_tls_or_plain_tcp_as_the_server_does() {
+        let mut runtime = Runtime::new().unwrap();
+        let (ca, cert, key) = (testdata("ca.pem"), testdata("server.pem"), testdata("server.key"));
+        let plain = serve(&mut runtime, None);
+        let secure = serve(&mut runtime, Some(acceptor(&cert, &key, None).unwrap()));
+        let tls = Connector::tls(&ca, None, "kitap.test").unwrap();
+
+        assert!(answered(&mut runtime, &Connector::Plain, &plain));
+        assert!(answered(&mut runtime, &tls, &secure));
+        assert!(!answered(&mut runtime, &tls, &plain));
+        assert!(!answered(&mut runtime, &Connector::Plain, &secure));
+        // the server certificate is issued for another name, or by an authority not trusted
+        assert!(!answered(&mut runtime, &Connector::tls(&ca, None, "kitap.example").unwrap(), &secure));
+        assert!(!answered(&mut runtime, &Connector::tls(&testdata("other.pem"), None, "kitap.test").unwrap(), &secure));
+
+        let mut blocking = crate::blocking::Client::connect_tls(secure, &ca, None, "kitap.test").unwrap();
+        assert_eq!(blocking.fetch(&[0; 64]).unwrap(), None);
+    }
+
+    #[test]
+    fn presents_client_certificates_to_servers_that_ask_for_one() {
+        let mut runtime = Runtime::new().unwrap();
+        let (ca, cert, key) = (testdata("ca.pem"), testdata("server.pem"), testdata("server.key"));
+        let addr = serve(&mut runtime, Some(acceptor(&cert, &key, Some(&ca)).unwrap()));
+        let identity = (testdata("client.pem"), testdata("client.key"));
+        let identity = Some((identity.0.as_str(), identity.1.as_str()));
+        let untrusted = (testdata("other.pem"), testdata("other.key"));
+        let untrusted = Some((untrusted.0.as_str(), untrusted.1.as_str()));
+
+        assert!(answered(&mut runtime, &Connector::tls(&ca, identity, "kitap.test").unwrap(), &addr));
+        assert!(!answered(&mut runtime, &Connector::tls(&ca, None, "kitap.test").unwrap(), &addr));
+        assert!(!answered(&mut runtime, &Connector::tls(&ca, untrusted, "kitap.test").unwrap(), &addr));
+
+        let mut blocking = crate::blocking::Client::connect_tls(addr, &ca, identity, "kitap.test").unwrap();
+        assert_eq!(blocking.fetch(&[0; 64]).unwrap(), None);
+        let refused = crate::blocking::Client::connect_tls(addr, &ca, None, "kitap.test")
+            .and_then(|mut client| client.fetch(&[0; 64]));
+        assert!(refused.is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use hex::{decode, encode};
+
+use log::{debug, info, warn};
+
+use crate::hash::HASH_SIZE;
+use crate::messages::{ErrorCode, ErrorMessage};
+use crate::store::{unix_time, SpoolFile};
+
+/// How long an upload may go without receiving data before it is dropped, in seconds.
+const UPLOAD_EXPIRY: u64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+struct Session {
+    size: u64,
+    /// The data received so far. It is taken out while more data is being added.
+    spool: Option<SpoolFile>,
+    /// When data was last added, in seconds since the unix epoch
+    touched: u64,
+}
+
+fn bad_request<S: Into<String>>(reason: S) -> ErrorMessage {
+    ErrorMessage::new(ErrorCode::BadRequest, reason)
+}
+
+/// The name of the file in which the data of an upload of `size` bytes under `hash` is
+/// spooled.
+fn spool_name(hash: &[u8], size: u64) -> String {
+    format!("{}-{}.upload", encode(hash), size)
+}
+
+/// The hash and size of the upload spooled in the file named `name`.
+fn parse_spool_name(name: &str) -> Option<(Vec<u8>, u64)> {
+    let mut parts = name.strip_suffix(".upload")?.splitn(2, '-');
+    let hash = decode(parts.next()?).ok().filter(|hash| hash.len() == HASH_SIZE)?;
+    let size = parts.next()?.parse().ok()?;
+    Some((hash, size))
+}
+
+/// The uploads being received, by the hash of their data.
+#[derive(Debug)]
+struct Sessions {
+    uploads: HashMap<Vec<u8>, Session>,
+    /// Whether the data of each upload is spooled in a file named after it, so that the
+    /// upload can be found again after a restart
+    named: bool,
+}
+
+impl Drop for Sessions {
+    /// The data of named uploads is left behind, for them to be resumed later.
+    fn drop(&mut self) {
+        if self.named {
+            for (_, session) in self.uploads.drain() {
+                if let Some(spool) = session.spool {
+                    spool.keep();
+                }
+            }
+        }
+    }
+}
+
+/// The uploads a server is receiving.
+///
+/// An upload is keyed by the hash of its data and may span several connections, so the
+/// data received for it is spooled until the upload is committed. Uploads that receive no
+/// data for a day are dropped.
+#[derive(Debug, Clone)]
+pub struct Uploads {
+    spool_dir: PathBuf,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl Uploads {
+    /// Creates the registry of uploads, which spools their data in `spool_dir`.
+    ///
+    /// The uploads are lost when the registry is dropped.
+    pub fn new<P: AsRef<Path>>(spool_dir: P) -> Uploads {
+        Uploads {
+            spool_dir: spool_dir.as_ref().to_path_buf(),
+            sessions: Arc::new(Mutex::new(Sessions {
+                uploads: HashMap::new(),
+                named: false,
+            })),
+        }
+    }
+
+    /// Opens the registry of uploads that spools their data in `dir`, creating it if
+    /// needed, and resumes the uploads already spooled there.
+    ///
+    /// `dir` must only be used by this registry.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Uploads> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut sessions = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let (hash, size) = match name.to_str().and_then(parse_spool_name) {
+                Some(upload) => upload,
+                None => {
+                    warn!("Skipping unknown file {:?} among uploads", name);
+                    continue;
+                },
+            };
+            let touched = entry.metadata()?.modified()?
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let spool = SpoolFile::open(entry.path())?;
+            if spool.len() > size {
+                warn!("Dropping the upload of {}, which holds more than its {} bytes", encode(&hash), size);
+                continue;
+            }
+            sessions.insert(hash, Session {
+                size,
+                spool: Some(spool),
+                touched,
+            });
+        }
+        let mut sessions = Sessions {
+            uploads: sessions,
+            named: true,
+        };
+        Uploads::expire(&mut sessions);
+        info!("Resuming {} uploads", sessions.uploads.len());
+        Ok(Uploads {
+            spool_dir: dir,
+            sessions: Arc::new(Mutex::new(sessions)),
+        })
+    }
+
+    /// Begins an upload of `size` bytes under `hash`, or resumes the one already begun.
+    ///
+    /// Returns how many bytes of the upload have been received.
+    pub fn begin(&self, hash: &[u8], size: u64) -> Result<u64, ErrorMessage> {
+        if hash.len() != HASH_SIZE {
+            return Err(bad_request(format!("Hash has length {}, expected {}", hash.len(), HASH_SIZE)));
+        }
+        let mut sessions = self.sessions.lock().unwrap();
+        Uploads::expire(&mut sessions);
+        if let Some(session) = sessions.uploads.get_mut(hash) {
+            if session.size != size {
+                return Err(bad_request(format!("An upload of {} bytes was begun for this hash", session.size)));
+            }
+            session.touched = unix_time();
+            return match session.spool {
+                Some(ref spool) => Ok(spool.len()),
+                None => Err(bad_request("Data is being added to the upload")),
+            };
+        }
+        let spool = if sessions.named {
+            SpoolFile::open(self.spool_dir.join(spool_name(hash, size)))
+        } else {
+            SpoolFile::create(&self.spool_dir)
+        };
+        let spool = spool
+            .map_err(|e| ErrorMessage::new(ErrorCode::Internal, format!("Could not spool data: {}", e)))?;
+        debug!("Beginning an upload of {} bytes for {}", size, encode(hash));
+        sessions.uploads.insert(hash.to_vec(), Session {
+            size,
+            spool: Some(spool),
+            touched: unix_time(),
+        });
+        Ok(0)
+    }
+
+    /// Starts adding `len` bytes to the upload of `hash`, which must have received `offset`
+    /// bytes so far.
+    ///
+    /// No other data can be added to the upload until the returned file is dropped.
+    pub fn append(&self, hash: &[u8], offset: u64, len: u64) -> Result<UploadFile, ErrorMessage> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.uploads.get_mut(hash)
+            .ok_or_else(|| bad_request(format!("No upload was begun for {}", encode(hash))))?;
+        let received = match session.spool {
+            Some(ref spool) => spool.len(),
+            None => return Err(bad_request("Data is already being added to the upload")),
+        };
+        if received != offset {
+            return Err(bad_request(format!("The upload has received {} bytes, not {}", received, offset)));
+        }
+        if offset.checked_add(len).is_none_or(|end| end > session.size) {
+            return Err(bad_request(format!("The upload is {} bytes long", session.size)));
+        }
+        Ok(UploadFile {
+            spool: session.spool.take(),
+            hash: hash.to_vec(),
+            uploads: self.clone(),
+        })
+    }
+
+    /// Ends the upload of `hash`, returning its data to be checked and stored.
+    pub fn commit(&self, hash: &[u8]) -> Result<SpoolFile, ErrorMessage> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.uploads.get(hash)
+            .ok_or_else(|| bad_request(format!("No upload was begun for {}", encode(hash))))?;
+        let received = match session.spool {
+            Some(ref spool) => spool.len(),
+            None => return Err(bad_request("Data is being added to the upload")),
+        };
+        if received != session.size {
+            return Err(bad_request(format!("Only {} of the {} bytes of the upload were received", received, session.size)));
+        }
+        Ok(sessions.uploads.remove(hash).and_then(|session| session.spool).unwrap())
+    }
+
+    /// Drops the uploads that have not received data for too long, along with their data.
+    pub fn sweep(&self) {
+        Uploads::expire(&mut self.sessions.lock().unwrap());
+    }
+
+    fn expire(sessions: &mut Sessions) {
+        let now = unix_time();
+        sessions.uploads.retain(|hash, session| {
+            let expired = session.spool.is_some() && now.saturating_sub(session.touched) > UPLOAD_EXPIRY;
+            if expired {
+                debug!("Dropping the upload of {}", encode(hash));
+            }
+            !expired
+        });
+    }
+}
+
+/// Data being added to an upload.
+///
+/// Whatever was written to it is kept in the upload when it is dropped, even if less than
+/// announced, so that the upload can go on from there.
+#[derive(Debug)]
+pub struct UploadFile {
+    spool: Option<SpoolFile>,
+    hash: Vec<u8>,
+    uploads: Uploads,
+}
+
+impl UploadFile {
+    /// How many bytes the upload has received, including those written to this file.
+    pub fn received(&self) -> u64 {
+        self.spool.as_ref().map_or(0, |spool| spool.len())
+    }
+}
+
+impl Write for UploadFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.spool.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for UploadFile {
+    fn drop(&mut self) {
+        let mut sessions = self.uploads.sessions.lock().unwrap();
+        if let Some(session) = sessions.uploads.get_mut(&self.hash) {
+            session.spool = self.spool.take();
+            session.touched = unix_time();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::store::tests::{hash_of, TestDir};
+
+    #[test]
+    fn resumes_uploads_after_a_restart() {
+        let dir = TestDir::new("uploads");
+        let hash = hash_of(b"0123456789");
+        {
+            let uploads = Uploads::open(dir.path()).unwrap();
+            assert_eq!(uploads.begin(&hash, 10).unwrap(), 0);
+            let mut file = uploads.append(&hash, 0, 4).unwrap();
+            file.write_all(b"0123").unwrap();
+        }
+        let uploads = Uploads::open(dir.path()).unwrap();
+        assert!(uploads.begin(&hash, 12).is_err());
+        assert_eq!(uploads.begin(&hash, 10).unwrap(), 4);
+        let mut file = uploads.append(&hash, 4, 6).unwrap();
+        file.write_all(b"456789").unwrap();
+        drop(file);
+        let spool = uploads.commit(&hash).unwrap();
+        assert_eq!(spool.read_all().unwrap(), b"0123456789".to_vec());
+        drop(spool);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sweeps_expired_uploads() {
+        let dir = TestDir::new("uploads-sweep");
+        let uploads = Uploads::open(dir.path()).unwrap();
+        let (old, new) = (hash_of(b"old"), hash_of(b"new"));
+        uploads.begin(&old, 3).unwrap();
+        uploads.begin(&new, 3).unwrap();
+        uploads.sessions.lock().unwrap().uploads.get_mut(&old).unwrap().touched -= UPLOAD_EXPIRY + 1;
+        uploads.sweep();
+        assert!(uploads.append(&old, 0, 3).is_err());
+        assert!(uploads.append(&new, 0, 3).is_ok());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn skips_unknown_files() {
+        let dir = TestDir::new("uploads-unknown");
+        fs::write(dir.path().join("notes.txt"), b"").unwrap();
+        fs::write(dir.path().join(spool_name(&hash_of(b"big"), 2)), b"too long").unwrap();
+        let uploads = Uploads::open(dir.path()).unwrap();
+        assert_eq!(uploads.sessions.lock().unwrap().uploads.len(), 0);
+        assert!(!dir.path().join(spool_name(&hash_of(b"big"), 2)).exists());
+    }
+}
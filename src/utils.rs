@@ -1,5 +1,6 @@
+use std::cmp;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::fs::File;
 use std::io::{Bytes, Read, BufReader};
@@ -13,12 +14,13 @@ use itertools::structs::IntoChunks;
 
 use futures::future::Future;
 
-use tokio::io::{ReadHalf, WriteHalf};
-use tokio::net::TcpStream;
-use tokio::prelude::AsyncRead;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
 
 use clap::{App, Arg};
 
+use net2::TcpBuilder;
+
 use crate::hash::{KitapHasher, KitapHash};
 
 pub type BoxedFuture<T, E> = Box<dyn Future<Item = T, Error = E> + Send>;
@@ -46,12 +48,57 @@ where
 }
 
 /// Shortcut function to create a connection to a particular address
-pub fn connect(
-    addr: SocketAddr,
-) -> impl Future<Item = (ReadHalf<TcpStream>, WriteHalf<TcpStream>), Error = ()> {
+pub fn connect(addr: SocketAddr) -> impl Future<Item = TcpStream, Error = ()> {
     TcpStream::connect(&addr)
         .map_err(|e| eprintln!("could not connect: {}", e))
-        .map(|s| s.split())
+}
+
+/// Checks whether the hex encoding of `key` starts with `prefix`.
+///
+/// `prefix` may have an odd number of digits, so it is compared as lowercase text.
+pub fn has_hex_prefix(key: &[u8], prefix: &str) -> bool {
+    let bytes = cmp::min(key.len(), prefix.len().div_ceil(2));
+    hex::encode(&key[..bytes]).starts_with(prefix)
+}
+
+/// Resolves a host name or ip address and a port to the socket addresses they refer to
+pub fn resolve(host: &str, port: &str) -> Result<Vec<SocketAddr>, String> {
+    let port: u16 = port.parse().or(Err(format!("Invalid port {}", port)))?;
+    // ipv6 addresses may be given in brackets, as in urls
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Could not resolve {}: {}", host, e))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(format!("{} did not resolve to any address", host));
+    }
+    Ok(addrs)
+}
+
+/// Resolves an address given as `HOST:PORT` to the first socket address it refers to
+pub fn resolve_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs()
+        .map_err(|e| format!("Could not resolve {}: {}", addr, e))?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to any address", addr))
+}
+
+/// Binds a listening socket on `addr`.
+///
+/// Ipv6 sockets only accept ipv6 connections, so that both `0.0.0.0` and `::` can be
+/// listened on at the same time.
+pub fn bind(addr: &SocketAddr) -> io::Result<TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        },
+    };
+    let listener = builder.reuse_address(true)?.bind(addr)?.listen(1024)?;
+    TcpListener::from_std(listener, &Handle::default())
 }
 
 /// Sets logging up for this project
@@ -64,11 +111,14 @@ pub fn setup_logging(verbosity: u64, logfile: Option<&str>) -> Result<(), fern::
                 .level(log::LevelFilter::Info)
                 .level_for("tokio_reactor", log::LevelFilter::Warn)
                 .level_for("kitap::mapper", log::LevelFilter::Warn)
+                // failed TLS handshakes are logged by kitapd itself
+                .level_for("rustls", log::LevelFilter::Off)
         }
         1 => base_config
             .level(log::LevelFilter::Debug)
             .level_for("tokio_reactor", log::LevelFilter::Info)
-            .level_for("kitap::mapper", log::LevelFilter::Info),
+            .level_for("kitap::mapper", log::LevelFilter::Info)
+            .level_for("rustls", log::LevelFilter::Off),
         2 => base_config.level(log::LevelFilter::Debug),
         _3_or_more => base_config
             .level(log::LevelFilter::Trace)
@@ -125,9 +175,11 @@ pub fn create_base_app(name: &str) -> App<'static, 'static> {
         ).arg(
             Arg::with_name("host")
                 .long("--host")
-                .help("The host to connect to")
+                .help("The host to connect to. kitapd accepts it multiple times and listens on all of them")
                 .takes_value(true)
-                .required(true),
+                .multiple(true)
+                .number_of_values(1)
+                .required_unless("server"),
         )
         .arg(
             Arg::with_name("port")
@@ -135,7 +187,7 @@ pub fn create_base_app(name: &str) -> App<'static, 'static> {
                 .short("-p")
                 .help("The port to connect to")
                 .takes_value(true)
-                .required(true),
+                .required_unless("server"),
         )
 
 }
@@ -148,12 +200,20 @@ pub fn file_chunks(filename: &str) -> Result<IntoChunks<Bytes<BufReader<File>>>,
 
 /// Reads the contents of a file in chunks, feeds them in a hasher one by one and returns the hash
 pub fn hash_file(filename: &str) -> Result<KitapHash, String> {
+    let f = File::open(filename).or(Err(format!("Could not open {}", filename)))?;
+    hash_reader(f).or(Err(format!("Could not read from {}", filename)))
+}
+
+/// Hashes everything read from `reader`.
+pub fn hash_reader<R: Read>(mut reader: R) -> io::Result<KitapHash> {
     let mut hasher = KitapHasher::new();
-    let reader = file_chunks(filename)?;//?.bytes().chunks(1024);
-    for chunk in &reader {
-        let v: Result<Vec<u8>, std::io::Error>  = chunk.collect();
-        let v = v.or(Err(format!("Could not read from {}", filename)))?;
-        hasher.input(v);
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
     }
     Ok(hasher.result())
 }
@@ -0,0 +1,33 @@
+#!/bin/sh
+# Generates the keys and certificates the TLS tests use: an authority, the certificates
+# it issues to a server named kitap.test and to a client, a self-signed certificate for
+# kitap.test that nothing trusts, and an RSA key in PKCS #1 format.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+ec_key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+ec_key ca
+openssl req -x509 -new -key ca.key -subj /CN=kitap-test-ca -days $days -out ca.pem \
+    -addext basicConstraints=critical,CA:TRUE -addext keyUsage=critical,keyCertSign
+
+cat > leaf.ext <<END
+subjectAltName=DNS:kitap.test
+basicConstraints=CA:FALSE
+extendedKeyUsage=serverAuth,clientAuth
+END
+for name in server client; do
+    ec_key $name
+    openssl req -new -key $name.key -subj /CN=$name |
+        openssl x509 -req -CA ca.pem -CAkey ca.key -CAcreateserial -days $days -extfile leaf.ext -out $name.pem
+done
+rm leaf.ext ca.srl
+
+ec_key other
+openssl req -x509 -new -key other.key -subj /CN=other -days $days -out other.pem \
+    -addext subjectAltName=DNS:kitap.test
+
+openssl genrsa -traditional -out rsa.key 2048